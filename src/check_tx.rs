@@ -0,0 +1,83 @@
+use crate::engine::{self, PolicyEngine, RpcEngine};
+use crate::error::Error;
+use crate::policy;
+use crate::reject::RejectReason;
+use crate::retry::RetryPolicy;
+use crate::source;
+use bitcoincore_rpc::bitcoin::consensus;
+use bitcoincore_rpc::bitcoin::hashes::hex::FromHex;
+use bitcoincore_rpc::bitcoin::{Transaction, Txid};
+use config::Config;
+use serde_json::{json, Map};
+use std::str::FromStr;
+
+/// Tests a single transaction against the policy engine and the compared
+/// test nodes without keeping it, and prints the verdicts as JSON. The
+/// transaction is given as hex or as txid, which is looked up with the data
+/// source (the `node` source needs -txindex for confirmed transactions). The
+/// outputs spent by the transaction are only known for confirmed
+/// transactions.
+pub fn run(settings: &Config, tx: &str) -> Result<(), Error> {
+    let retry = RetryPolicy::from_config(settings)?;
+    let data_source = source::from_config(settings, &retry)?;
+    let (tx, block_hash) = match Txid::from_str(tx) {
+        Ok(txid) => data_source.get_transaction(&txid)?.ok_or_else(|| {
+            Error::Config(
+                "the data_source can't look up transactions by txid: pass the transaction as hex"
+                    .to_string(),
+            )
+        })?,
+        Err(_) => {
+            let bytes = Vec::<u8>::from_hex(tx)
+                .map_err(|e| Error::Input(format!("neither a txid nor a transaction: {}", e)))?;
+            let tx: Transaction = consensus::deserialize(&bytes)
+                .map_err(|e| Error::Input(format!("invalid transaction: {}", e)))?;
+            (tx, None)
+        }
+    };
+    let prevouts = match block_hash {
+        Some(block_hash) => data_source.get_prevouts(&tx, &block_hash)?,
+        None => None,
+    };
+
+    let verdict = engine::from_config(settings, &retry)?.check(&tx, prevouts.as_deref())?;
+    let mut node_verdicts = Map::new();
+    let mut compared_nodes: Vec<String> = settings
+        .get_table("nodes")
+        .map_err(|e| Error::config("invalid nodes".to_string(), e))?
+        .into_keys()
+        .filter(|name| name.starts_with("test_"))
+        .collect();
+    compared_nodes.sort();
+    for name in compared_nodes {
+        let verdict = RpcEngine::new(settings, &name, retry.clone())?.check(&tx, None)?;
+        node_verdicts.insert(
+            name,
+            json!({
+                "allowed": verdict.allowed,
+                "reject_reason": verdict.reject_reason,
+            }),
+        );
+    }
+
+    let reject = verdict
+        .reject_reason
+        .as_deref()
+        .filter(|_| !verdict.allowed)
+        .map(RejectReason::parse);
+    let result = json!({
+        "txid": tx.txid(),
+        "allowed": verdict.allowed,
+        "reject_reason": verdict.reject_reason,
+        "reject_code": reject.as_ref().map(|(reason, _)| reason.clone()),
+        "reject_category": reject.as_ref().map(|(reason, _)| reason.category()),
+        "reject_detail": reject.and_then(|(_, detail)| detail),
+        "policy_violations": policy::violations(&tx, prevouts.as_deref()),
+        "node_verdicts": node_verdicts,
+    });
+    println!(
+        "{}",
+        serde_json::to_string_pretty(&result).expect("JSON values can be serialized")
+    );
+    Ok(())
+}
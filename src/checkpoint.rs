@@ -0,0 +1,218 @@
+use crate::error::Error;
+use crate::ResultRow;
+use bitcoincore_rpc::bitcoin::BlockHash;
+use config::Config;
+use std::collections::VecDeque;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// A block that is about to be submitted to the test node together with the
+/// rows we want to write for it. When we crash after submitting the block,
+/// but before the rows are written to the output, the rows are written on
+/// the next start.
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct PendingBlock {
+    pub height: u64,
+    pub hash: BlockHash,
+    #[serde(default)]
+    pub miner: String,
+    pub rows: Vec<ResultRow>,
+}
+
+/// The number of processed blocks we remember to be able to roll back the
+/// output when blocks are reorged out.
+const MAX_REORG_DEPTH: usize = 100;
+
+/// A block that was fully processed and the position of the output once the
+/// rows of that block were written.
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct ProcessedBlock {
+    pub height: u64,
+    pub hash: BlockHash,
+    pub output_len: u64,
+}
+
+/// The range of heights a shard node of a sharded scan tests.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Shard {
+    pub name: String,
+    pub start_height: u64,
+    pub end_height: u64,
+}
+
+/// The persistent state of a run: the last fully processed block and the
+/// position of the output once the rows of that block were written.
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct Checkpoint {
+    pub height: u64,
+    pub hash: BlockHash,
+    pub output_len: u64,
+    pub pending: Option<PendingBlock>,
+    /// The blocks processed before the last one, oldest first.
+    #[serde(default)]
+    pub history: VecDeque<ProcessedBlock>,
+    /// The shards of a sharded scan, in height order. Empty otherwise.
+    #[serde(default)]
+    pub shards: Vec<Shard>,
+}
+
+impl Checkpoint {
+    /// Records the block as the last fully processed block.
+    pub fn advance(&mut self, height: u64, hash: BlockHash, output_len: u64) {
+        self.history.push_back(ProcessedBlock {
+            height: self.height,
+            hash: self.hash,
+            output_len: self.output_len,
+        });
+        if self.history.len() > MAX_REORG_DEPTH {
+            self.history.pop_front();
+        }
+        self.height = height;
+        self.hash = hash;
+        self.output_len = output_len;
+    }
+
+    /// Makes the processed block at the given height the last processed
+    /// block again. Returns false if we don't remember that block.
+    pub fn rollback(&mut self, height: u64) -> bool {
+        while self.height > height {
+            match self.history.pop_back() {
+                Some(block) => {
+                    self.height = block.height;
+                    self.hash = block.hash;
+                    self.output_len = block.output_len;
+                }
+                None => return false,
+            }
+        }
+        self.height == height
+    }
+
+    /// The hash of the processed block at the given height. None if we don't
+    /// remember that block.
+    pub fn hash_at(&self, height: u64) -> Option<BlockHash> {
+        if height == self.height {
+            return Some(self.hash);
+        }
+        self.history
+            .iter()
+            .find(|block| block.height == height)
+            .map(|block| block.hash)
+    }
+
+    pub fn load(path: &Path) -> io::Result<Option<Checkpoint>> {
+        match fs::read_to_string(path) {
+            Ok(content) => serde_json::from_str(&content)
+                .map(Some)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    // Writes the checkpoint to a temporary file first and then renames it, so
+    // we never end up with a half-written checkpoint.
+    pub fn store(&self, path: &Path) -> io::Result<()> {
+        let tmp_path = tmp_path(path);
+        let content = serde_json::to_string(self)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        fs::write(&tmp_path, content)?;
+        fs::File::open(&tmp_path)?.sync_all()?;
+        fs::rename(&tmp_path, path)
+    }
+}
+
+/// The configured `checkpoint` file, `<output>.checkpoint` by default.
+pub fn path_from_config(settings: &Config) -> Result<PathBuf, Error> {
+    let output = settings
+        .get::<String>("output")
+        .map_err(|e| Error::config("No 'output' defined in the configuration".to_string(), e))?;
+    Ok(PathBuf::from(
+        settings
+            .get::<String>("checkpoint")
+            .unwrap_or(format!("{}.checkpoint", output)),
+    ))
+}
+
+fn tmp_path(path: &Path) -> PathBuf {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    PathBuf::from(tmp)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoincore_rpc::bitcoin::hashes::Hash;
+    use bitcoincore_rpc::bitcoin::Txid;
+
+    fn checkpoint() -> Checkpoint {
+        let mut checkpoint = Checkpoint {
+            height: 1,
+            hash: BlockHash::from_byte_array([1; 32]),
+            output_len: 10,
+            pending: Some(PendingBlock {
+                height: 3,
+                hash: BlockHash::from_byte_array([3; 32]),
+                miner: "Unknown".to_string(),
+                rows: vec![ResultRow::rejected(3, Txid::all_zeros())],
+            }),
+            history: VecDeque::new(),
+            shards: vec![Shard {
+                name: "shard0".to_string(),
+                start_height: 1,
+                end_height: 100,
+            }],
+        };
+        checkpoint.advance(2, BlockHash::from_byte_array([2; 32]), 20);
+        checkpoint
+    }
+
+    #[test]
+    fn stores_and_loads_a_checkpoint() {
+        let dir =
+            std::env::temp_dir().join(format!("non-standard-checkpoint-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("output.csv.checkpoint");
+        assert!(Checkpoint::load(&path).unwrap().is_none());
+
+        checkpoint().store(&path).unwrap();
+        let loaded = Checkpoint::load(&path).unwrap().unwrap();
+        assert_eq!(
+            serde_json::to_string(&loaded).unwrap(),
+            serde_json::to_string(&checkpoint()).unwrap()
+        );
+        assert_eq!(loaded.hash_at(1), Some(BlockHash::from_byte_array([1; 32])));
+        assert_eq!(loaded.pending.unwrap().rows.len(), 1);
+        assert!(!tmp_path(&path).exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn ignores_a_half_written_checkpoint() {
+        let dir = std::env::temp_dir().join(format!(
+            "non-standard-checkpoint-tmp-{}",
+            std::process::id()
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("output.csv.checkpoint");
+
+        // A run crashed while it wrote its first checkpoint.
+        fs::write(tmp_path(&path), "{\"height\":").unwrap();
+        assert!(Checkpoint::load(&path).unwrap().is_none());
+
+        // Or while it wrote a later one.
+        checkpoint().store(&path).unwrap();
+        fs::write(tmp_path(&path), "{\"height\":").unwrap();
+        assert_eq!(Checkpoint::load(&path).unwrap().unwrap().height, 2);
+
+        // The next checkpoint replaces it.
+        let mut checkpoint = checkpoint();
+        checkpoint.advance(3, BlockHash::from_byte_array([3; 32]), 30);
+        checkpoint.store(&path).unwrap();
+        assert_eq!(Checkpoint::load(&path).unwrap().unwrap().height, 3);
+        assert!(!tmp_path(&path).exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,125 @@
+use crate::error::{self, Error};
+use bitcoincore_rpc::jsonrpc::{self, Request, Response, Transport};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+
+// Builds the transport with the user and password.
+type Connect<T> = dyn Fn(&str, &str) -> T + Send + Sync;
+
+/// Sends the requests to the RPC server of a node that uses cookie
+/// authentication. Bitcoin Core writes a new cookie when it restarts, so if
+/// the credentials are refused, the cookie file is read again and the
+/// request is sent once more with the new credentials. Only if these are
+/// refused too, the error is returned.
+pub struct CookieTransport<T> {
+    cookie_file: PathBuf,
+    connect: Box<Connect<T>>,
+    transport: RwLock<T>,
+}
+
+impl<T: Transport> CookieTransport<T> {
+    pub fn new(
+        cookie_file: PathBuf,
+        connect: impl Fn(&str, &str) -> T + Send + Sync + 'static,
+    ) -> Result<CookieTransport<T>, Error> {
+        let (user, pass) = read(&cookie_file)?;
+        let transport = RwLock::new(connect(&user, &pass));
+        Ok(CookieTransport {
+            cookie_file,
+            connect: Box::new(connect),
+            transport,
+        })
+    }
+
+    fn send<R>(&self, send: impl Fn(&T) -> Result<R, jsonrpc::Error>) -> Result<R, jsonrpc::Error> {
+        let result = send(&self.transport.read().expect("not poisoned"));
+        match result {
+            Err(jsonrpc::Error::Transport(ref e)) if error::is_auth_error(e.as_ref()) => {
+                // If the cookie can't be read, the refused credentials are
+                // the error.
+                let Ok((user, pass)) = read(&self.cookie_file) else {
+                    return result;
+                };
+                let transport = (self.connect)(&user, &pass);
+                let result = send(&transport);
+                *self.transport.write().expect("not poisoned") = transport;
+                result
+            }
+            result => result,
+        }
+    }
+}
+
+impl<T: Transport> Transport for CookieTransport<T> {
+    fn send_request(&self, request: Request) -> Result<Response, jsonrpc::Error> {
+        self.send(|transport| transport.send_request(request.clone()))
+    }
+
+    fn send_batch(&self, requests: &[Request]) -> Result<Vec<Response>, jsonrpc::Error> {
+        self.send(|transport| transport.send_batch(requests))
+    }
+
+    fn fmt_target(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.transport.read().expect("not poisoned").fmt_target(f)
+    }
+}
+
+/// Reads the user and password from the cookie file.
+pub fn read(cookie_file: &Path) -> Result<(String, String), Error> {
+    let cookie = fs::read_to_string(cookie_file).map_err(|e| {
+        Error::Config(format!(
+            "can't read the cookie file {}: {}",
+            cookie_file.display(),
+            e
+        ))
+    })?;
+    cookie
+        .trim()
+        .split_once(':')
+        .map(|(user, pass)| (user.to_string(), pass.to_string()))
+        .ok_or_else(|| {
+            Error::Config(format!(
+                "the cookie file {} isn't 'user:password'",
+                cookie_file.display()
+            ))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mock_node::mock_node_with_credentials;
+    use bitcoincore_rpc::RpcApi;
+    use config::Config;
+    use serde_json::json;
+    use std::fs;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn reads_the_cookie_again_when_it_is_refused() {
+        let cookie_file =
+            std::env::temp_dir().join(format!("non-standard-cookie-{}", std::process::id()));
+        fs::write(&cookie_file, "__cookie__:first").unwrap();
+        let credentials = Arc::new(Mutex::new("__cookie__:first".to_string()));
+        let url = mock_node_with_credentials(credentials.clone(), |_, _| Ok(json!(100)));
+        let settings = Config::builder()
+            .set_override("nodes.test.rpc_url", url)
+            .and_then(|b| b.set_override("nodes.test.rpc_cookie_file", cookie_file.to_str()))
+            .and_then(|b| b.build())
+            .unwrap();
+        let client = crate::rpc_client(&settings, "test").unwrap();
+        assert_eq!(client.get_block_count().unwrap(), 100);
+
+        // The node restarted and wrote a new cookie.
+        *credentials.lock().unwrap() = "__cookie__:second".to_string();
+        fs::write(&cookie_file, "__cookie__:second").unwrap();
+        assert_eq!(client.get_block_count().unwrap(), 100);
+
+        // The new cookie is refused too.
+        *credentials.lock().unwrap() = "__cookie__:third".to_string();
+        let result = client.get_block_count().map_err(crate::error::Error::from);
+        assert!(matches!(result, Err(crate::error::Error::Config(_))));
+        fs::remove_file(&cookie_file).unwrap();
+    }
+}
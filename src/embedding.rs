@@ -0,0 +1,228 @@
+use crate::policy::{MAX_OP_RETURN_RELAY, TAPROOT_ANNEX_TAG};
+use bitcoincore_rpc::bitcoin::blockdata::opcodes::all::{
+    OP_ENDIF, OP_IF, OP_PUSHNUM_1, OP_PUSHNUM_13,
+};
+use bitcoincore_rpc::bitcoin::blockdata::script::Instruction;
+use bitcoincore_rpc::bitcoin::secp256k1::PublicKey;
+use bitcoincore_rpc::bitcoin::{Script, Transaction, TxOut, Witness};
+
+const ORD_TAG: &[u8] = b"ord";
+
+/// The protocols a transaction embeds data with and the size of the
+/// embedded data in bytes.
+#[derive(Debug, Default)]
+pub struct Embedding {
+    pub protocols: Vec<&'static str>,
+    pub payload_size: usize,
+}
+
+impl Embedding {
+    fn add(&mut self, protocol: &'static str, payload_size: usize) {
+        if !self.protocols.contains(&protocol) {
+            self.protocols.push(protocol);
+        }
+        self.payload_size += payload_size;
+    }
+}
+
+/// Detects data embedded with ordinals inscriptions (envelopes in tapscript
+/// witnesses), runes (runestones), stamps (data in the public keys of bare
+/// 1-of-n multisig outputs, like Counterparty) and OP_RETURN outputs, which
+/// are `large-op-return` when exceeding the default -datacarriersize.
+/// Inscriptions are only detected in inputs spending known taproot outputs.
+pub fn detect(tx: &Transaction, prevouts: Option<&[TxOut]>) -> Embedding {
+    let mut embedding = Embedding::default();
+    for (index, input) in tx.input.iter().enumerate() {
+        let spends_taproot = prevouts
+            .and_then(|prevouts| prevouts.get(index))
+            .is_some_and(|prevout| prevout.script_pubkey.is_p2tr());
+        if !spends_taproot {
+            continue;
+        }
+        if let Some(tapscript) = tapscript(&input.witness) {
+            let size = inscription_size(tapscript);
+            if size > 0 {
+                embedding.add("inscription", size);
+            }
+        }
+    }
+    for output in tx.output.iter() {
+        let script = &output.script_pubkey;
+        if script.is_op_return() {
+            let bytes = script.as_bytes();
+            if bytes.get(1) == Some(&OP_PUSHNUM_13.to_u8()) {
+                embedding.add("runes", push_size(Script::from_bytes(&bytes[2..])));
+            } else if script.len() > MAX_OP_RETURN_RELAY {
+                embedding.add("large-op-return", script.len() - 1);
+            } else {
+                embedding.add("op-return", script.len() - 1);
+            }
+        } else if let Some(size) = multisig_data_size(script) {
+            embedding.add("stamps", size);
+        }
+    }
+    embedding
+}
+
+// The script of a taproot script path spend, i.e. the witness item before the
+// control block (and the annex, if any).
+fn tapscript(witness: &Witness) -> Option<&Script> {
+    let mut items: Vec<&[u8]> = witness.iter().collect();
+    if items.len() >= 2 && items.last()?.first() == Some(&TAPROOT_ANNEX_TAG) {
+        items.pop();
+    }
+    if items.len() < 2 {
+        return None;
+    }
+    Some(Script::from_bytes(items[items.len() - 2]))
+}
+
+// The size of the data pushed in `OP_FALSE OP_IF "ord" ... OP_ENDIF`
+// envelopes after the "ord" tag, i.e. the fields and the content. 0 if the
+// script has no envelope.
+fn inscription_size(tapscript: &Script) -> usize {
+    let instructions: Vec<Instruction> = tapscript.instructions().map_while(Result::ok).collect();
+    let pushes = |index: usize, data: &[u8]| matches!(instructions[index], Instruction::PushBytes(bytes) if bytes.as_bytes() == data);
+    let mut size = 0;
+    let mut i = 0;
+    while i + 2 < instructions.len() {
+        let is_envelope = pushes(i, &[])
+            && instructions[i + 1] == Instruction::Op(OP_IF)
+            && pushes(i + 2, ORD_TAG);
+        if !is_envelope {
+            i += 1;
+            continue;
+        }
+        i += 3;
+        while i < instructions.len() && instructions[i] != Instruction::Op(OP_ENDIF) {
+            if let Instruction::PushBytes(bytes) = instructions[i] {
+                size += bytes.len();
+            }
+            i += 1;
+        }
+    }
+    size
+}
+
+// The size of the data pushed by the script.
+fn push_size(script: &Script) -> usize {
+    script
+        .instructions()
+        .map_while(Result::ok)
+        .map(|instruction| match instruction {
+            Instruction::PushBytes(bytes) => bytes.len(),
+            Instruction::Op(_) => 0,
+        })
+        .sum()
+}
+
+// Stamps (and Counterparty) encode data in all but the last public key of
+// bare 1-of-n multisig outputs. The last key is a key of the sender. Returns
+// the size of the data keys, if one of them isn't a valid public key, which
+// sets them apart from regular multisig outputs.
+fn multisig_data_size(script: &Script) -> Option<usize> {
+    if !script.is_multisig() {
+        return None;
+    }
+    let instructions: Vec<Instruction> = script.instructions().collect::<Result<_, _>>().ok()?;
+    if instructions.first() != Some(&Instruction::Op(OP_PUSHNUM_1)) {
+        return None;
+    }
+    let keys = &instructions[1..instructions.len() - 2];
+    if keys.len() < 2 {
+        return None;
+    }
+    let has_data_key = keys[..keys.len() - 1].iter().any(|key| match key {
+        Instruction::PushBytes(bytes) => PublicKey::from_slice(bytes.as_bytes()).is_err(),
+        Instruction::Op(_) => false,
+    });
+    if !has_data_key {
+        return None;
+    }
+    Some(
+        keys[..keys.len() - 1]
+            .iter()
+            .map(|key| match key {
+                Instruction::PushBytes(bytes) => bytes.len(),
+                Instruction::Op(_) => 0,
+            })
+            .sum(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoincore_rpc::bitcoin::blockdata::opcodes::all::{OP_CHECKMULTISIG, OP_PUSHNUM_2};
+    use bitcoincore_rpc::bitcoin::blockdata::opcodes::OP_FALSE;
+    use bitcoincore_rpc::bitcoin::blockdata::script::Builder;
+    use bitcoincore_rpc::bitcoin::hashes::Hash;
+    use bitcoincore_rpc::bitcoin::{
+        absolute, key::TapTweak, transaction, Amount, ScriptBuf, TxIn, WScriptHash, XOnlyPublicKey,
+    };
+
+    // The compressed generator point, a valid public key.
+    const KEY: [u8; 33] = [
+        0x02, 0x79, 0xbe, 0x66, 0x7e, 0xf9, 0xdc, 0xbb, 0xac, 0x55, 0xa0, 0x62, 0x95, 0xce, 0x87,
+        0x0b, 0x07, 0x02, 0x9b, 0xfc, 0xdb, 0x2d, 0xce, 0x28, 0xd9, 0x59, 0xf2, 0x81, 0x5b, 0x16,
+        0xf8, 0x17, 0x98,
+    ];
+
+    fn multisig(data_key: [u8; 33]) -> ScriptBuf {
+        Builder::new()
+            .push_opcode(OP_PUSHNUM_1)
+            .push_slice(data_key)
+            .push_slice(KEY)
+            .push_opcode(OP_PUSHNUM_2)
+            .push_opcode(OP_CHECKMULTISIG)
+            .into_script()
+    }
+
+    #[test]
+    fn stamps_need_a_data_key() {
+        assert_eq!(multisig_data_size(&multisig(KEY)), None);
+        // The x coordinate is larger than the field size.
+        let mut data_key = [0xff; 33];
+        data_key[0] = 0x02;
+        assert_eq!(multisig_data_size(&multisig(data_key)), Some(33));
+    }
+
+    fn inscribing_tx() -> Transaction {
+        let tapscript = Builder::new()
+            .push_opcode(OP_FALSE)
+            .push_opcode(OP_IF)
+            .push_slice(b"ord")
+            .push_slice(b"hello")
+            .push_opcode(OP_ENDIF)
+            .into_script();
+        Transaction {
+            version: transaction::Version::TWO,
+            lock_time: absolute::LockTime::ZERO,
+            input: vec![TxIn {
+                witness: Witness::from_slice(&[&[0u8; 64][..], tapscript.as_bytes(), &[0xc0; 33]]),
+                ..Default::default()
+            }],
+            output: vec![],
+        }
+    }
+
+    #[test]
+    fn inscriptions_only_in_taproot_spends() {
+        let tx = inscribing_tx();
+        let key = XOnlyPublicKey::from_slice(&KEY[1..]).unwrap();
+        let p2tr = TxOut {
+            value: Amount::from_sat(1000),
+            script_pubkey: ScriptBuf::new_p2tr_tweaked(key.dangerous_assume_tweaked()),
+        };
+        let p2wsh = TxOut {
+            value: Amount::from_sat(1000),
+            script_pubkey: ScriptBuf::new_p2wsh(&WScriptHash::all_zeros()),
+        };
+
+        let embedding = detect(&tx, Some(&[p2tr]));
+        assert_eq!(embedding.protocols, vec!["inscription"]);
+        assert_eq!(embedding.payload_size, 5);
+        assert!(detect(&tx, Some(&[p2wsh])).protocols.is_empty());
+        assert!(detect(&tx, None).protocols.is_empty());
+    }
+}
@@ -0,0 +1,71 @@
+use crate::error::Error;
+use crate::package::PackageVerdict;
+use crate::retry::RetryPolicy;
+use bitcoincore_rpc::bitcoin::{Block, BlockHash, Transaction, TxOut};
+use config::Config;
+
+mod native;
+mod rpc;
+
+pub use native::NativeEngine;
+pub use rpc::RpcEngine;
+
+/// The verdict of a policy engine on a single transaction.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Verdict {
+    pub allowed: bool,
+    pub reject_reason: Option<String>,
+}
+
+/// Tells whether transactions are standard. The transactions of a block are
+/// tested in block order and accepted transactions are kept (e.g. in the
+/// mempool of a node), so that their descendants can be tested too.
+pub trait PolicyEngine {
+    /// Tests the transaction and keeps it if it's accepted. The prevouts are
+    /// the outputs spent by the transaction, if the block source knows them.
+    fn test(&self, tx: &Transaction, prevouts: Option<&[TxOut]>) -> Result<Verdict, Error>;
+    /// Tests the transaction like test(), but doesn't keep it.
+    fn check(&self, tx: &Transaction, prevouts: Option<&[TxOut]>) -> Result<Verdict, Error> {
+        self.test(tx, prevouts)
+    }
+    /// Tests the transactions like test() in order, but possibly at once.
+    /// None of them may spend an output of another one of them.
+    fn test_batch(
+        &self,
+        txs: &[&Transaction],
+        prevouts: &[Option<&[TxOut]>],
+    ) -> Result<Vec<Verdict>, Error> {
+        txs.iter()
+            .zip(prevouts)
+            .map(|(tx, prevouts)| self.test(tx, *prevouts))
+            .collect()
+    }
+    /// Tests the parents and the child (last) as a package and keeps them if
+    /// the package is accepted.
+    fn test_package(&self, package: &[&Transaction]) -> Result<PackageVerdict, Error>;
+    /// The height of the best block of the engine. None if the engine doesn't
+    /// keep a chain.
+    fn block_count(&self) -> Result<Option<u64>, Error>;
+    fn block_hash(&self, height: u64) -> Result<Option<BlockHash>, Error>;
+    /// Connects the block once its transactions were tested. Returns false if
+    /// the engine already knew about the block.
+    fn connect_block(&self, block: &Block, height: u64) -> Result<bool, Error>;
+    /// Disconnects the block and all blocks building on it.
+    fn disconnect_block(&self, hash: &BlockHash) -> Result<(), Error>;
+}
+
+/// Builds the policy engine selected with `policy_engine` in the
+/// configuration.
+pub fn from_config(settings: &Config, retry: &RetryPolicy) -> Result<Box<dyn PolicyEngine>, Error> {
+    let policy_engine = settings
+        .get::<String>("policy_engine")
+        .map_err(|e| Error::config("invalid policy_engine".to_string(), e))?;
+    match policy_engine.as_str() {
+        "node" => Ok(Box::new(RpcEngine::new(settings, "test", retry.clone())?)),
+        "native" => Ok(Box::new(NativeEngine)),
+        other => Err(Error::Config(format!(
+            "unknown policy_engine '{}': expected 'node' or 'native'",
+            other
+        ))),
+    }
+}
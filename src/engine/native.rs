@@ -0,0 +1,53 @@
+use super::{PolicyEngine, Verdict};
+use crate::error::Error;
+use crate::package::PackageVerdict;
+use crate::policy;
+use bitcoincore_rpc::bitcoin::{Block, BlockHash, Transaction, TxOut};
+
+/// Checks transactions in-process with our re-implementation of Bitcoin
+/// Core's standardness rules, so no test node is needed. It needs the
+/// outputs spent by the transactions from the block source. As it doesn't
+/// verify scripts and doesn't know about the mempool, it can accept
+/// transactions a node would reject.
+pub struct NativeEngine;
+
+impl PolicyEngine for NativeEngine {
+    fn test(&self, tx: &Transaction, prevouts: Option<&[TxOut]>) -> Result<Verdict, Error> {
+        let prevouts = prevouts.ok_or_else(|| {
+            Error::Source(format!(
+                "the native policy engine needs the outputs spent by {}, but the data source doesn't provide them",
+                tx.txid()
+            ))
+        })?;
+        // Report the first violation, like Bitcoin Core does.
+        let reject_reason = policy::violations(tx, Some(prevouts))
+            .first()
+            .map(|reason| reason.to_string());
+        Ok(Verdict {
+            allowed: reject_reason.is_none(),
+            reject_reason,
+        })
+    }
+
+    fn test_package(&self, _package: &[&Transaction]) -> Result<PackageVerdict, Error> {
+        Err(Error::Config(
+            "the native policy engine can't test packages".to_string(),
+        ))
+    }
+
+    fn block_count(&self) -> Result<Option<u64>, Error> {
+        Ok(None)
+    }
+
+    fn block_hash(&self, _height: u64) -> Result<Option<BlockHash>, Error> {
+        Ok(None)
+    }
+
+    fn connect_block(&self, _block: &Block, _height: u64) -> Result<bool, Error> {
+        Ok(true)
+    }
+
+    fn disconnect_block(&self, _hash: &BlockHash) -> Result<(), Error> {
+        Ok(())
+    }
+}
@@ -0,0 +1,453 @@
+use super::{NativeEngine, PolicyEngine, Verdict};
+use crate::error::Error;
+use crate::package::{self, PackageVerdict};
+use crate::reject::RejectReason;
+use crate::retry::RetryPolicy;
+use crate::MAX_FEE;
+use bitcoincore_rpc::bitcoin::consensus::encode::serialize_hex;
+use bitcoincore_rpc::bitcoin::hashes::Hash;
+use bitcoincore_rpc::bitcoin::{Block, BlockHash, OutPoint, ScriptBuf, Transaction, TxOut, Txid};
+use bitcoincore_rpc::json::TestMempoolAcceptResult;
+use bitcoincore_rpc::{Client, RpcApi};
+use config::Config;
+use log::info;
+use serde::de::DeserializeOwned;
+use serde_json::value::{to_raw_value, RawValue};
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+
+const DUPLICATE_BLOCK_ERROR: &str = "\"duplicate\"";
+const DUPLICATE_INVALID_BLOCK_ERROR: &str = "\"duplicate-invalid\"";
+
+/// Tests transactions with the testmempoolaccept RPC of a Bitcoin Core node.
+/// Accepted transactions are sent to the node and blocks are submitted to it
+/// once their transactions were tested.
+pub struct RpcEngine {
+    client: Client,
+    name: String,
+    retry: RetryPolicy,
+    // The outputs spent by the transactions of disconnected blocks. The node
+    // adds these transactions back to its mempool, so transactions of the new
+    // chain spending the same outputs are rejected as conflicting.
+    stale_spends: Mutex<HashSet<OutPoint>>,
+    // The outputs of the transactions that conflicted with the transactions
+    // of disconnected blocks and were accepted by the native engine instead.
+    // They aren't in the mempool of the node.
+    retested: Mutex<HashMap<Txid, Vec<TxOut>>>,
+}
+
+impl RpcEngine {
+    /// Connects to the node configured in the `[nodes.<name>]` section.
+    pub fn new(settings: &Config, name: &str, retry: RetryPolicy) -> Result<RpcEngine, Error> {
+        Ok(RpcEngine {
+            client: crate::rpc_client(settings, name)?,
+            name: name.to_string(),
+            retry,
+            stale_spends: Mutex::new(HashSet::new()),
+            retested: Mutex::new(HashMap::new()),
+        })
+    }
+
+    // Re-tests a transaction the node rejected because of a transaction of a
+    // disconnected block in its mempool (or because it spends the output of
+    // such a re-tested transaction) with the native engine, i.e. against an
+    // empty mempool. Returns None if the verdict of the node stands.
+    fn retest_stale_conflict(
+        &self,
+        tx: &Transaction,
+        verdict: &Verdict,
+    ) -> Result<Option<Verdict>, Error> {
+        let reason = match (&verdict.reject_reason, verdict.allowed) {
+            (Some(reason), false) => RejectReason::parse(reason).0,
+            _ => return Ok(None),
+        };
+        let affected = match reason {
+            RejectReason::MempoolConflict | RejectReason::InsufficientFee => {
+                let stale_spends = self.stale_spends.lock().expect("not poisoned");
+                tx.input
+                    .iter()
+                    .any(|input| stale_spends.contains(&input.previous_output))
+            }
+            RejectReason::MissingInputs => {
+                let retested = self.retested.lock().expect("not poisoned");
+                tx.input
+                    .iter()
+                    .any(|input| retested.contains_key(&input.previous_output.txid))
+            }
+            _ => false,
+        };
+        if !affected {
+            return Ok(None);
+        }
+        let prevouts = tx
+            .input
+            .iter()
+            .map(|input| self.prevout(&input.previous_output))
+            .collect::<Result<Option<Vec<TxOut>>, Error>>()?;
+        let Some(prevouts) = prevouts else {
+            return Ok(None);
+        };
+        let native_verdict = NativeEngine.test(tx, Some(&prevouts))?;
+        info!(
+            "Transaction {} conflicts with a transaction of a disconnected block in the mempool of the '{}' node. Re-tested it natively: {}",
+            tx.txid(),
+            self.name,
+            native_verdict.reject_reason.as_deref().unwrap_or("allowed")
+        );
+        if native_verdict.allowed {
+            self.retested
+                .lock()
+                .expect("not poisoned")
+                .insert(tx.txid(), tx.output.clone());
+        }
+        Ok(Some(native_verdict))
+    }
+
+    // The output spent by an input: an output of a re-tested transaction, an
+    // unspent output of the chain of the node (even if a transaction in the
+    // mempool spends it), or an output of a transaction in the mempool.
+    fn prevout(&self, outpoint: &OutPoint) -> Result<Option<TxOut>, Error> {
+        if let Some(outputs) = self
+            .retested
+            .lock()
+            .expect("not poisoned")
+            .get(&outpoint.txid)
+        {
+            return Ok(outputs.get(outpoint.vout as usize).cloned());
+        }
+        for include_mempool in [false, true] {
+            let result = self.retry.retry("gettxout", || {
+                self.client
+                    .get_tx_out(&outpoint.txid, outpoint.vout, Some(include_mempool))
+            })?;
+            if let Some(result) = result {
+                return Ok(Some(TxOut {
+                    value: result.value,
+                    script_pubkey: ScriptBuf::from_bytes(result.script_pub_key.hex),
+                }));
+            }
+        }
+        Ok(None)
+    }
+
+    // Sends a call per set of params as a single JSON-RPC batch request and
+    // returns the result of each call in order.
+    fn call_batch<T: DeserializeOwned>(
+        &self,
+        method: &str,
+        params: &[Vec<Box<RawValue>>],
+    ) -> Result<Vec<Result<T, Error>>, Error> {
+        let client = self.client.get_jsonrpc_client();
+        let requests: Vec<_> = params
+            .iter()
+            .map(|params| client.build_request(method, params))
+            .collect();
+        let responses = client
+            .send_batch(&requests)
+            .map_err(bitcoincore_rpc::Error::from)?;
+        Ok(responses
+            .into_iter()
+            .map(|response| {
+                let response =
+                    response.ok_or(Error::Rpc(bitcoincore_rpc::Error::UnexpectedStructure))?;
+                Ok(response
+                    .result::<T>()
+                    .map_err(bitcoincore_rpc::Error::from)?)
+            })
+            .collect())
+    }
+}
+
+fn raw_value(value: serde_json::Value) -> Box<RawValue> {
+    to_raw_value(&value).expect("JSON values can be serialized")
+}
+
+impl PolicyEngine for RpcEngine {
+    fn test(&self, tx: &Transaction, prevouts: Option<&[TxOut]>) -> Result<Verdict, Error> {
+        let verdict = self.check(tx, prevouts)?;
+        if let Some(verdict) = self.retest_stale_conflict(tx, &verdict)? {
+            return Ok(verdict);
+        }
+        if verdict.allowed {
+            // Sending a transaction that is already in the mempool isn't
+            // an error, so a timed out call can safely be retried.
+            self.retry.retry("sendrawtransaction", || {
+                self.client
+                    .send_raw_transaction(tx, Some(MAX_FEE), Some(MAX_FEE))
+            })?;
+        }
+        Ok(verdict)
+    }
+
+    fn check(&self, tx: &Transaction, _prevouts: Option<&[TxOut]>) -> Result<Verdict, Error> {
+        let results = self.retry.retry("testmempoolaccept", || {
+            self.client.test_mempool_accept(&[tx], Some(MAX_FEE))
+        })?;
+        let result = results
+            .first()
+            .ok_or(Error::Rpc(bitcoincore_rpc::Error::UnexpectedStructure))?;
+        Ok(Verdict {
+            allowed: result.allowed,
+            reject_reason: result.reject_reason.clone(),
+        })
+    }
+
+    // Tests all transactions with a single batch request and sends the
+    // allowed ones with another one.
+    fn test_batch(
+        &self,
+        txs: &[&Transaction],
+        _prevouts: &[Option<&[TxOut]>],
+    ) -> Result<Vec<Verdict>, Error> {
+        if txs.is_empty() {
+            return Ok(vec![]);
+        }
+        let max_fee_rate = serde_json::Value::from(MAX_FEE.to_btc());
+        let hexes: Vec<String> = txs.iter().map(|tx| serialize_hex(*tx)).collect();
+        let params: Vec<Vec<Box<RawValue>>> = hexes
+            .iter()
+            .map(|hex| {
+                vec![
+                    raw_value(vec![hex.clone()].into()),
+                    raw_value(max_fee_rate.clone()),
+                ]
+            })
+            .collect();
+        let results: Vec<Result<Vec<TestMempoolAcceptResult>, Error>> =
+            self.retry.retry("testmempoolaccept", || {
+                self.call_batch("testmempoolaccept", &params)
+            })?;
+        let mut verdicts = results
+            .into_iter()
+            .map(|results| {
+                let result = results?
+                    .into_iter()
+                    .next()
+                    .ok_or(Error::Rpc(bitcoincore_rpc::Error::UnexpectedStructure))?;
+                Ok(Verdict {
+                    allowed: result.allowed,
+                    reject_reason: result.reject_reason,
+                })
+            })
+            .collect::<Result<Vec<Verdict>, Error>>()?;
+        // Transactions re-tested natively aren't sent to the node.
+        let mut send = vec![];
+        for (tx, verdict) in txs.iter().zip(verdicts.iter_mut()) {
+            match self.retest_stale_conflict(tx, verdict)? {
+                Some(native_verdict) => {
+                    *verdict = native_verdict;
+                    send.push(false);
+                }
+                None => send.push(verdict.allowed),
+            }
+        }
+
+        let sent: Vec<usize> = (0..txs.len()).filter(|i| send[*i]).collect();
+        let params: Vec<Vec<Box<RawValue>>> = sent
+            .iter()
+            .map(|i| {
+                vec![
+                    raw_value(hexes[*i].clone().into()),
+                    raw_value(max_fee_rate.clone()),
+                    raw_value(max_fee_rate.clone()),
+                ]
+            })
+            .collect();
+        if params.is_empty() {
+            return Ok(verdicts);
+        }
+        // See test(): sending again after a timeout is safe.
+        let results = self.retry.retry("sendrawtransaction", || {
+            self.call_batch::<serde_json::Value>("sendrawtransaction", &params)
+        })?;
+        // Transactions of the batch can be accepted on their own but not
+        // together, e.g. when their common ancestors would exceed the
+        // ancestor limits. Re-test those rejected when sent one at a time,
+        // now against a mempool with the other transactions of the batch, to
+        // record the actual reject reason.
+        for (i, result) in sent.into_iter().zip(results) {
+            if let Err(e) = result {
+                verdicts[i] = self.test(txs[i], None)?;
+                info!(
+                    "Transaction {} of a batch was rejected when sent to the '{}' node ({}). Re-tested it on its own: {}",
+                    txs[i].txid(),
+                    self.name,
+                    e,
+                    verdicts[i].reject_reason.as_deref().unwrap_or("allowed")
+                );
+            }
+        }
+        Ok(verdicts)
+    }
+
+    fn test_package(&self, package: &[&Transaction]) -> Result<PackageVerdict, Error> {
+        package::submit(&self.client, &self.retry, package)
+    }
+
+    fn block_count(&self) -> Result<Option<u64>, Error> {
+        self.retry
+            .retry("getblockcount", || self.client.get_block_count())
+            .map(Some)
+    }
+
+    fn block_hash(&self, height: u64) -> Result<Option<BlockHash>, Error> {
+        self.retry
+            .retry("getblockhash", || self.client.get_block_hash(height))
+            .map(Some)
+    }
+
+    // Either submits the block (retrying transient errors) or returns an error.
+    fn connect_block(&self, block: &Block, height: u64) -> Result<bool, Error> {
+        let mut attempts = 0;
+        let result = self.retry.retry("submitblock", || {
+            attempts += 1;
+            self.client.submit_block(block)
+        });
+        if result.is_ok() {
+            // The outputs spent by the block can't be spent by transactions
+            // of later blocks, and the re-tested transactions are confirmed.
+            let mut stale_spends = self.stale_spends.lock().expect("not poisoned");
+            for input in block.txdata.iter().flat_map(|tx| tx.input.iter()) {
+                stale_spends.remove(&input.previous_output);
+            }
+            self.retested.lock().expect("not poisoned").clear();
+        }
+        match result {
+            Ok(_) => Ok(true),
+            // The submitblock RPC returns an error DUPLICATE_BLOCK_ERROR, when
+            // the block is already known by Bitcoin Core. A few of these are
+            // expected. If an earlier attempt timed out, the block might be
+            // known because we submitted it.
+            Err(Error::Rpc(bitcoincore_rpc::Error::ReturnedError(s)))
+                if s == DUPLICATE_BLOCK_ERROR =>
+            {
+                if attempts > 1 {
+                    return Ok(true);
+                }
+                info!(
+                    "Block {} is already known by the '{}' Bitcoin Core node. Skipping..",
+                    height, self.name
+                );
+                Ok(false)
+            }
+            // We invalidated the block during an earlier reorg and the data source
+            // switched back to it.
+            Err(Error::Rpc(bitcoincore_rpc::Error::ReturnedError(s)))
+                if s == DUPLICATE_INVALID_BLOCK_ERROR =>
+            {
+                let block_hash = block.block_hash();
+                info!(
+                    "Block {} was invalidated during an earlier reorg. Reconsidering it..",
+                    height
+                );
+                self.retry.retry("reconsiderblock", || {
+                    self.client.reconsider_block(&block_hash)
+                })?;
+                Ok(true)
+            }
+            Err(Error::Rpc(bitcoincore_rpc::Error::ReturnedError(s))) => {
+                Err(Error::ConsensusReject { height, reason: s })
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    // Invalidating the block disconnects it and all blocks building on it.
+    // Their transactions are added back to the mempool and there is no RPC
+    // to evict them, so we remember the outputs they spend to re-test the
+    // transactions of the new chain conflicting with them.
+    fn disconnect_block(&self, hash: &BlockHash) -> Result<(), Error> {
+        let mut block_hash = self
+            .retry
+            .retry("getbestblockhash", || self.client.get_best_block_hash())?;
+        let mut spends = vec![];
+        loop {
+            let block = self
+                .retry
+                .retry("getblock", || self.client.get_block(&block_hash))?;
+            spends.extend(
+                block
+                    .txdata
+                    .iter()
+                    .filter(|tx| !tx.is_coinbase())
+                    .flat_map(|tx| tx.input.iter().map(|input| input.previous_output)),
+            );
+            if block_hash == *hash || block.header.prev_blockhash == BlockHash::all_zeros() {
+                break;
+            }
+            block_hash = block.header.prev_blockhash;
+        }
+        self.retry
+            .retry("invalidateblock", || self.client.invalidate_block(hash))?;
+        self.stale_spends
+            .lock()
+            .expect("not poisoned")
+            .extend(spends);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_node::{self, mock_node};
+    use bitcoincore_rpc::bitcoin::{absolute, transaction, Amount, TxIn};
+    use serde_json::json;
+
+    fn engine(url: &str) -> RpcEngine {
+        RpcEngine::new(&mock_node::settings(url), "test", mock_node::retry()).expect("can connect")
+    }
+
+    fn child(parent: Txid, vout: u32) -> Transaction {
+        Transaction {
+            version: transaction::Version::TWO,
+            lock_time: absolute::LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::new(parent, vout),
+                ..Default::default()
+            }],
+            output: vec![TxOut {
+                value: Amount::from_sat(1000),
+                script_pubkey: ScriptBuf::new(),
+            }],
+        }
+    }
+
+    #[test]
+    fn retests_transactions_rejected_when_sent_in_a_batch() {
+        // Two children of a transaction in the mempool. Each is accepted on
+        // its own, but not both of them.
+        let parent = Txid::all_zeros();
+        let children = [child(parent, 0), child(parent, 1)];
+        let mut mempool: Vec<Txid> = vec![];
+        let url = mock_node(move |method, params| {
+            let tx = match method {
+                "testmempoolaccept" => mock_node::tx(&params[0][0]),
+                _ => mock_node::tx(&params[0]),
+            };
+            let allowed = mempool.is_empty() || mempool.contains(&tx.txid());
+            match method {
+                "testmempoolaccept" if allowed => Ok(json!([{"txid": tx.txid(), "allowed": true}])),
+                "testmempoolaccept" => Ok(json!([{
+                    "txid": tx.txid(),
+                    "allowed": false,
+                    "reject-reason": "too-long-mempool-chain"
+                }])),
+                "sendrawtransaction" if allowed => {
+                    mempool.push(tx.txid());
+                    Ok(json!(tx.txid()))
+                }
+                _ => Err("too-long-mempool-chain".to_string()),
+            }
+        });
+
+        let txs: Vec<&Transaction> = children.iter().collect();
+        let verdicts = engine(&url).test_batch(&txs, &[None, None]).unwrap();
+        assert!(verdicts[0].allowed);
+        assert!(!verdicts[1].allowed);
+        assert_eq!(
+            verdicts[1].reject_reason.as_deref(),
+            Some("too-long-mempool-chain")
+        );
+    }
+}
@@ -0,0 +1,156 @@
+use crate::unix_socket::AuthError;
+use bitcoincore_rpc::jsonrpc;
+use std::fmt;
+use std::io;
+
+// Bitcoin Core is still starting up (e.g. loading the block index).
+const RPC_IN_WARMUP: i32 = -28;
+// The API is rate-limiting us.
+const HTTP_TOO_MANY_REQUESTS: u16 = 429;
+const HTTP_UNAUTHORIZED: u16 = 401;
+const HTTP_FORBIDDEN: u16 = 403;
+
+#[derive(Debug)]
+pub enum Error {
+    /// The node (or API) couldn't be reached or didn't answer in time. These
+    /// errors are usually transient and the call is retried.
+    Transport(Box<dyn std::error::Error + Send + Sync>),
+    /// The node answered the RPC call with an error.
+    Rpc(bitcoincore_rpc::Error),
+    /// The test node didn't accept a block from the data node.
+    ConsensusReject {
+        height: u64,
+        reason: String,
+    },
+    /// The configuration is missing a value or has an invalid value.
+    Config(String),
+    /// An argument on the command line is invalid.
+    Input(String),
+    /// The test node, the checkpoint and the output don't fit together.
+    State(String),
+    /// The block source couldn't provide a block or information about it.
+    Source(String),
+    Io(io::Error),
+    Csv(csv::Error),
+    #[cfg(feature = "sqlite")]
+    Sqlite(rusqlite::Error),
+    #[cfg(feature = "parquet")]
+    Parquet(parquet::errors::ParquetError),
+}
+
+impl Error {
+    pub fn config(message: String, e: config::ConfigError) -> Error {
+        Error::Config(format!("{}: {}", message, e))
+    }
+
+    /// Whether it makes sense to retry the call that failed with this error.
+    pub fn is_transient(&self) -> bool {
+        matches!(self, Error::Transport(_))
+    }
+}
+
+impl From<bitcoincore_rpc::Error> for Error {
+    fn from(e: bitcoincore_rpc::Error) -> Error {
+        match e {
+            bitcoincore_rpc::Error::JsonRpc(jsonrpc::Error::Transport(ref transport_error))
+                if is_auth_error(transport_error.as_ref()) =>
+            {
+                Error::Config(format!("the node refused the RPC credentials: {}", e))
+            }
+            bitcoincore_rpc::Error::JsonRpc(jsonrpc::Error::Transport(_))
+            | bitcoincore_rpc::Error::Io(_) => Error::Transport(Box::new(e)),
+            bitcoincore_rpc::Error::JsonRpc(jsonrpc::Error::Rpc(ref rpc_error))
+                if rpc_error.code == RPC_IN_WARMUP =>
+            {
+                Error::Transport(Box::new(e))
+            }
+            _ => Error::Rpc(e),
+        }
+    }
+}
+
+// Whether the RPC server refused the credentials, over HTTP or a unix socket.
+pub fn is_auth_error(e: &(dyn std::error::Error + Send + Sync + 'static)) -> bool {
+    e.is::<AuthError>()
+        || matches!(
+            e.downcast_ref::<jsonrpc::simple_http::Error>(),
+            Some(jsonrpc::simple_http::Error::HttpErrorCode(
+                HTTP_UNAUTHORIZED | HTTP_FORBIDDEN
+            ))
+        )
+}
+
+impl From<ureq::Error> for Error {
+    fn from(e: ureq::Error) -> Error {
+        match e {
+            ureq::Error::Status(code, _) if code >= 500 || code == HTTP_TOO_MANY_REQUESTS => {
+                Error::Transport(Box::new(e))
+            }
+            ureq::Error::Status(code, response) => {
+                Error::Source(format!("HTTP status {} for {}", code, response.get_url()))
+            }
+            ureq::Error::Transport(_) => Error::Transport(Box::new(e)),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+impl From<csv::Error> for Error {
+    fn from(e: csv::Error) -> Error {
+        Error::Csv(e)
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl From<rusqlite::Error> for Error {
+    fn from(e: rusqlite::Error) -> Error {
+        Error::Sqlite(e)
+    }
+}
+
+#[cfg(feature = "parquet")]
+impl From<parquet::errors::ParquetError> for Error {
+    fn from(e: parquet::errors::ParquetError) -> Error {
+        Error::Parquet(e)
+    }
+}
+
+#[cfg(feature = "parquet")]
+impl From<arrow_schema::ArrowError> for Error {
+    fn from(e: arrow_schema::ArrowError) -> Error {
+        Error::Parquet(e.into())
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Transport(e) => write!(f, "could not reach node: {}", e),
+            Error::Rpc(e) => write!(f, "RPC error: {}", e),
+            Error::ConsensusReject { height, reason } => {
+                write!(
+                    f,
+                    "block {} was rejected by the test node: {}",
+                    height, reason
+                )
+            }
+            Error::Config(e) => write!(f, "configuration error: {}", e),
+            Error::Input(e) => write!(f, "invalid argument: {}", e),
+            Error::State(e) => write!(f, "{}", e),
+            Error::Source(e) => write!(f, "block source error: {}", e),
+            Error::Io(e) => write!(f, "I/O error: {}", e),
+            Error::Csv(e) => write!(f, "CSV error: {}", e),
+            #[cfg(feature = "sqlite")]
+            Error::Sqlite(e) => write!(f, "SQLite error: {}", e),
+            #[cfg(feature = "parquet")]
+            Error::Parquet(e) => write!(f, "Parquet error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
@@ -0,0 +1,43 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Stops the scan once the current block is processed when Ctrl-C is
+/// pressed. Pressing it again terminates the application right away.
+pub fn install() {
+    // SAFETY: the handler only stores to an atomic and calls signal(), which
+    // are async-signal-safe.
+    unsafe {
+        libc::signal(
+            libc::SIGINT,
+            on_interrupt as extern "C" fn(libc::c_int) as libc::sighandler_t,
+        );
+    }
+}
+
+extern "C" fn on_interrupt(_signal: libc::c_int) {
+    REQUESTED.store(true, Ordering::Relaxed);
+    // SAFETY: see install().
+    unsafe {
+        libc::signal(libc::SIGINT, libc::SIG_DFL);
+    }
+}
+
+/// Whether Ctrl-C was pressed.
+pub fn requested() -> bool {
+    REQUESTED.load(Ordering::Relaxed)
+}
+
+/// Sleeps for the duration, unless Ctrl-C is pressed in the meantime.
+pub fn sleep(duration: Duration) {
+    let end = Instant::now() + duration;
+    while !requested() {
+        let now = Instant::now();
+        if now >= end {
+            break;
+        }
+        thread::sleep((end - now).min(Duration::from_millis(200)));
+    }
+}
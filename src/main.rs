@@ -1,195 +1,1253 @@
-use bitcoin_pool_identification::{default_data, PoolIdentification};
-use bitcoincore_rpc::bitcoin::{Amount, Block, Network, Txid};
+use bitcoin_pool_identification::default_data;
+use bitcoincore_rpc::bitcoin::{Amount, BlockHash, Network, Txid};
 use bitcoincore_rpc::jsonrpc;
-use bitcoincore_rpc::{Client, RpcApi};
+use bitcoincore_rpc::Client;
+use checkpoint::{Checkpoint, PendingBlock};
+use clap::{Parser, Subcommand};
 use config::Config;
-use csv::Writer;
+use cookie::CookieTransport;
+use engine::{NativeEngine, PolicyEngine, RpcEngine, Verdict};
 use env_logger::Env;
-use log::info;
-use std::fs::OpenOptions;
+use error::Error;
+use log::{error, info, warn};
+use output::OutputSink;
+use prefetch::{PrefetchedBlock, Prefetcher};
+use reject::{Category, RejectReason};
+use retry::RetryPolicy;
+use script_type::{ScriptTypeCounts, ScriptTypes};
+use shard::ShardedScan;
+use source::BlockSource;
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::Arc;
 use std::time;
+use tester::BlockTester;
+use unix_socket::UnixSocketTransport;
+
+mod check_tx;
+mod checkpoint;
+mod cookie;
+mod embedding;
+mod engine;
+mod error;
+mod interrupt;
+#[cfg(test)]
+mod mock_node;
+mod out_of_band;
+mod output;
+mod package;
+mod policy;
+mod prefetch;
+mod reject;
+mod report;
+mod retry;
+mod script_type;
+mod shard;
+mod source;
+mod tester;
+mod unix_socket;
 
-const DUPLICATE_BLOCK_ERROR: &str = "\"duplicate\"";
-const TX_ALREADY_IN_MEMPOOL_REJECTION_REASON: &str = "txn-already-in-mempool";
 const RPC_TIMEOUT: time::Duration = time::Duration::from_secs(60 * 5); // 5 minutes
 const MAX_FEE: Amount = Amount::from_int_btc(10000);
 
-fn rpc_client(settings: &Config, node: &str) -> Client {
-    let rpc_url = &format!(
-        "{}:{}",
+fn rpc_client(settings: &Config, node: &str) -> Result<Client, Error> {
+    let optional = |key: &str| {
         settings
-            .get::<String>(&format!("nodes.{}.rpc_host", node))
-            .expect(&format!("need a rpc_host for the {} node", node)),
+            .get::<String>(&format!("nodes.{}.{}", node, key))
+            .ok()
+    };
+    let get = |key: &str| {
         settings
-            .get::<u16>(&format!("nodes.{}.rpc_port", node))
-            .expect(&format!("need a rpc_port for the {} node", node)),
-    );
+            .get::<String>(&format!("nodes.{}.{}", node, key))
+            .map_err(|e| Error::config(format!("need a {} for the {} node", key, node), e))
+    };
+
+    // The RPC server is reached at rpc_host and rpc_port, at the full
+    // rpc_url, or over HTTP on the unix domain socket rpc_socket.
+    let credentials = rpc_credentials(settings, node)?;
+    if let Some(socket) = optional("rpc_socket") {
+        return with_credentials(credentials, move |user, pass| {
+            UnixSocketTransport::new(PathBuf::from(&socket), user, pass, RPC_TIMEOUT)
+        });
+    }
+    let rpc_url = match optional("rpc_url") {
+        Some(rpc_url) => rpc_url,
+        None => format!("{}:{}", get("rpc_host")?, get("rpc_port")?),
+    };
+    jsonrpc::simple_http::Builder::new()
+        .url(&rpc_url)
+        .map_err(|e| Error::Config(format!("invalid rpc url {}: {}", rpc_url, e)))?;
 
     // Build a custom transport to be able to configure the timeout.
-    let custom_timeout_transport = jsonrpc::simple_http::Builder::new()
-        .url(rpc_url)
-        .expect("invalid rpc url")
-        .auth(
-            settings
-                .get::<String>(&format!("nodes.{}.rpc_user", node))
-                .expect(&format!("need a rpc_user for the {} node", node)),
-            Some(
-                settings
-                    .get::<String>(&format!("nodes.{}.rpc_pass", node))
-                    .expect(&format!("need a rpc_pass for the {} node", node)),
-            ),
-        )
-        .timeout(RPC_TIMEOUT)
-        .build();
-    Client::from_jsonrpc(jsonrpc::client::Client::with_transport(
-        custom_timeout_transport,
-    ))
+    with_credentials(credentials, move |user, pass| {
+        jsonrpc::simple_http::Builder::new()
+            .url(&rpc_url)
+            .expect("the url was checked")
+            .auth(user, Some(pass))
+            .timeout(RPC_TIMEOUT)
+            .build()
+    })
+}
+
+// The client with the transport connect() builds with the user and password.
+fn with_credentials<T: jsonrpc::Transport>(
+    credentials: RpcCredentials,
+    connect: impl Fn(&str, &str) -> T + Send + Sync + 'static,
+) -> Result<Client, Error> {
+    let client = match credentials {
+        RpcCredentials::Password(user, pass) => {
+            jsonrpc::client::Client::with_transport(connect(&user, &pass))
+        }
+        RpcCredentials::Cookie(cookie_file) => {
+            jsonrpc::client::Client::with_transport(CookieTransport::new(cookie_file, connect)?)
+        }
+    };
+    Ok(Client::from_jsonrpc(client))
+}
+
+enum RpcCredentials {
+    Password(String, String),
+    // The cookie Bitcoin Core writes when it has no rpcpassword. It's read
+    // again when the node refuses it, as the node writes a new one when it
+    // restarts.
+    Cookie(PathBuf),
+}
+
+// The credentials for the RPC server of the node: rpc_user and rpc_pass,
+// rpc_auth as "user:password" (e.g. set with an environment variable), or
+// the cookie file given as rpc_cookie_file or the .cookie file in
+// rpc_datadir.
+fn rpc_credentials(settings: &Config, node: &str) -> Result<RpcCredentials, Error> {
+    let optional = |key: &str| {
+        settings
+            .get::<String>(&format!("nodes.{}.{}", node, key))
+            .ok()
+    };
+    if let (Some(user), Some(pass)) = (optional("rpc_user"), optional("rpc_pass")) {
+        return Ok(RpcCredentials::Password(user, pass));
+    }
+    if let Some(auth) = optional("rpc_auth") {
+        return match auth.split_once(':') {
+            Some((user, pass)) => Ok(RpcCredentials::Password(user.to_string(), pass.to_string())),
+            None => Err(Error::Config(format!(
+                "the rpc_auth of the {} node isn't 'user:password'",
+                node
+            ))),
+        };
+    }
+    match (optional("rpc_cookie_file"), optional("rpc_datadir")) {
+        (Some(cookie_file), _) => Ok(RpcCredentials::Cookie(PathBuf::from(cookie_file))),
+        (None, Some(datadir)) => Ok(RpcCredentials::Cookie(Path::new(&datadir).join(".cookie"))),
+        (None, None) => Err(Error::Config(format!(
+            "need rpc_user and rpc_pass, rpc_auth, rpc_cookie_file or rpc_datadir for the {} node",
+            node
+        ))),
+    }
 }
 
-#[derive(Debug, serde::Serialize)]
-struct ResultRow {
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct ResultRow {
     height: u64,
     miner: String,
+    // The index of the transaction in the block. The coinbase is 0.
+    block_position: usize,
     reject_reason: String,
+    // The reject reason parsed into a stable code, its category and detail.
+    reject_code: Option<RejectReason>,
+    reject_category: Option<Category>,
+    reject_detail: Option<String>,
     txid: Txid,
     vsize: usize,
+    weight: u64,
+    // The size without the witness data and the size of the witness data.
+    base_size: usize,
+    witness_size: usize,
     inputs: usize,
     outputs: usize,
+    // The types of the spent inputs and of the created outputs, in order,
+    // and how often each type occurs.
+    input_types: ScriptTypes,
+    input_type_counts: ScriptTypeCounts,
+    output_types: ScriptTypes,
+    output_type_counts: ScriptTypeCounts,
     fee: u64,
+    // Whether the data source knows the fee. If not, the fee is 0.
+    fee_known: bool,
+    // In sat/vB. None if the fee isn't known.
+    feerate: Option<f64>,
+    // The weighted median feerate of the transactions in the block and the
+    // feerate relative to it (e.g. 2.0 pays twice the median feerate).
+    block_median_feerate: Option<f64>,
+    median_feerate_ratio: Option<f64>,
+    // Whether the transaction likely reached the miner out of band, and the
+    // signs for it, separated by ';'.
+    likely_out_of_band: bool,
+    out_of_band_evidence: String,
+    // The protocols the transaction embeds data with (e.g. inscription or
+    // runes), separated by ';', and the size of the embedded data in bytes.
+    data_embedding: String,
+    data_payload_size: usize,
+    package_allowed: Option<bool>,
+    package_reject_reason: Option<String>,
+    rejected_ancestor: Option<Txid>,
+    ancestor_reject_reason: Option<String>,
+    relaxed_allowed: Option<bool>,
+    relaxed_reject_reason: Option<String>,
+    // All standardness rules the transaction violates, separated by ';'.
+    policy_violations: String,
+    // Whether one of the compared test nodes disagrees with the test node.
+    // None if no test nodes are compared.
+    policies_disagree: Option<bool>,
+    // The verdicts of the compared test nodes, in the order of their names.
+    // Written as a pair of columns per node.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    node_verdicts: Vec<Verdict>,
+}
+
+#[cfg(test)]
+impl ResultRow {
+    /// A row of a transaction rejected for a non-standard output.
+    pub fn rejected(height: u64, txid: Txid) -> ResultRow {
+        ResultRow {
+            height,
+            miner: "Unknown".to_string(),
+            block_position: 1,
+            reject_reason: "scriptpubkey".to_string(),
+            reject_code: None,
+            reject_category: None,
+            reject_detail: None,
+            txid,
+            vsize: 100,
+            weight: 400,
+            base_size: 100,
+            witness_size: 0,
+            inputs: 1,
+            outputs: 1,
+            input_types: Default::default(),
+            input_type_counts: Default::default(),
+            output_types: Default::default(),
+            output_type_counts: Default::default(),
+            fee: 0,
+            fee_known: false,
+            feerate: None,
+            block_median_feerate: None,
+            median_feerate_ratio: None,
+            likely_out_of_band: false,
+            out_of_band_evidence: String::new(),
+            data_embedding: String::new(),
+            data_payload_size: 0,
+            package_allowed: None,
+            package_reject_reason: None,
+            rejected_ancestor: None,
+            ancestor_reject_reason: None,
+            relaxed_allowed: None,
+            relaxed_reject_reason: None,
+            policy_violations: String::new(),
+            policies_disagree: None,
+            node_verdicts: vec![],
+        }
+    }
+}
+
+/// Finds transactions in blocks that a Bitcoin Core node with the default
+/// policy rejects as non-standard.
+#[derive(Debug, Parser)]
+struct Cli {
+    /// The configuration file. Its values can be overridden with environment
+    /// variables, e.g. NON_STANDARD_NODES__TEST__RPC_PASS.
+    #[arg(long, global = true, default_value = "config.toml")]
+    config: PathBuf,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Test the transactions of the blocks (the default).
+    Scan(ScanArgs),
+    /// Test a single transaction, given as hex or as the txid of a
+    /// transaction the data node knows, without keeping it.
+    CheckTx { tx: String },
+    /// Summarize the rows written to the output.
+    Report,
+    /// Check the configuration and whether the configured nodes can be
+    /// reached.
+    ValidateConfig,
+}
+
+#[derive(Debug, Default, clap::Args)]
+struct ScanArgs {
+    /// The height of the first block to test. The test node must be at the
+    /// block before it.
+    #[arg(long)]
+    start_height: Option<u64>,
+    /// Stop once the block at this height is processed.
+    #[arg(long)]
+    end_height: Option<u64>,
+    /// Stop once the block with this hash is processed.
+    #[arg(long, conflicts_with = "end_height")]
+    end_hash: Option<BlockHash>,
 }
 
 fn main() {
     env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
 
-    let settings = Config::builder()
-        .add_source(config::File::with_name("config.toml"))
+    let cli = Cli::parse();
+    if matches!(cli.command, None | Some(Command::Scan(_))) {
+        interrupt::install();
+    }
+    let result = load_settings(&cli.config).and_then(|settings| match &cli.command {
+        None => scan(&settings, &ScanArgs::default()),
+        Some(Command::Scan(args)) => scan(&settings, args),
+        Some(Command::CheckTx { tx }) => check_tx::run(&settings, tx),
+        Some(Command::Report) => report::run(&settings),
+        Some(Command::ValidateConfig) => validate_config(&settings),
+    });
+    if let Err(e) = result {
+        error!("{}", e);
+        process::exit(1);
+    }
+}
+
+// Loads the configuration file. Environment variables starting with
+// NON_STANDARD_ override its values, with `__` separating the keys of nested
+// tables (e.g. NON_STANDARD_NODES__DATA__RPC_USER for nodes.data.rpc_user).
+fn load_settings(path: &Path) -> Result<Config, Error> {
+    Config::builder()
+        .set_default("retry.max_retries", 10)
+        .and_then(|b| b.set_default("retry.initial_backoff_ms", 1000))
+        .and_then(|b| b.set_default("retry.max_backoff_ms", 60 * 1000))
+        .and_then(|b| b.set_default("data_source", "node"))
+        .and_then(|b| b.set_default("policy_engine", "node"))
+        .and_then(|b| b.set_default("cross_check_native", false))
+        .and_then(|b| b.set_default("p2p_network", "bitcoin"))
+        .and_then(|b| b.set_default("package_acceptance", false))
+        .and_then(|b| b.set_default("retest_descendants", false))
+        .and_then(|b| b.set_default("follow_tip", false))
+        .and_then(|b| b.set_default("poll_interval_secs", 30))
+        .and_then(|b| b.set_default("out_of_band_max_position", 10))
+        .and_then(|b| b.set_default("prefetch_blocks", 4))
+        .and_then(|b| b.set_default("test_batch_size", 100))
+        .and_then(|b| b.set_default("parquet_rows_per_file", 100_000))
+        .map_err(|e| Error::config("invalid default".to_string(), e))?
+        .add_source(config::File::from(path))
+        .add_source(
+            config::Environment::with_prefix("NON_STANDARD")
+                .prefix_separator("_")
+                .separator("__"),
+        )
         .build()
-        .unwrap();
-
-    // We need two nodes. One node that can give us data about blocks (could
-    // also be a block explorer API) and a node that we submit transactions
-    // to and which tells us if the transaction is standard or is being
-    // rejected as non-standard.
-    // The data node and the test node.
-    let data_node = rpc_client(&settings, "data");
-    let test_node = rpc_client(&settings, "test");
-
-    let test_node_height = test_node.get_block_count().unwrap();
-    println!("The test node is at height {}", test_node_height);
-    let start_height = test_node_height + 1;
-    println!(
-        "Starting to collect non-standard transactions at height {}",
-        start_height
-    );
+        .map_err(|e| Error::config(format!("can't load {}", path.display()), e))
+}
 
-    let output_filename = settings
-        .get::<String>("output")
-        .expect("No 'output' defined in the configuration");
+// The options of a scan from the configuration.
+struct Options {
+    uses_test_node: bool,
+    compared_nodes: Vec<String>,
+    shard_nodes: Vec<String>,
+    follow_tip: bool,
+    poll_interval: time::Duration,
+    out_of_band_max_position: usize,
+    prefetch_blocks: usize,
+    test_batch_size: usize,
+    package_acceptance: bool,
+    retest_descendants: bool,
+    cross_check_native: bool,
+    checkpoint_path: PathBuf,
+}
 
-    let output_file = OpenOptions::new()
-        .write(true)
-        .create(true)
-        .append(true)
-        .open(output_filename.clone())
-        .expect(&format!("Can't open output file {}", output_filename));
+impl Options {
+    fn from_config(settings: &Config) -> Result<Options, Error> {
+        // The policy engine tells us if a transaction is standard or is being
+        // rejected as non-standard. It's usually a node we submit the
+        // transactions to (the test node), but the standardness rules can
+        // also be checked natively.
+        let uses_test_node = settings
+            .get::<String>("policy_engine")
+            .map_err(|e| Error::config("invalid policy_engine".to_string(), e))?
+            == "node";
 
-    let mut wtr = Writer::from_writer(output_file);
+        let nodes = |prefix: &str| -> Result<Vec<String>, Error> {
+            let mut names: Vec<String> = settings
+                .get_table("nodes")
+                .map_err(|e| Error::config("invalid nodes".to_string(), e))?
+                .into_keys()
+                .filter(|name| name.starts_with(prefix))
+                .collect();
+            names.sort();
+            Ok(names)
+        };
+        // Every `[nodes.test_*]` section configures another test node, e.g.
+        // with another Bitcoin Core version or policy, that the transactions
+        // are tested against. Their verdicts are recorded next to the verdict
+        // of the test node.
+        let compared_nodes = nodes("test_")?;
+        // Every `[nodes.shard_*]` section configures a test node that tests a
+        // range of heights concurrently with the other shard nodes.
+        let shard_nodes = nodes("shard_")?;
 
-    let pools = default_data(Network::Bitcoin);
+        // In follow-tip mode, we don't stop once we reached the tip of the
+        // data node, but wait for new blocks and process them as they arrive.
+        let follow_tip = settings
+            .get::<bool>("follow_tip")
+            .map_err(|e| Error::config("invalid follow_tip".to_string(), e))?;
+        let poll_interval = time::Duration::from_secs(
+            settings
+                .get::<u64>("poll_interval_secs")
+                .map_err(|e| Error::config("invalid poll_interval_secs".to_string(), e))?,
+        );
+        let out_of_band_max_position = settings
+            .get::<usize>("out_of_band_max_position")
+            .map_err(|e| Error::config("invalid out_of_band_max_position".to_string(), e))?;
 
-    let mut current_height = start_height;
-    while current_height <= data_node.get_block_count().unwrap() {
-        let block_hash = data_node.get_block_hash(current_height).unwrap();
-        let block = data_node.get_block(&block_hash).unwrap();
+        // Upcoming blocks are fetched while the policy engines test the
+        // current one, and transactions not depending on each other are
+        // tested at once.
+        let prefetch_blocks = settings
+            .get::<usize>("prefetch_blocks")
+            .map_err(|e| Error::config("invalid prefetch_blocks".to_string(), e))?;
+        let test_batch_size = settings
+            .get::<usize>("test_batch_size")
+            .map_err(|e| Error::config("invalid test_batch_size".to_string(), e))?
+            .max(1);
 
-        let pool_name = match block.identify_pool(Network::Bitcoin, &pools) {
-            Some(result) => result.pool.name,
-            None => "Unknown".to_string(),
-        };
+        // Test transactions depending on rejected transactions in the same
+        // block as a package with submitpackage.
+        let package_acceptance = settings
+            .get::<bool>("package_acceptance")
+            .map_err(|e| Error::config("invalid package_acceptance".to_string(), e))?;
 
-        let mut csv_rows = vec![];
-        for tx in block.txdata.iter() {
-            if tx.is_coinbase() {
-                continue;
-            }
+        // Descendants of rejected transactions are rejected as missing-inputs
+        // by the test node. To learn whether they are standard themselves,
+        // they are re-tested on a second node with a relaxed policy that
+        // accepted their rejected ancestors.
+        let retest_descendants = settings
+            .get::<bool>("retest_descendants")
+            .map_err(|e| Error::config("invalid retest_descendants".to_string(), e))?;
+
+        // Check the verdicts of the test node against our own implementation
+        // of the standardness rules and log when they disagree.
+        let cross_check_native = settings
+            .get::<bool>("cross_check_native")
+            .map_err(|e| Error::config("invalid cross_check_native".to_string(), e))?;
+
+        if !uses_test_node
+            && (package_acceptance
+                || retest_descendants
+                || cross_check_native
+                || !compared_nodes.is_empty())
+        {
+            return Err(Error::Config(
+                "package_acceptance, retest_descendants, cross_check_native and [nodes.test_*] need a test node (policy_engine = \"node\")".to_string(),
+            ));
+        }
+        if !shard_nodes.is_empty()
+            && (!uses_test_node || retest_descendants || follow_tip || !compared_nodes.is_empty())
+        {
+            return Err(Error::Config(
+                "[nodes.shard_*] can't be combined with retest_descendants, follow_tip, [nodes.test_*] or the native policy engine".to_string(),
+            ));
+        }
+
+        Ok(Options {
+            uses_test_node,
+            compared_nodes,
+            shard_nodes,
+            follow_tip,
+            poll_interval,
+            out_of_band_max_position,
+            prefetch_blocks,
+            test_batch_size,
+            package_acceptance,
+            retest_descendants,
+            cross_check_native,
+            checkpoint_path: checkpoint::path_from_config(settings)?,
+        })
+    }
+}
+
+// Checks the configuration like a scan does and connects to the configured
+// nodes, without retrying, to check that they can be reached.
+fn validate_config(settings: &Config) -> Result<(), Error> {
+    let options = Options::from_config(settings)?;
+    let retry = RetryPolicy {
+        max_retries: 0,
+        ..RetryPolicy::from_config(settings)?
+    };
+    let (output, format) = output::output_format(settings)?;
+    println!("output: {} ({})", output, format);
+    println!("checkpoint: {}", options.checkpoint_path.display());
 
-            let results = test_node.test_mempool_accept(&[tx], Some(MAX_FEE)).unwrap();
-            let result = results.first().unwrap();
+    let data_source = source::from_config(settings, &retry)?;
+    println!("data source: at height {}", data_source.get_block_count()?);
 
-            if !result.allowed {
-                // If a previously aborted run left transactions in the mempool,
-                // a transaction will be rejected for already being in the mempool.
-                // We don't care about these cases.
-                let reject_reason = result.reject_reason.clone().unwrap();
-                if reject_reason == TX_ALREADY_IN_MEMPOOL_REJECTION_REASON {
-                    continue;
+    let mut engines: Vec<(String, Box<dyn PolicyEngine>)> = vec![];
+    if options.shard_nodes.is_empty() {
+        engines.push(("test".to_string(), engine::from_config(settings, &retry)?));
+    }
+    if options.retest_descendants {
+        engines.push((
+            "relaxed".to_string(),
+            Box::new(RpcEngine::new(settings, "relaxed", retry.clone())?),
+        ));
+    }
+    for name in options
+        .compared_nodes
+        .iter()
+        .chain(options.shard_nodes.iter())
+    {
+        engines.push((
+            name.clone(),
+            Box::new(RpcEngine::new(settings, name, retry.clone())?),
+        ));
+    }
+    for (name, engine) in engines.iter() {
+        match engine.block_count()? {
+            Some(height) => println!("'{}' node: at height {}", name, height),
+            None => println!("'{}' policy engine: native", name),
+        }
+    }
+    println!("The configuration is valid");
+    Ok(())
+}
+
+// The height of the block given with --end-hash, which must be on the active
+// chain of the data source.
+fn end_hash_height(data_source: &dyn BlockSource, end_hash: &BlockHash) -> Result<u64, Error> {
+    let block = data_source.get_block(end_hash)?;
+    let height = block.bip34_block_height().map_err(|_| {
+        Error::Config(format!(
+            "can't find the height of the end block {}, as its coinbase doesn't contain it (BIP34): use --end-height instead",
+            end_hash
+        ))
+    })?;
+    if height > data_source.get_block_count()? || data_source.get_block_hash(height)? != *end_hash {
+        return Err(Error::Config(format!(
+            "the end block {} isn't on the active chain of the data source",
+            end_hash
+        )));
+    }
+    Ok(height)
+}
+
+fn scan(settings: &Config, args: &ScanArgs) -> Result<(), Error> {
+    let retry = RetryPolicy::from_config(settings)?;
+    let Options {
+        uses_test_node,
+        compared_nodes,
+        shard_nodes,
+        follow_tip,
+        poll_interval,
+        out_of_band_max_position,
+        prefetch_blocks,
+        test_batch_size,
+        package_acceptance,
+        retest_descendants,
+        cross_check_native,
+        checkpoint_path,
+    } = Options::from_config(settings)?;
+    let checkpoint_filename = checkpoint_path.display();
+    let checkpoint_path = checkpoint_path.as_path();
+
+    if let (Some(start_height), Some(end_height)) = (args.start_height, args.end_height) {
+        if end_height < start_height {
+            return Err(Error::Config(format!(
+                "the end height {} is before the start height {}",
+                end_height, start_height
+            )));
+        }
+    }
+
+    // We need a source of blocks and a policy engine (see Options). The
+    // source of blocks is usually another node (the data node), but can also
+    // be the blocks directory of a Bitcoin Core datadir.
+    let data_source: Arc<dyn BlockSource> = Arc::from(source::from_config(settings, &retry)?);
+
+    let mut output = output::from_config(settings, &compared_nodes)?;
+
+    if !shard_nodes.is_empty() {
+        // The shard nodes start at their own heights.
+        if args.start_height.is_some() || args.end_hash.is_some() {
+            return Err(Error::Config(
+                "--start-height and --end-hash can't be combined with [nodes.shard_*]".to_string(),
+            ));
+        }
+        return ShardedScan {
+            settings,
+            retry: &retry,
+            data_source,
+            shard_nodes,
+            checkpoint_path,
+            prefetch_blocks,
+            poll_interval,
+            package_acceptance,
+            cross_check_native,
+            test_batch_size,
+            out_of_band_max_position,
+            end_height: args.end_height,
+        }
+        .run(output.as_mut());
+    }
+
+    let end_height = match args.end_hash {
+        Some(end_hash) => Some(end_hash_height(data_source.as_ref(), &end_hash)?),
+        None => args.end_height,
+    };
+
+    let policy_engine = engine::from_config(settings, &retry)?;
+
+    let engine_height = policy_engine.block_count()?;
+    if let Some(engine_height) = engine_height {
+        println!("The test node is at height {}", engine_height);
+    }
+
+    // On the first run, we start with the block the test node is at, which
+    // must be the block before --start-height, if given. Without a test node,
+    // we start with --start-height or the configured start_height. Later runs
+    // resume from the last block recorded in the checkpoint.
+    let mut checkpoint = match Checkpoint::load(checkpoint_path)? {
+        Some(checkpoint) => {
+            if let Some(start_height) = args.start_height {
+                if checkpoint.height + 1 != start_height {
+                    return Err(Error::State(format!(
+                        "The checkpoint {} resumes at height {}, not at the start height {}. Run without --start-height to resume, or remove the checkpoint to start over",
+                        checkpoint_filename,
+                        checkpoint.height + 1,
+                        start_height
+                    )));
                 }
+            }
+            checkpoint
+        }
+        None => {
+            let height = match (engine_height, args.start_height) {
+                (Some(engine_height), Some(start_height)) if engine_height + 1 != start_height => {
+                    return Err(Error::State(format!(
+                        "The test node is at height {}, but needs to be at height {} to start at height {} (e.g. with -stopatheight={})",
+                        engine_height,
+                        start_height.saturating_sub(1),
+                        start_height,
+                        start_height.saturating_sub(1)
+                    )));
+                }
+                (Some(engine_height), _) => engine_height,
+                (None, Some(start_height)) => start_height.saturating_sub(1),
+                (None, None) => settings
+                    .get::<u64>("start_height")
+                    .map_err(|e| {
+                        Error::config(
+                            "need a start_height for the native policy engine".to_string(),
+                            e,
+                        )
+                    })?
+                    .saturating_sub(1),
+            };
+            let hash = match policy_engine.block_hash(height)? {
+                Some(hash) => hash,
+                None => data_source.get_block_hash(height)?,
+            };
+            Checkpoint {
+                height,
+                hash,
+                output_len: output.position()?,
+                pending: None,
+                history: VecDeque::new(),
+                shards: vec![],
+            }
+        }
+    };
+
+    // Remove rows that a previous run wrote after the last checkpoint, e.g.
+    // when it crashed while writing.
+    output.truncate(checkpoint.output_len)?;
 
-                let info = data_node
-                    .get_raw_transaction_info_with_fee(&tx.txid(), Some(&block_hash))
-                    .unwrap();
-                let fee = info.fee.unwrap_or_default();
-
-                // When using -stopatheight=X, Bitcoin Core might already know
-                // about blocks at a height >X. In this case, transactions are
-                // rejected because they are "already known" (as the blocks
-                // are already known). We don't care about these cases and
-                // filter them out when we receive an error on submitblock.
-                csv_rows.push(ResultRow {
-                    height: current_height,
-                    miner: pool_name.clone(),
-                    txid: tx.txid(),
-                    reject_reason,
-                    vsize: tx.vsize(),
-                    inputs: tx.input.len(),
-                    outputs: tx.output.len(),
-                    fee: fee.to_sat(),
-                });
-            } else {
-                test_node
-                    .send_raw_transaction(tx, Some(MAX_FEE), Some(MAX_FEE))
-                    .expect(&format!("Could not send raw transaction {}", tx.txid()));
+    recover_pending(
+        &mut checkpoint,
+        policy_engine.as_ref(),
+        engine_height,
+        output.as_mut(),
+    )?;
+    checkpoint.store(checkpoint_path)?;
+
+    if let Some(engine_height) = engine_height {
+        if engine_height != checkpoint.height
+            || policy_engine.block_hash(checkpoint.height)? != Some(checkpoint.hash)
+        {
+            return Err(Error::State(format!(
+                "The test node is at height {}, but the checkpoint {} expects it to be at block {} (height {})",
+                engine_height, checkpoint_filename, checkpoint.hash, checkpoint.height
+            )));
+        }
+    }
+
+    if let Some(end_height) = end_height.filter(|end_height| *end_height < checkpoint.height) {
+        return Err(Error::Config(format!(
+            "the end height {} is before the checkpoint {} at height {}",
+            end_height, checkpoint_filename, checkpoint.height
+        )));
+    }
+    let start_height = checkpoint.height + 1;
+    match end_height {
+        Some(end_height) => println!(
+            "Starting to collect non-standard transactions at height {} up to height {}",
+            start_height, end_height
+        ),
+        None => println!(
+            "Starting to collect non-standard transactions at height {}",
+            start_height
+        ),
+    }
+
+    let relaxed_engine = match retest_descendants {
+        true => {
+            let relaxed_engine = RpcEngine::new(settings, "relaxed", retry.clone())?;
+            sync_engine(
+                &relaxed_engine,
+                "relaxed",
+                policy_engine.as_ref(),
+                data_source.as_ref(),
+                &checkpoint,
+            )?;
+            Some(relaxed_engine)
+        }
+        false => None,
+    };
+    let mut compared_engines = vec![];
+    for name in compared_nodes.iter() {
+        let compared_engine = RpcEngine::new(settings, name, retry.clone())?;
+        sync_engine(
+            &compared_engine,
+            name,
+            policy_engine.as_ref(),
+            data_source.as_ref(),
+            &checkpoint,
+        )?;
+        compared_engines.push(compared_engine);
+    }
+    let native_engine = match cross_check_native {
+        true => Some(NativeEngine),
+        false => None,
+    };
+    // The native engine needs the outputs spent by each transaction.
+    let needs_prevouts = !uses_test_node || cross_check_native;
+
+    let mut engines: Vec<&dyn PolicyEngine> = vec![policy_engine.as_ref()];
+    if let Some(relaxed_engine) = &relaxed_engine {
+        engines.push(relaxed_engine);
+    }
+    for compared_engine in compared_engines.iter() {
+        engines.push(compared_engine);
+    }
+
+    let tester = BlockTester {
+        policy_engine: policy_engine.as_ref(),
+        relaxed_engine: relaxed_engine
+            .as_ref()
+            .map(|engine| engine as &dyn PolicyEngine),
+        compared_engines: compared_engines
+            .iter()
+            .map(|engine| engine as &dyn PolicyEngine)
+            .collect(),
+        native_engine,
+        data_source: data_source.as_ref(),
+        package_acceptance,
+        test_batch_size,
+        out_of_band_max_position,
+    };
+
+    let pools = default_data(Network::Bitcoin);
+    let mut prefetcher = Prefetcher::new(
+        data_source.clone(),
+        prefetch_blocks,
+        needs_prevouts,
+        poll_interval,
+    );
+
+    let mut current_height = start_height;
+    loop {
+        if interrupt::requested() {
+            info!("Stopped at height {}", checkpoint.height);
+            break;
+        }
+        if end_height.is_some_and(|end_height| current_height > end_height) {
+            if let Some(end_hash) = args.end_hash {
+                if checkpoint.hash != end_hash {
+                    return Err(Error::State(format!(
+                        "The end block {} was reorged out: block {} is at its height {}",
+                        end_hash, checkpoint.hash, checkpoint.height
+                    )));
+                }
             }
+            info!(
+                "Reached the end of the scan at height {}",
+                checkpoint.height
+            );
+            break;
         }
+        let data_height = data_source.get_block_count()?;
+        if current_height > data_height {
+            if !follow_tip {
+                if let Some(end_height) = end_height {
+                    warn!(
+                        "The data source is at height {}, before the end height {}",
+                        data_height, end_height
+                    );
+                }
+                break;
+            }
+            interrupt::sleep(poll_interval);
+            continue;
+        }
+
+        let PrefetchedBlock {
+            hash: block_hash,
+            block,
+            prevouts: block_prevouts,
+        } = prefetcher.get(current_height)?;
+
+        // The block doesn't build on the last block we processed: the data
+        // node switched to another chain.
+        if block.header.prev_blockhash != checkpoint.hash {
+            current_height = rollback(
+                data_source.as_ref(),
+                &engines,
+                &mut checkpoint,
+                output.as_mut(),
+            )? + 1;
+            checkpoint.store(checkpoint_path)?;
+            continue;
+        }
+
+        let pool_name = tester::pool_name(&block, &pools);
 
-        let block_was_unknown = submit_block(&test_node, &block, current_height);
+        let csv_rows = tester.test_block(
+            current_height,
+            block_hash,
+            &block,
+            block_prevouts.as_deref(),
+            &pool_name,
+        )?;
+
+        // Remember the rows before submitting the block: once the block is
+        // submitted, the transactions can't be tested again.
+        checkpoint.pending = Some(PendingBlock {
+            height: current_height,
+            hash: block_hash,
+            miner: pool_name.clone(),
+            rows: csv_rows,
+        });
+        checkpoint.store(checkpoint_path)?;
+
+        let block_was_unknown = policy_engine.connect_block(&block, current_height)?;
+        if let Some(relaxed_engine) = &relaxed_engine {
+            relaxed_engine.connect_block(&block, current_height)?;
+        }
+        for compared_engine in compared_engines.iter() {
+            compared_engine.connect_block(&block, current_height)?;
+        }
+        let pending = checkpoint
+            .pending
+            .take()
+            .expect("pending block was just set");
         if block_was_unknown {
-            for row in csv_rows.iter() {
-                wtr.serialize(&row).unwrap();
+            for row in pending.rows.iter() {
                 info!(
                     "Transaction rejected in block {}: txid: {} reason: {:?} pool: {}",
                     row.height, row.txid, row.reject_reason, row.miner,
                 );
             }
         }
-        csv_rows.clear();
+        let output_len = if block_was_unknown {
+            output.write_block(current_height, &block_hash, &pending.miner, &pending.rows)?
+        } else {
+            checkpoint.output_len
+        };
+        checkpoint.advance(current_height, block_hash, output_len);
+        checkpoint.store(checkpoint_path)?;
         current_height += 1;
-        wtr.flush().unwrap();
     }
+    output.finish()
 }
 
-// Either submits the block (if needed by retrying) or panics on an unhandled error
-// returns true if the node didn't know about the block; false if the node already knew about it
-fn submit_block(node: &Client, block: &Block, current_height: u64) -> bool {
-    loop {
-        match node.submit_block(&block) {
-            Ok(_) => return true,
-            Err(e) => {
-                match e {
-                    // The submitblock RPC returns an error DUPLICATE_BLOCK_ERROR, when
-                    // the block is already known by Bitcoin Core. A few of these are
-                    // expected.
-                    bitcoincore_rpc::Error::ReturnedError(s) => {
-                        if s == DUPLICATE_BLOCK_ERROR {
-                            info!("Block {} is already known by the 'test' Bitcoin Core node. Skipping..", current_height);
-                            return false;
-                        } else {
-                            panic!("ReturnedError({})", s);
-                        }
-                    }
-                    _ => panic!("{}", e),
-                }
+// If the previous run crashed after submitting a block, but before writing
+// the rows for it, we write them now. Otherwise, the block is processed again.
+fn recover_pending(
+    checkpoint: &mut Checkpoint,
+    policy_engine: &dyn PolicyEngine,
+    engine_height: Option<u64>,
+    output: &mut dyn OutputSink,
+) -> Result<(), Error> {
+    let Some(pending) = checkpoint.pending.take() else {
+        return Ok(());
+    };
+    let submitted = match engine_height {
+        Some(engine_height) if engine_height >= pending.height => {
+            policy_engine.block_hash(pending.height)? == Some(pending.hash)
+        }
+        _ => false,
+    };
+    if submitted {
+        info!(
+            "Block {} was submitted by the previous run. Writing its {} rows..",
+            pending.height,
+            pending.rows.len()
+        );
+        let output_len =
+            output.write_block(pending.height, &pending.hash, &pending.miner, &pending.rows)?;
+        checkpoint.advance(pending.height, pending.hash, output_len);
+    }
+    Ok(())
+}
+
+// Brings another node (the relaxed node or a compared test node) to the
+// block of the checkpoint by submitting the blocks it's missing. This is
+// needed when the node is added to an existing run or a previous run crashed
+// between submitting a block to the test node and to the other node.
+fn sync_engine(
+    engine: &dyn PolicyEngine,
+    name: &str,
+    policy_engine: &dyn PolicyEngine,
+    data_source: &dyn BlockSource,
+    checkpoint: &Checkpoint,
+) -> Result<(), Error> {
+    let engine_height = engine.block_count()?.unwrap_or_default();
+    if engine_height < checkpoint.height {
+        info!(
+            "The '{}' node is at height {}. Submitting the blocks up to height {}..",
+            name, engine_height, checkpoint.height
+        );
+    }
+    for height in engine_height + 1..=checkpoint.height {
+        let hash = policy_engine.block_hash(height)?.ok_or_else(|| {
+            Error::State(format!("the test node has no block at height {}", height))
+        })?;
+        let block = data_source.get_block(&hash)?;
+        engine.connect_block(&block, height)?;
+    }
+
+    if engine_height > checkpoint.height
+        || engine.block_hash(checkpoint.height)? != Some(checkpoint.hash)
+    {
+        return Err(Error::State(format!(
+            "The '{}' node is at height {}, but expected it to be at block {} (height {})",
+            name, engine_height, checkpoint.hash, checkpoint.height
+        )));
+    }
+    Ok(())
+}
+
+// Rolls the policy engines and the output back to the last processed block
+// that is still part of the data source's chain. Returns the height of this
+// block.
+fn rollback(
+    data_source: &dyn BlockSource,
+    engines: &[&dyn PolicyEngine],
+    checkpoint: &mut Checkpoint,
+    output: &mut dyn OutputSink,
+) -> Result<u64, Error> {
+    let mut fork_height = checkpoint.height;
+    while let Some(hash) = checkpoint.hash_at(fork_height) {
+        if hash == data_source.get_block_hash(fork_height)? {
+            break;
+        }
+        fork_height = fork_height.checked_sub(1).ok_or_else(|| {
+            Error::State(
+                "Can't roll back: the data source doesn't share a block with the checkpoint"
+                    .to_string(),
+            )
+        })?;
+    }
+    // The data source switched back to our chain in the meantime.
+    if fork_height == checkpoint.height {
+        return Ok(fork_height);
+    }
+    warn!(
+        "Reorg detected: the data source switched to another chain at height {}. Rolling back from block {} (height {})..",
+        fork_height + 1,
+        checkpoint.hash,
+        checkpoint.height
+    );
+
+    let stale_hash = checkpoint.hash_at(fork_height + 1);
+    if !checkpoint.rollback(fork_height) {
+        return Err(Error::State(format!(
+            "Can't roll back to height {}: the reorg is deeper than the blocks remembered in the checkpoint",
+            fork_height
+        )));
+    }
+
+    // Disconnecting the first stale block disconnects all blocks building on it.
+    let stale_hash = stale_hash.expect("the stale block is remembered");
+    for engine in engines.iter() {
+        engine.disconnect_block(&stale_hash)?;
+    }
+
+    // Remove the rows of the stale blocks.
+    output.truncate(checkpoint.output_len)?;
+    Ok(fork_height)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::package::PackageVerdict;
+    use bitcoincore_rpc::bitcoin::hashes::Hash;
+    use bitcoincore_rpc::bitcoin::{Block, Transaction, TxOut};
+    use std::sync::Mutex;
+
+    // The hash of the block at the height on the main chain (branch 0) or on
+    // a fork (branch 1).
+    fn hash(height: u64, branch: u8) -> BlockHash {
+        let mut hash = [0; 32];
+        hash[..8].copy_from_slice(&height.to_le_bytes());
+        hash[8] = branch;
+        BlockHash::from_byte_array(hash)
+    }
+
+    fn checkpoint() -> Checkpoint {
+        Checkpoint {
+            height: 0,
+            hash: hash(0, 0),
+            output_len: 0,
+            pending: None,
+            history: VecDeque::new(),
+            shards: vec![],
+        }
+    }
+
+    // A data source that switched to the fork at the height.
+    struct ForkedSource {
+        fork_height: u64,
+    }
+
+    impl BlockSource for ForkedSource {
+        fn get_block_count(&self) -> Result<u64, Error> {
+            unimplemented!()
+        }
+
+        fn get_block_hash(&self, height: u64) -> Result<BlockHash, Error> {
+            Ok(hash(height, u8::from(height >= self.fork_height)))
+        }
+
+        fn get_block(&self, _: &BlockHash) -> Result<Block, Error> {
+            unimplemented!()
+        }
+
+        fn get_fee(&self, _: &Transaction, _: &BlockHash) -> Result<Option<Amount>, Error> {
+            unimplemented!()
+        }
+
+        fn get_prevouts(
+            &self,
+            _: &Transaction,
+            _: &BlockHash,
+        ) -> Result<Option<Vec<TxOut>>, Error> {
+            unimplemented!()
+        }
+
+        fn get_median_feerate(&self, _: &Block, _: &BlockHash) -> Result<Option<f64>, Error> {
+            unimplemented!()
+        }
+
+        fn get_transaction(
+            &self,
+            _: &Txid,
+        ) -> Result<Option<(Transaction, Option<BlockHash>)>, Error> {
+            unimplemented!()
+        }
+    }
+
+    // A test node with the blocks of the main chain up to the height.
+    struct MockEngine {
+        height: u64,
+        disconnected: Mutex<Vec<BlockHash>>,
+    }
+
+    impl MockEngine {
+        fn new(height: u64) -> MockEngine {
+            MockEngine {
+                height,
+                disconnected: Mutex::new(vec![]),
             }
         }
     }
+
+    impl PolicyEngine for MockEngine {
+        fn test(&self, _: &Transaction, _: Option<&[TxOut]>) -> Result<Verdict, Error> {
+            unimplemented!()
+        }
+
+        fn test_package(&self, _: &[&Transaction]) -> Result<PackageVerdict, Error> {
+            unimplemented!()
+        }
+
+        fn block_count(&self) -> Result<Option<u64>, Error> {
+            Ok(Some(self.height))
+        }
+
+        fn block_hash(&self, height: u64) -> Result<Option<BlockHash>, Error> {
+            Ok((height <= self.height).then(|| hash(height, 0)))
+        }
+
+        fn connect_block(&self, _: &Block, _: u64) -> Result<bool, Error> {
+            unimplemented!()
+        }
+
+        fn disconnect_block(&self, hash: &BlockHash) -> Result<(), Error> {
+            self.disconnected.lock().unwrap().push(*hash);
+            Ok(())
+        }
+    }
+
+    // Keeps the heights of the written rows. The position is their number.
+    #[derive(Default)]
+    struct MockOutput {
+        rows: Vec<u64>,
+    }
+
+    impl OutputSink for MockOutput {
+        fn position(&mut self) -> Result<u64, Error> {
+            Ok(self.rows.len() as u64)
+        }
+
+        fn write_block(
+            &mut self,
+            height: u64,
+            _: &BlockHash,
+            _: &str,
+            rows: &[ResultRow],
+        ) -> Result<u64, Error> {
+            self.rows.extend(rows.iter().map(|_| height));
+            self.position()
+        }
+
+        fn truncate(&mut self, position: u64) -> Result<(), Error> {
+            self.rows.truncate(position as usize);
+            Ok(())
+        }
+    }
+
+    // Processes the blocks of the main chain up to the height with two rows
+    // each.
+    fn process(checkpoint: &mut Checkpoint, output: &mut MockOutput, height: u64) {
+        for height in checkpoint.height + 1..=height {
+            let rows = [
+                ResultRow::rejected(height, Txid::all_zeros()),
+                ResultRow::rejected(height, Txid::all_zeros()),
+            ];
+            let output_len = output
+                .write_block(height, &hash(height, 0), "Unknown", &rows)
+                .unwrap();
+            checkpoint.advance(height, hash(height, 0), output_len);
+        }
+    }
+
+    #[test]
+    fn rolls_back_the_stale_blocks_and_their_rows() {
+        let mut checkpoint = checkpoint();
+        let mut output = MockOutput::default();
+        process(&mut checkpoint, &mut output, 10);
+        assert_eq!(checkpoint.output_len, 20);
+        let engine = MockEngine::new(10);
+
+        // The data source is still on our chain.
+        let source = ForkedSource { fork_height: 11 };
+        let fork_height = rollback(&source, &[&engine], &mut checkpoint, &mut output).unwrap();
+        assert_eq!(fork_height, 10);
+        assert_eq!(output.rows.len(), 20);
+        assert!(engine.disconnected.lock().unwrap().is_empty());
+
+        let source = ForkedSource { fork_height: 7 };
+        let fork_height = rollback(&source, &[&engine], &mut checkpoint, &mut output).unwrap();
+        assert_eq!(fork_height, 6);
+        assert_eq!((checkpoint.height, checkpoint.hash), (6, hash(6, 0)));
+        assert_eq!(checkpoint.output_len, 12);
+        assert_eq!(checkpoint.hash_at(7), None);
+        assert_eq!(output.rows.len(), 12);
+        assert_eq!(output.rows.last(), Some(&6));
+        // Only the first stale block is disconnected.
+        assert_eq!(*engine.disconnected.lock().unwrap(), vec![hash(7, 0)]);
+    }
+
+    #[test]
+    fn refuses_to_roll_back_past_the_remembered_blocks() {
+        let mut checkpoint = checkpoint();
+        let mut output = MockOutput::default();
+        process(&mut checkpoint, &mut output, 150);
+        assert_eq!(checkpoint.history.len(), 100);
+        assert_eq!(checkpoint.hash_at(150), Some(hash(150, 0)));
+        assert_eq!(checkpoint.hash_at(50), Some(hash(50, 0)));
+        assert_eq!(checkpoint.hash_at(49), None);
+        let engine = MockEngine::new(150);
+
+        let source = ForkedSource { fork_height: 50 };
+        let result = rollback(&source, &[&engine], &mut checkpoint, &mut output);
+        assert!(matches!(result, Err(Error::State(_))));
+        assert_eq!(output.rows.len(), 300);
+        assert!(engine.disconnected.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn rolls_back_to_the_oldest_remembered_block() {
+        let mut checkpoint = checkpoint();
+        let mut output = MockOutput::default();
+        process(&mut checkpoint, &mut output, 150);
+        let engine = MockEngine::new(150);
+
+        let source = ForkedSource { fork_height: 51 };
+        let fork_height = rollback(&source, &[&engine], &mut checkpoint, &mut output).unwrap();
+        assert_eq!(fork_height, 50);
+        assert_eq!(checkpoint.output_len, 100);
+        assert_eq!(output.rows.len(), 100);
+        assert_eq!(*engine.disconnected.lock().unwrap(), vec![hash(51, 0)]);
+    }
+
+    fn pending_checkpoint(output: &mut MockOutput) -> Checkpoint {
+        let mut checkpoint = checkpoint();
+        process(&mut checkpoint, output, 5);
+        checkpoint.pending = Some(PendingBlock {
+            height: 6,
+            hash: hash(6, 0),
+            miner: "Unknown".to_string(),
+            rows: vec![ResultRow::rejected(6, Txid::all_zeros())],
+        });
+        checkpoint
+    }
+
+    #[test]
+    fn writes_the_rows_of_a_submitted_pending_block() {
+        let mut output = MockOutput::default();
+        let mut checkpoint = pending_checkpoint(&mut output);
+        let engine = MockEngine::new(6);
+        recover_pending(&mut checkpoint, &engine, Some(6), &mut output).unwrap();
+        assert!(checkpoint.pending.is_none());
+        assert_eq!((checkpoint.height, checkpoint.hash), (6, hash(6, 0)));
+        assert_eq!(checkpoint.output_len, 11);
+        assert_eq!(checkpoint.hash_at(5), Some(hash(5, 0)));
+        assert_eq!(output.rows.len(), 11);
+        assert_eq!(output.rows.last(), Some(&6));
+    }
+
+    #[test]
+    fn drops_a_pending_block_that_wasnt_submitted() {
+        let mut output = MockOutput::default();
+        let mut checkpoint = pending_checkpoint(&mut output);
+        let engine = MockEngine::new(5);
+        recover_pending(&mut checkpoint, &engine, Some(5), &mut output).unwrap();
+        assert!(checkpoint.pending.is_none());
+        assert_eq!((checkpoint.height, checkpoint.hash), (5, hash(5, 0)));
+        assert_eq!(checkpoint.output_len, 10);
+        assert_eq!(output.rows.len(), 10);
+
+        // The test node has another block at the height.
+        let mut output = MockOutput::default();
+        let mut checkpoint = pending_checkpoint(&mut output);
+        checkpoint.pending.as_mut().unwrap().hash = hash(6, 1);
+        let engine = MockEngine::new(6);
+        recover_pending(&mut checkpoint, &engine, Some(6), &mut output).unwrap();
+        assert!(checkpoint.pending.is_none());
+        assert_eq!(checkpoint.height, 5);
+        assert_eq!(output.rows.len(), 10);
+    }
 }
@@ -0,0 +1,150 @@
+use crate::retry::RetryPolicy;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use bitcoincore_rpc::bitcoin::consensus::deserialize;
+use bitcoincore_rpc::bitcoin::hashes::hex::FromHex;
+use bitcoincore_rpc::bitcoin::Transaction;
+use config::Config;
+use serde_json::{json, Value};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+// Answers the calls to a mock node with the result or the message of an
+// error with code -26 (a rejected transaction).
+type Handler = dyn FnMut(&str, &Value) -> Result<Value, String> + Send;
+
+/// Serves JSON-RPC requests over HTTP on a local port, answering each call
+/// with the handler, and returns the URL. The handler returns the result or
+/// the message of an error with code -26 (a rejected transaction).
+pub fn mock_node(
+    handler: impl FnMut(&str, &Value) -> Result<Value, String> + Send + 'static,
+) -> String {
+    serve(None, Box::new(handler))
+}
+
+/// Like mock_node(), but answers requests without the `user:password`
+/// credentials with HTTP 401.
+pub fn mock_node_with_credentials(
+    credentials: Arc<Mutex<String>>,
+    handler: impl FnMut(&str, &Value) -> Result<Value, String> + Send + 'static,
+) -> String {
+    serve(Some(credentials), Box::new(handler))
+}
+
+fn serve(credentials: Option<Arc<Mutex<String>>>, handler: Box<Handler>) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("can bind");
+    let url = format!("http://{}", listener.local_addr().expect("has an address"));
+    let handler = Arc::new(Mutex::new(handler));
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = stream.expect("can accept");
+            let credentials = credentials.clone();
+            let handler = handler.clone();
+            // Like Bitcoin Core, keep the connection alive: the client reuses
+            // it for the next request.
+            thread::spawn(
+                move || {
+                    while serve_request(&stream, credentials.as_deref(), &handler) {}
+                },
+            );
+        }
+    });
+    url
+}
+
+// Answers the next request on the connection. Returns false once the client
+// closed it.
+fn serve_request(
+    mut stream: &TcpStream,
+    credentials: Option<&Mutex<String>>,
+    handler: &Mutex<Box<Handler>>,
+) -> bool {
+    let mut reader = BufReader::new(stream);
+    let mut content_length = 0;
+    let mut authorization = String::new();
+    let mut request_line = String::new();
+    if !matches!(reader.read_line(&mut request_line), Ok(n) if n > 0) {
+        return false;
+    }
+    loop {
+        let mut header = String::new();
+        reader.read_line(&mut header).expect("can read");
+        if header == "\r\n" || header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse().expect("is a length");
+            } else if name.eq_ignore_ascii_case("authorization") {
+                authorization = value.trim().to_string();
+            }
+        }
+    }
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body).expect("can read");
+    if let Some(credentials) = credentials {
+        let expected = format!(
+            "Basic {}",
+            BASE64.encode(credentials.lock().expect("not poisoned").as_bytes())
+        );
+        if authorization != expected {
+            let _ = write!(
+                stream,
+                "HTTP/1.1 401 Unauthorized\r\nContent-Length: 0\r\n\r\n"
+            );
+            return true;
+        }
+    }
+    let mut handler = handler.lock().expect("not poisoned");
+    let mut respond = |request: &Value| {
+        let (result, error) = match handler(
+            request["method"].as_str().expect("has a method"),
+            &request["params"],
+        ) {
+            Ok(result) => (result, Value::Null),
+            Err(message) => (Value::Null, json!({"code": -26, "message": message})),
+        };
+        json!({"result": result, "error": error, "id": request["id"]})
+    };
+    let request: Value = serde_json::from_slice(&body).expect("is JSON");
+    let response = match request.as_array() {
+        Some(requests) => Value::Array(requests.iter().map(&mut respond).collect()),
+        None => respond(&request),
+    };
+    let body = response.to_string();
+    write!(
+        stream,
+        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}",
+        body.len(),
+        body
+    )
+    .is_ok()
+}
+
+/// The configuration of a `test` node at the URL.
+pub fn settings(url: &str) -> Config {
+    Config::builder()
+        .set_override("nodes.test.rpc_url", url)
+        .and_then(|b| b.set_override("nodes.test.rpc_user", "user"))
+        .and_then(|b| b.set_override("nodes.test.rpc_pass", "pass"))
+        .and_then(|b| b.build())
+        .expect("valid config")
+}
+
+/// Doesn't retry failed calls.
+pub fn retry() -> RetryPolicy {
+    RetryPolicy {
+        max_retries: 0,
+        initial_backoff: Duration::from_millis(1),
+        max_backoff: Duration::from_millis(1),
+    }
+}
+
+/// The transaction in a hex parameter.
+pub fn tx(hex: &Value) -> Transaction {
+    let bytes = Vec::<u8>::from_hex(hex.as_str().expect("is hex")).expect("is hex");
+    deserialize(&bytes).expect("is a transaction")
+}
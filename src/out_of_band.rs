@@ -0,0 +1,70 @@
+use crate::policy::MIN_RELAY_TX_FEE_SAT_PER_KVB;
+use bitcoincore_rpc::bitcoin::{Amount, Block, Transaction};
+
+// Many transactions at the beginning of a block were relayed normally, so
+// this sign only supports the others.
+const BLOCK_BEGINNING: &str = "block-beginning";
+
+/// Signs that a transaction reached the miner out of band (e.g. via an
+/// accelerator service) instead of the public mempool. The fee must be known
+/// for the fee based signs. `position` is the index of the transaction in the
+/// block and `max_position` the last index counted as the beginning of the
+/// block.
+pub fn evidence(
+    tx: &Transaction,
+    position: usize,
+    fee: Option<Amount>,
+    block: &Block,
+    max_position: usize,
+) -> Vec<&'static str> {
+    let mut evidence = vec![];
+    if let Some(fee) = fee {
+        let min_fee = Amount::from_sat(tx.vsize() as u64 * MIN_RELAY_TX_FEE_SAT_PER_KVB / 1000);
+        if fee == Amount::ZERO {
+            evidence.push("zero-fee");
+        } else if fee < min_fee {
+            evidence.push("below-min-relay-fee");
+        }
+    }
+    if pays_coinbase_address(tx, block) {
+        evidence.push("pays-coinbase-address");
+    }
+    if position <= max_position {
+        evidence.push(BLOCK_BEGINNING);
+    }
+    evidence
+}
+
+/// Whether the signs show that the transaction likely reached the miner out
+/// of band. Being at the beginning of the block isn't enough on its own.
+pub fn is_likely(evidence: &[&str]) -> bool {
+    evidence.iter().any(|sign| *sign != BLOCK_BEGINNING)
+}
+
+// Whether an output pays to a script the coinbase transaction pays to, i.e.
+// the miner is paid directly. The witness commitment and other OP_RETURN
+// outputs of the coinbase are ignored.
+fn pays_coinbase_address(tx: &Transaction, block: &Block) -> bool {
+    let Some(coinbase) = block.coinbase() else {
+        return false;
+    };
+    tx.output.iter().any(|output| {
+        coinbase.output.iter().any(|coinbase_output| {
+            !coinbase_output.script_pubkey.is_op_return()
+                && coinbase_output.script_pubkey == output.script_pubkey
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn block_beginning_only_supports_other_signs() {
+        assert!(!is_likely(&[]));
+        assert!(!is_likely(&[BLOCK_BEGINNING]));
+        assert!(is_likely(&["zero-fee", BLOCK_BEGINNING]));
+        assert!(is_likely(&["pays-coinbase-address"]));
+    }
+}
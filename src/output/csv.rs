@@ -0,0 +1,88 @@
+use super::OutputSink;
+use crate::error::Error;
+use crate::ResultRow;
+use bitcoincore_rpc::bitcoin::BlockHash;
+use csv::{ReaderBuilder, StringRecord, Writer, WriterBuilder};
+use serde::de::DeserializeOwned;
+use std::fs::{File, OpenOptions};
+use std::path::Path;
+
+/// Appends the rows to a CSV file. The position is the length of the file.
+pub struct CsvSink {
+    wtr: Writer<File>,
+    compared_nodes: Vec<String>,
+}
+
+impl CsvSink {
+    pub fn open(path: &Path, compared_nodes: &[String]) -> Result<CsvSink, Error> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        // The header is written with the first rows, as the columns of the
+        // compared test nodes can't be derived from ResultRow.
+        Ok(CsvSink {
+            wtr: WriterBuilder::new().has_headers(false).from_writer(file),
+            compared_nodes: compared_nodes.to_vec(),
+        })
+    }
+}
+
+impl OutputSink for CsvSink {
+    fn position(&mut self) -> Result<u64, Error> {
+        self.wtr.flush()?;
+        Ok(self.wtr.get_ref().metadata()?.len())
+    }
+
+    // Writes the rows to the output and makes sure they are on disk.
+    fn write_block(
+        &mut self,
+        _height: u64,
+        _hash: &BlockHash,
+        _miner: &str,
+        rows: &[ResultRow],
+    ) -> Result<u64, Error> {
+        if self.wtr.get_ref().metadata()?.len() == 0 {
+            if let Some(row) = rows.first() {
+                self.wtr.write_record(&header(row, &self.compared_nodes)?)?;
+            }
+        }
+        for row in rows.iter() {
+            self.wtr.serialize(row)?;
+        }
+        self.wtr.flush()?;
+        let file = self.wtr.get_ref();
+        file.sync_data()?;
+        Ok(file.metadata()?.len())
+    }
+
+    fn truncate(&mut self, position: u64) -> Result<(), Error> {
+        self.wtr.flush()?;
+        self.wtr.get_ref().set_len(position)?;
+        Ok(())
+    }
+}
+
+/// Reads the rows of the CSV file.
+pub fn read_rows<T: DeserializeOwned>(path: &Path, visit: &mut dyn FnMut(T)) -> Result<(), Error> {
+    for row in ReaderBuilder::new().from_path(path)?.deserialize() {
+        visit(row?);
+    }
+    Ok(())
+}
+
+// The names of the output columns: the fields of ResultRow, named by serde,
+// and a pair of columns for each compared test node.
+fn header(row: &ResultRow, compared_nodes: &[String]) -> Result<StringRecord, Error> {
+    let mut row = row.clone();
+    row.node_verdicts.clear();
+    let mut wtr = Writer::from_writer(vec![]);
+    wtr.serialize(&row)?;
+    let data = wtr.into_inner().map_err(|e| Error::Io(e.into_error()))?;
+    let mut header = ReaderBuilder::new()
+        .from_reader(data.as_slice())
+        .headers()?
+        .clone();
+    for name in compared_nodes.iter() {
+        header.push_field(&format!("{}_allowed", name));
+        header.push_field(&format!("{}_reject_reason", name));
+    }
+    Ok(header)
+}
@@ -0,0 +1,67 @@
+use super::{json_row, OutputSink};
+use crate::error::Error;
+use crate::ResultRow;
+use bitcoincore_rpc::bitcoin::BlockHash;
+use serde::de::DeserializeOwned;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+
+/// Appends the rows to a JSON Lines file, one JSON object per row. The
+/// position is the length of the file.
+pub struct JsonlSink {
+    wtr: BufWriter<File>,
+    compared_nodes: Vec<String>,
+}
+
+impl JsonlSink {
+    pub fn open(path: &Path, compared_nodes: &[String]) -> Result<JsonlSink, Error> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(JsonlSink {
+            wtr: BufWriter::new(file),
+            compared_nodes: compared_nodes.to_vec(),
+        })
+    }
+}
+
+impl OutputSink for JsonlSink {
+    fn position(&mut self) -> Result<u64, Error> {
+        self.wtr.flush()?;
+        Ok(self.wtr.get_ref().metadata()?.len())
+    }
+
+    fn write_block(
+        &mut self,
+        _height: u64,
+        _hash: &BlockHash,
+        _miner: &str,
+        rows: &[ResultRow],
+    ) -> Result<u64, Error> {
+        for row in rows.iter() {
+            serde_json::to_writer(&mut self.wtr, &json_row(row, &self.compared_nodes)?)
+                .map_err(io::Error::from)?;
+            self.wtr.write_all(b"\n")?;
+        }
+        self.wtr.flush()?;
+        let file = self.wtr.get_ref();
+        file.sync_data()?;
+        Ok(file.metadata()?.len())
+    }
+
+    fn truncate(&mut self, position: u64) -> Result<(), Error> {
+        self.wtr.flush()?;
+        self.wtr.get_ref().set_len(position)?;
+        Ok(())
+    }
+}
+
+/// Reads the rows of the JSON Lines file.
+pub fn read_rows<T: DeserializeOwned>(path: &Path, visit: &mut dyn FnMut(T)) -> Result<(), Error> {
+    for line in BufReader::new(File::open(path)?).lines() {
+        let line = line?;
+        if !line.is_empty() {
+            visit(serde_json::from_str(&line).map_err(io::Error::from)?);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,181 @@
+use crate::error::Error;
+use crate::ResultRow;
+use bitcoincore_rpc::bitcoin::BlockHash;
+use config::Config;
+use serde::de::DeserializeOwned;
+use serde_json::{json, Map, Value};
+use std::path::Path;
+
+mod csv;
+mod jsonl;
+#[cfg(feature = "parquet")]
+mod parquet;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+
+pub use self::csv::CsvSink;
+pub use self::jsonl::JsonlSink;
+#[cfg(feature = "parquet")]
+pub use self::parquet::ParquetSink;
+#[cfg(feature = "sqlite")]
+pub use self::sqlite::SqliteSink;
+
+/// Where the rows of the processed blocks are written to. The position of
+/// the output after a block was written is stored in the checkpoint, so that
+/// the output can be truncated to it when blocks are rolled back or a run
+/// crashed before it stored the checkpoint.
+///
+/// What a position is depends on the sink: the length of the file for CSV
+/// and JSON Lines, the height of the last written block for Parquet and the
+/// id of the last written block for SQLite. Positions only grow, but they
+/// can only be compared with positions of the same sink.
+pub trait OutputSink {
+    /// The current position of the output.
+    fn position(&mut self) -> Result<u64, Error>;
+    /// Writes the rows of a processed block and returns the new position of
+    /// the output once they are durable.
+    fn write_block(
+        &mut self,
+        height: u64,
+        hash: &BlockHash,
+        miner: &str,
+        rows: &[ResultRow],
+    ) -> Result<u64, Error>;
+    /// Removes everything written after the position.
+    fn truncate(&mut self, position: u64) -> Result<(), Error>;
+    /// Writes out rows the sink holds back, once a scan stops without an
+    /// error. The position doesn't change.
+    fn finish(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// Opens the output sink for the configured `output`. The format is set with
+/// `output_format`. Without it, outputs ending with `.sqlite`, `.sqlite3` or
+/// `.db` are written as SQLite databases, `.jsonl` or `.ndjson` as JSON Lines,
+/// `.parquet` as a directory of Parquet files and everything else as CSV.
+/// `compared_nodes` are the names of the compared test nodes.
+pub fn from_config(
+    settings: &Config,
+    compared_nodes: &[String],
+) -> Result<Box<dyn OutputSink>, Error> {
+    let (output, format) = output_format(settings)?;
+    let path = Path::new(&output);
+    match format.as_str() {
+        "csv" => Ok(Box::new(CsvSink::open(path, compared_nodes)?)),
+        "jsonl" => Ok(Box::new(JsonlSink::open(path, compared_nodes)?)),
+        #[cfg(feature = "parquet")]
+        "parquet" => {
+            let rows_per_file = settings
+                .get::<usize>("parquet_rows_per_file")
+                .map_err(|e| Error::config("invalid parquet_rows_per_file".to_string(), e))?
+                .max(1);
+            Ok(Box::new(ParquetSink::open(
+                path,
+                compared_nodes,
+                rows_per_file,
+            )?))
+        }
+        #[cfg(feature = "sqlite")]
+        "sqlite" => Ok(Box::new(SqliteSink::open(path, compared_nodes)?)),
+        other => Err(unknown_format(other)),
+    }
+}
+
+/// Reads the rows written to the configured `output` in the order they were
+/// written. `T` picks the fields it needs from the rows.
+pub fn read_rows<T: DeserializeOwned>(
+    settings: &Config,
+    visit: &mut dyn FnMut(T),
+) -> Result<(), Error> {
+    let (output, format) = output_format(settings)?;
+    let path = Path::new(&output);
+    match format.as_str() {
+        "csv" => csv::read_rows(path, visit),
+        "jsonl" => jsonl::read_rows(path, visit),
+        #[cfg(feature = "parquet")]
+        "parquet" => parquet::read_rows(path, visit),
+        #[cfg(feature = "sqlite")]
+        "sqlite" => sqlite::read_rows(path, visit),
+        other => Err(unknown_format(other)),
+    }
+}
+
+/// The configured `output` and its format.
+pub fn output_format(settings: &Config) -> Result<(String, String), Error> {
+    let output = settings
+        .get::<String>("output")
+        .map_err(|e| Error::config("No 'output' defined in the configuration".to_string(), e))?;
+    let format = match settings.get::<String>("output_format") {
+        Ok(format) => format,
+        Err(_) => match Path::new(&output)
+            .extension()
+            .and_then(|extension| extension.to_str())
+        {
+            Some("sqlite" | "sqlite3" | "db") => "sqlite".to_string(),
+            Some("jsonl" | "ndjson") => "jsonl".to_string(),
+            Some("parquet") => "parquet".to_string(),
+            _ => "csv".to_string(),
+        },
+    };
+    Ok((output, format))
+}
+
+fn unknown_format(format: &str) -> Error {
+    match format {
+        "parquet" | "sqlite" => Error::Config(format!(
+            "the '{}' output_format needs a build with `--features {}`",
+            format, format
+        )),
+        _ => Error::Config(format!(
+            "unknown output_format '{}': expected 'csv', 'jsonl', 'parquet' or 'sqlite'",
+            format
+        )),
+    }
+}
+
+// The row as JSON object, as written by the formats with nested fields. The
+// script types are lists, their counts objects keyed by type and the verdicts
+// of the compared test nodes are keyed by the name of the node.
+fn json_row(row: &ResultRow, compared_nodes: &[String]) -> Result<Value, Error> {
+    let mut value = serde_json::to_value(row)
+        .map_err(|e| Error::State(format!("can't serialize row: {}", e)))?;
+    if let Some(object) = value.as_object_mut() {
+        for (key, script_types) in [
+            ("input_types", &row.input_types),
+            ("output_types", &row.output_types),
+        ] {
+            let names: Vec<&str> = script_types.0.iter().map(|t| t.as_str()).collect();
+            object.insert(key.to_string(), json!(names));
+        }
+        for (key, counts) in [
+            ("input_type_counts", &row.input_type_counts),
+            ("output_type_counts", &row.output_type_counts),
+        ] {
+            let counts: Map<String, Value> = counts
+                .0
+                .iter()
+                .map(|(t, count)| (t.as_str().to_string(), json!(count)))
+                .collect();
+            object.insert(key.to_string(), Value::Object(counts));
+        }
+        object.remove("node_verdicts");
+        if !compared_nodes.is_empty() {
+            let verdicts: Map<String, Value> = compared_nodes
+                .iter()
+                .zip(row.node_verdicts.iter())
+                .map(|(name, verdict)| {
+                    (
+                        name.clone(),
+                        json!({
+                            "allowed": verdict.allowed,
+                            "reject_reason": verdict.reject_reason,
+                        }),
+                    )
+                })
+                .collect();
+            object.insert("node_verdicts".to_string(), Value::Object(verdicts));
+        }
+    }
+    Ok(value)
+}
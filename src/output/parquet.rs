@@ -0,0 +1,456 @@
+use super::{json_row, OutputSink};
+use crate::error::Error;
+use crate::ResultRow;
+use arrow_json::{LineDelimitedWriter, ReaderBuilder};
+use arrow_schema::{DataType, Field, Fields, Schema, SchemaRef};
+use bitcoincore_rpc::bitcoin::BlockHash;
+use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+use parquet::arrow::ArrowWriter;
+use parquet::basic::Compression;
+use parquet::file::properties::WriterProperties;
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+// Readers of Parquet datasets skip files starting with '_' or '.'.
+const STAGED_FILE: &str = "_staged.jsonl";
+
+/// Writes the rows to Parquet files in the output directory, which can be
+/// read as one dataset. The rows of the written blocks are staged in a JSON
+/// Lines file until there are `rows_per_file` of them, and are then written
+/// to a Parquet file named after the heights of its first and last row. A
+/// Parquet file is only visible once it's complete. The position is the
+/// height of the last written block with rows.
+pub struct ParquetSink {
+    dir: PathBuf,
+    schema: SchemaRef,
+    compared_nodes: Vec<String>,
+    rows_per_file: usize,
+    staged: File,
+    staged_rows: usize,
+}
+
+impl ParquetSink {
+    pub fn open(
+        path: &Path,
+        compared_nodes: &[String],
+        rows_per_file: usize,
+    ) -> Result<ParquetSink, Error> {
+        fs::create_dir_all(path)?;
+        // Drop the rows that were staged again after they were written to a
+        // Parquet file, if a run crashed before it emptied the staged rows,
+        // and a partially written last row.
+        let written = parts(path)?.last().map(|(_, last)| *last);
+        let staged: Vec<Value> = staged_rows(path)?
+            .into_iter()
+            .filter(|row| Some(height(row)) > written)
+            .collect();
+        write_staged(path, &staged)?;
+        Ok(ParquetSink {
+            dir: path.to_path_buf(),
+            schema: Arc::new(schema(compared_nodes)),
+            compared_nodes: compared_nodes.to_vec(),
+            rows_per_file,
+            staged: open_staged(path)?,
+            staged_rows: staged.len(),
+        })
+    }
+
+    // Writes the staged rows to a Parquet file and empties them.
+    fn write_staged_part(&mut self) -> Result<(), Error> {
+        write_part(&self.dir, &self.schema, &staged_rows(&self.dir)?)?;
+        self.staged.set_len(0)?;
+        self.staged.sync_all()?;
+        self.staged_rows = 0;
+        Ok(())
+    }
+}
+
+impl OutputSink for ParquetSink {
+    fn position(&mut self) -> Result<u64, Error> {
+        let staged = staged_rows(&self.dir)?.last().map(height);
+        let written = parts(&self.dir)?.last().map(|(_, last)| *last);
+        Ok(staged.or(written).unwrap_or(0))
+    }
+
+    fn write_block(
+        &mut self,
+        height: u64,
+        _hash: &BlockHash,
+        _miner: &str,
+        rows: &[ResultRow],
+    ) -> Result<u64, Error> {
+        if rows.is_empty() {
+            return Ok(height);
+        }
+        let mut lines = vec![];
+        for row in rows.iter() {
+            serde_json::to_writer(&mut lines, &json_row(row, &self.compared_nodes)?)
+                .map_err(io::Error::from)?;
+            lines.push(b'\n');
+        }
+        self.staged.write_all(&lines)?;
+        self.staged.sync_data()?;
+        self.staged_rows += rows.len();
+        if self.staged_rows >= self.rows_per_file {
+            self.write_staged_part()?;
+        }
+        Ok(height)
+    }
+
+    fn truncate(&mut self, position: u64) -> Result<(), Error> {
+        let keep = |row: &Value| height(row) <= position;
+        let staged: Vec<Value> = staged_rows(&self.dir)?.into_iter().filter(keep).collect();
+        write_staged(&self.dir, &staged)?;
+        self.staged = open_staged(&self.dir)?;
+        self.staged_rows = staged.len();
+        for (first, last) in parts(&self.dir)? {
+            if last <= position {
+                continue;
+            }
+            // Rewrite a file with rows before and after the position.
+            if first <= position {
+                let mut rows = vec![];
+                read_part(&part_path(&self.dir, first, last), &mut |line| {
+                    rows.push(serde_json::from_slice(line).map_err(io::Error::from)?);
+                    Ok(())
+                })?;
+                rows.retain(keep);
+                write_part(&self.dir, &self.schema, &rows)?;
+            }
+            fs::remove_file(part_path(&self.dir, first, last))?;
+        }
+        Ok(())
+    }
+
+    // The staged rows are written to a shorter file.
+    fn finish(&mut self) -> Result<(), Error> {
+        if self.staged_rows > 0 {
+            self.write_staged_part()?;
+        }
+        Ok(())
+    }
+}
+
+/// Reads the rows of the Parquet files in the directory in height order,
+/// followed by the staged rows.
+pub fn read_rows<T: DeserializeOwned>(dir: &Path, visit: &mut dyn FnMut(T)) -> Result<(), Error> {
+    for (first, last) in parts(dir)? {
+        read_part(&part_path(dir, first, last), &mut |line| {
+            visit(serde_json::from_slice(line).map_err(io::Error::from)?);
+            Ok(())
+        })?;
+    }
+    for row in staged_rows(dir)? {
+        visit(serde_json::from_value(row).map_err(io::Error::from)?);
+    }
+    Ok(())
+}
+
+fn height(row: &Value) -> u64 {
+    row["height"].as_u64().unwrap_or_default()
+}
+
+// The heights of the first and last rows of the Parquet files in the
+// directory, in height order.
+fn parts(dir: &Path) -> Result<Vec<(u64, u64)>, Error> {
+    let mut parts = vec![];
+    for entry in fs::read_dir(dir)? {
+        let name = entry?.file_name();
+        if let Some((first, last)) = name
+            .to_str()
+            .and_then(|name| name.strip_prefix("part-"))
+            .and_then(|name| name.strip_suffix(".parquet"))
+            .and_then(|heights| heights.split_once('-'))
+        {
+            if let (Ok(first), Ok(last)) = (first.parse(), last.parse()) {
+                parts.push((first, last));
+            }
+        }
+    }
+    parts.sort();
+    Ok(parts)
+}
+
+fn part_path(dir: &Path, first: u64, last: u64) -> PathBuf {
+    dir.join(format!("part-{:07}-{:07}.parquet", first, last))
+}
+
+// Writes the rows to a new Parquet file, through a temporary file so that
+// readers never see a half-written file.
+fn write_part(dir: &Path, schema: &SchemaRef, rows: &[Value]) -> Result<(), Error> {
+    let (Some(first), Some(last)) = (rows.first(), rows.last()) else {
+        return Ok(());
+    };
+    let mut decoder = ReaderBuilder::new(schema.clone()).build_decoder()?;
+    decoder.serialize(rows)?;
+    let batch = decoder.flush()?.expect("there are rows");
+
+    let path = part_path(dir, height(first), height(last));
+    let tmp_path = dir.join(format!(".part-{}-{}.tmp", height(first), height(last)));
+    let properties = WriterProperties::builder()
+        .set_compression(Compression::SNAPPY)
+        .build();
+    let mut writer =
+        ArrowWriter::try_new(File::create(&tmp_path)?, schema.clone(), Some(properties))?;
+    writer.write(&batch)?;
+    writer.into_inner()?.sync_all()?;
+    fs::rename(&tmp_path, &path)?;
+    Ok(())
+}
+
+// Visits the rows of a Parquet file as JSON.
+fn read_part(path: &Path, visit: &mut dyn FnMut(&[u8]) -> Result<(), Error>) -> Result<(), Error> {
+    let reader = ParquetRecordBatchReaderBuilder::try_new(File::open(path)?)?.build()?;
+    for batch in reader {
+        let mut writer = LineDelimitedWriter::new(vec![]);
+        writer.write(&batch?)?;
+        writer.finish()?;
+        for line in writer.into_inner().split(|byte| *byte == b'\n') {
+            if !line.is_empty() {
+                visit(line)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn open_staged(dir: &Path) -> Result<File, Error> {
+    Ok(OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(dir.join(STAGED_FILE))?)
+}
+
+// The staged rows. A partially written last row is ignored.
+fn staged_rows(dir: &Path) -> Result<Vec<Value>, Error> {
+    let data = match fs::read(dir.join(STAGED_FILE)) {
+        Ok(data) => data,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e.into()),
+    };
+    let complete = data
+        .iter()
+        .rposition(|byte| *byte == b'\n')
+        .map_or(0, |end| end + 1);
+    data[..complete]
+        .split(|byte| *byte == b'\n')
+        .filter(|line| !line.is_empty())
+        .map(|line| Ok(serde_json::from_slice(line).map_err(io::Error::from)?))
+        .collect()
+}
+
+// Replaces the staged rows.
+fn write_staged(dir: &Path, rows: &[Value]) -> Result<(), Error> {
+    let path = dir.join(STAGED_FILE);
+    let tmp_path = dir.join(".staged.jsonl.tmp");
+    let mut file = File::create(&tmp_path)?;
+    for row in rows {
+        serde_json::to_writer(&mut file, row).map_err(io::Error::from)?;
+        file.write_all(b"\n")?;
+    }
+    file.sync_all()?;
+    fs::rename(&tmp_path, &path)?;
+    Ok(())
+}
+
+// The columns of the Parquet files. They follow the fields of ResultRow, with
+// the verdicts of the compared test nodes as a struct keyed by node name.
+fn schema(compared_nodes: &[String]) -> Schema {
+    let mut fields = vec![
+        Field::new("height", DataType::UInt64, false),
+        Field::new("miner", DataType::Utf8, false),
+        Field::new("block_position", DataType::UInt64, false),
+        Field::new("reject_reason", DataType::Utf8, false),
+        Field::new("reject_code", DataType::Utf8, true),
+        Field::new("reject_category", DataType::Utf8, true),
+        Field::new("reject_detail", DataType::Utf8, true),
+        Field::new("txid", DataType::Utf8, false),
+        Field::new("vsize", DataType::UInt64, false),
+        Field::new("weight", DataType::UInt64, false),
+        Field::new("base_size", DataType::UInt64, false),
+        Field::new("witness_size", DataType::UInt64, false),
+        Field::new("inputs", DataType::UInt64, false),
+        Field::new("outputs", DataType::UInt64, false),
+        script_types("input_types"),
+        script_type_counts("input_type_counts"),
+        script_types("output_types"),
+        script_type_counts("output_type_counts"),
+        Field::new("fee", DataType::UInt64, false),
+        Field::new("fee_known", DataType::Boolean, false),
+        Field::new("feerate", DataType::Float64, true),
+        Field::new("block_median_feerate", DataType::Float64, true),
+        Field::new("median_feerate_ratio", DataType::Float64, true),
+        Field::new("likely_out_of_band", DataType::Boolean, false),
+        Field::new("out_of_band_evidence", DataType::Utf8, false),
+        Field::new("data_embedding", DataType::Utf8, false),
+        Field::new("data_payload_size", DataType::UInt64, false),
+        Field::new("package_allowed", DataType::Boolean, true),
+        Field::new("package_reject_reason", DataType::Utf8, true),
+        Field::new("rejected_ancestor", DataType::Utf8, true),
+        Field::new("ancestor_reject_reason", DataType::Utf8, true),
+        Field::new("relaxed_allowed", DataType::Boolean, true),
+        Field::new("relaxed_reject_reason", DataType::Utf8, true),
+        Field::new("policy_violations", DataType::Utf8, false),
+        Field::new("policies_disagree", DataType::Boolean, true),
+    ];
+    if !compared_nodes.is_empty() {
+        let verdict = Fields::from(vec![
+            Field::new("allowed", DataType::Boolean, false),
+            Field::new("reject_reason", DataType::Utf8, true),
+        ]);
+        let nodes: Vec<Field> = compared_nodes
+            .iter()
+            .map(|name| Field::new(name, DataType::Struct(verdict.clone()), false))
+            .collect();
+        fields.push(Field::new(
+            "node_verdicts",
+            DataType::Struct(Fields::from(nodes)),
+            false,
+        ));
+    }
+    Schema::new(fields)
+}
+
+fn script_types(name: &str) -> Field {
+    Field::new_list(name, Field::new("item", DataType::Utf8, false), false)
+}
+
+fn script_type_counts(name: &str) -> Field {
+    Field::new_map(
+        name,
+        "entries",
+        Field::new("keys", DataType::Utf8, false),
+        Field::new("values", DataType::UInt64, false),
+        false,
+        false,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoincore_rpc::bitcoin::hashes::Hash;
+    use bitcoincore_rpc::bitcoin::Txid;
+
+    fn heights(dir: &Path) -> Vec<u64> {
+        let mut heights = vec![];
+        read_rows(dir, &mut |row: Value| heights.push(height(&row))).unwrap();
+        heights
+    }
+
+    #[test]
+    fn rolls_over_by_row_count_and_truncates() {
+        let dir = std::env::temp_dir().join(format!("non-standard-parquet-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let hash = BlockHash::all_zeros();
+        let mut sink = ParquetSink::open(&dir, &[], 3).unwrap();
+        sink.write_block(
+            1,
+            &hash,
+            "",
+            &[
+                ResultRow::rejected(1, Txid::all_zeros()),
+                ResultRow::rejected(1, Txid::all_zeros()),
+            ],
+        )
+        .unwrap();
+        sink.write_block(2, &hash, "", &[]).unwrap();
+        sink.write_block(
+            3,
+            &hash,
+            "",
+            &[
+                ResultRow::rejected(3, Txid::all_zeros()),
+                ResultRow::rejected(3, Txid::all_zeros()),
+            ],
+        )
+        .unwrap();
+        sink.write_block(4, &hash, "", &[ResultRow::rejected(4, Txid::all_zeros())])
+            .unwrap();
+        assert_eq!(parts(&dir).unwrap(), vec![(1, 3)]);
+        assert_eq!(sink.position().unwrap(), 4);
+        assert_eq!(heights(&dir), vec![1, 1, 3, 3, 4]);
+
+        // A partially written row is dropped when reopening.
+        fs::OpenOptions::new()
+            .append(true)
+            .open(dir.join(STAGED_FILE))
+            .unwrap()
+            .write_all(b"{\"height\":5,")
+            .unwrap();
+        let mut sink = ParquetSink::open(&dir, &[], 3).unwrap();
+        assert_eq!(sink.position().unwrap(), 4);
+        assert_eq!(heights(&dir), vec![1, 1, 3, 3, 4]);
+
+        sink.truncate(1).unwrap();
+        assert_eq!(parts(&dir).unwrap(), vec![(1, 1)]);
+        assert_eq!(sink.position().unwrap(), 1);
+        assert_eq!(heights(&dir), vec![1, 1]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn finishing_writes_the_staged_rows() {
+        let dir = std::env::temp_dir().join(format!(
+            "non-standard-parquet-finish-{}",
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        let hash = BlockHash::all_zeros();
+        let mut sink = ParquetSink::open(&dir, &[], 100).unwrap();
+        sink.write_block(1, &hash, "", &[ResultRow::rejected(1, Txid::all_zeros())])
+            .unwrap();
+        sink.write_block(
+            2,
+            &hash,
+            "",
+            &[
+                ResultRow::rejected(2, Txid::all_zeros()),
+                ResultRow::rejected(2, Txid::all_zeros()),
+            ],
+        )
+        .unwrap();
+        assert!(parts(&dir).unwrap().is_empty());
+        sink.finish().unwrap();
+        assert_eq!(parts(&dir).unwrap(), vec![(1, 2)]);
+        assert!(staged_rows(&dir).unwrap().is_empty());
+        assert_eq!(sink.position().unwrap(), 2);
+
+        let mut heights = vec![];
+        read_part(&part_path(&dir, 1, 2), &mut |line| {
+            let row: Value = serde_json::from_slice(line).map_err(io::Error::from)?;
+            heights.push(height(&row));
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(heights, vec![1, 2, 2]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn drops_staged_rows_already_written() {
+        let dir = std::env::temp_dir().join(format!(
+            "non-standard-parquet-restage-{}",
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        let hash = BlockHash::all_zeros();
+        let mut sink = ParquetSink::open(&dir, &[], 2).unwrap();
+        sink.write_block(1, &hash, "", &[ResultRow::rejected(1, Txid::all_zeros())])
+            .unwrap();
+        let staged = fs::read(dir.join(STAGED_FILE)).unwrap();
+        sink.write_block(2, &hash, "", &[ResultRow::rejected(2, Txid::all_zeros())])
+            .unwrap();
+        // A crash after writing the Parquet file, before emptying the
+        // staged rows.
+        fs::write(dir.join(STAGED_FILE), staged).unwrap();
+        let mut sink = ParquetSink::open(&dir, &[], 2).unwrap();
+        assert_eq!(sink.position().unwrap(), 2);
+        assert_eq!(heights(&dir), vec![1, 2]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,146 @@
+use super::{json_row, OutputSink};
+use crate::error::Error;
+use crate::ResultRow;
+use bitcoincore_rpc::bitcoin::BlockHash;
+use rusqlite::{params, Connection, OpenFlags};
+use serde::de::DeserializeOwned;
+use std::io;
+use std::path::Path;
+
+// A row in `blocks` for every processed block marks it as processed. The
+// full row is stored as JSON in `transactions.row`, so that the columns
+// without a table column of their own can be queried with SQLite's JSON
+// functions.
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS blocks (
+        id INTEGER PRIMARY KEY,
+        height INTEGER NOT NULL,
+        hash TEXT NOT NULL,
+        miner TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS blocks_height ON blocks (height);
+    CREATE INDEX IF NOT EXISTS blocks_miner ON blocks (miner);
+
+    CREATE TABLE IF NOT EXISTS reject_reasons (
+        code TEXT PRIMARY KEY,
+        category TEXT NOT NULL
+    );
+
+    CREATE TABLE IF NOT EXISTS transactions (
+        block_id INTEGER NOT NULL REFERENCES blocks (id),
+        height INTEGER NOT NULL,
+        txid TEXT NOT NULL,
+        reject_reason TEXT NOT NULL,
+        reject_code TEXT REFERENCES reject_reasons (code),
+        reject_detail TEXT,
+        vsize INTEGER NOT NULL,
+        inputs INTEGER NOT NULL,
+        outputs INTEGER NOT NULL,
+        fee INTEGER NOT NULL,
+        policy_violations TEXT NOT NULL,
+        row TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS transactions_block_id ON transactions (block_id);
+    CREATE INDEX IF NOT EXISTS transactions_height ON transactions (height);
+    CREATE INDEX IF NOT EXISTS transactions_txid ON transactions (txid);
+    CREATE INDEX IF NOT EXISTS transactions_reject_code ON transactions (reject_code);
+";
+
+/// Writes the rows to a SQLite database with tables for the blocks, the
+/// transactions and the reject reasons. Each block is written in a single
+/// database transaction. The position is the id of the last written block.
+pub struct SqliteSink {
+    connection: Connection,
+    compared_nodes: Vec<String>,
+}
+
+impl SqliteSink {
+    pub fn open(path: &Path, compared_nodes: &[String]) -> Result<SqliteSink, Error> {
+        let connection = Connection::open(path)?;
+        connection.execute_batch(SCHEMA)?;
+        Ok(SqliteSink {
+            connection,
+            compared_nodes: compared_nodes.to_vec(),
+        })
+    }
+}
+
+impl OutputSink for SqliteSink {
+    fn position(&mut self) -> Result<u64, Error> {
+        let id: i64 =
+            self.connection
+                .query_row("SELECT COALESCE(MAX(id), 0) FROM blocks", [], |row| {
+                    row.get(0)
+                })?;
+        Ok(id as u64)
+    }
+
+    fn write_block(
+        &mut self,
+        height: u64,
+        hash: &BlockHash,
+        miner: &str,
+        rows: &[ResultRow],
+    ) -> Result<u64, Error> {
+        let transaction = self.connection.transaction()?;
+        transaction.execute(
+            "INSERT INTO blocks (height, hash, miner) VALUES (?1, ?2, ?3)",
+            params![height, hash.to_string(), miner],
+        )?;
+        let block_id = transaction.last_insert_rowid();
+        for row in rows.iter() {
+            if let Some(reject_code) = &row.reject_code {
+                transaction.execute(
+                    "INSERT OR IGNORE INTO reject_reasons (code, category) VALUES (?1, ?2)",
+                    params![reject_code.code(), reject_code.category().as_str()],
+                )?;
+            }
+            let json = json_row(row, &self.compared_nodes)?.to_string();
+            transaction.execute(
+                "INSERT INTO transactions (block_id, height, txid, reject_reason, reject_code,
+                    reject_detail, vsize, inputs, outputs, fee, policy_violations, row)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)",
+                params![
+                    block_id,
+                    row.height,
+                    row.txid.to_string(),
+                    row.reject_reason,
+                    row.reject_code.as_ref().map(|reason| reason.code()),
+                    row.reject_detail,
+                    row.vsize,
+                    row.inputs,
+                    row.outputs,
+                    row.fee,
+                    row.policy_violations,
+                    json,
+                ],
+            )?;
+        }
+        transaction.commit()?;
+        Ok(block_id as u64)
+    }
+
+    fn truncate(&mut self, position: u64) -> Result<(), Error> {
+        let transaction = self.connection.transaction()?;
+        transaction.execute(
+            "DELETE FROM transactions WHERE block_id > ?1",
+            params![position],
+        )?;
+        transaction.execute("DELETE FROM blocks WHERE id > ?1", params![position])?;
+        transaction.commit()?;
+        Ok(())
+    }
+}
+
+/// Reads the rows stored as JSON in `transactions.row`, in the order they
+/// were written.
+pub fn read_rows<T: DeserializeOwned>(path: &Path, visit: &mut dyn FnMut(T)) -> Result<(), Error> {
+    let connection = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    let mut statement = connection.prepare("SELECT row FROM transactions ORDER BY rowid")?;
+    let mut rows = statement.query([])?;
+    while let Some(row) = rows.next()? {
+        let row: String = row.get(0)?;
+        visit(serde_json::from_str(&row).map_err(io::Error::from)?);
+    }
+    Ok(())
+}
@@ -0,0 +1,261 @@
+use crate::engine::Verdict;
+use crate::error::Error;
+use crate::retry::RetryPolicy;
+use crate::MAX_FEE;
+use bitcoincore_rpc::bitcoin::consensus::encode::serialize_hex;
+use bitcoincore_rpc::bitcoin::{Transaction, Txid};
+use bitcoincore_rpc::{jsonrpc, Client, RpcApi};
+use std::collections::{HashMap, HashSet};
+
+// Bitcoin Core's MAX_PACKAGE_COUNT
+const MAX_PACKAGE_COUNT: usize = 25;
+const PACKAGE_SUBMITTED: &str = "success";
+
+// The result of submitpackage. The transaction results are keyed by wtxid
+// and have an error if the transaction was rejected.
+#[derive(serde::Deserialize)]
+struct SubmitPackageResult {
+    package_msg: String,
+    #[serde(rename = "tx-results", default)]
+    tx_results: HashMap<String, SubmitPackageTxResult>,
+}
+
+#[derive(serde::Deserialize)]
+struct SubmitPackageTxResult {
+    txid: Txid,
+    error: Option<String>,
+}
+
+/// The result of testing a transaction together with its rejected in-block
+/// parents as a package.
+#[derive(Debug, Clone)]
+pub struct PackageVerdict {
+    /// The verdicts on the transactions, in package order. Some can be
+    /// accepted although the package as a whole isn't.
+    pub tx_verdicts: Vec<Verdict>,
+}
+
+/// Returns the package formed by the transaction and its in-block parents
+/// that aren't in the mempool of the test node, because they were rejected.
+/// Bitcoin Core only accepts child-with-parents packages, so None is returned
+/// if a rejected parent depends on another rejected transaction or if the
+/// package would be too large.
+pub fn child_with_rejected_parents<'a>(
+    tx: &'a Transaction,
+    rejected: &HashMap<Txid, &'a Transaction>,
+) -> Option<Vec<&'a Transaction>> {
+    let parents: HashSet<Txid> = tx
+        .input
+        .iter()
+        .map(|input| input.previous_output.txid)
+        .filter(|txid| rejected.contains_key(txid))
+        .collect();
+    if parents.is_empty() || parents.len() + 1 > MAX_PACKAGE_COUNT {
+        return None;
+    }
+
+    let mut package: Vec<&Transaction> = rejected
+        .iter()
+        .filter(|(txid, _)| parents.contains(*txid))
+        .map(|(_, parent)| *parent)
+        .collect();
+    let parent_depends_on_rejected = package.iter().any(|parent| {
+        parent
+            .input
+            .iter()
+            .any(|input| rejected.contains_key(&input.previous_output.txid))
+    });
+    if parent_depends_on_rejected {
+        return None;
+    }
+    // Parents don't depend on each other, so any order works as long as the
+    // child comes last. Sort them to be deterministic.
+    package.sort_by_key(|parent| parent.txid());
+    package.push(tx);
+    Some(package)
+}
+
+/// Submits the package to the node with submitpackage. It isn't tested with
+/// testmempoolaccept first, as testmempoolaccept doesn't consider the
+/// package feerate, so a low-fee parent with a child paying for it would be
+/// rejected.
+pub fn submit(
+    node: &Client,
+    retry: &RetryPolicy,
+    package: &[&Transaction],
+) -> Result<PackageVerdict, Error> {
+    let hexes: Vec<serde_json::Value> =
+        package.iter().map(|tx| serialize_hex(*tx).into()).collect();
+    let max_fee_rate = serde_json::Value::from(MAX_FEE.to_btc());
+
+    let result = retry.retry("submitpackage", || {
+        node.call::<SubmitPackageResult>(
+            "submitpackage",
+            &[
+                hexes.clone().into(),
+                max_fee_rate.clone(),
+                max_fee_rate.clone(),
+            ],
+        )
+    });
+    // Before v28.0, Bitcoin Core answers a rejected package with an error.
+    let result = match result {
+        Ok(result) => result,
+        Err(Error::Rpc(bitcoincore_rpc::Error::JsonRpc(jsonrpc::Error::Rpc(e)))) => {
+            SubmitPackageResult {
+                package_msg: e.message,
+                tx_results: HashMap::new(),
+            }
+        }
+        Err(e) => return Err(e),
+    };
+    let allowed = result.package_msg == PACKAGE_SUBMITTED;
+    let errors: HashMap<Txid, Option<String>> = result
+        .tx_results
+        .into_values()
+        .map(|tx_result| (tx_result.txid, tx_result.error))
+        .collect();
+    let tx_verdicts = package
+        .iter()
+        .map(|tx| match errors.get(&tx.txid()) {
+            Some(error) => Verdict {
+                allowed: error.is_none(),
+                reject_reason: error.clone(),
+            },
+            None => Verdict {
+                allowed,
+                reject_reason: (!allowed).then(|| result.package_msg.clone()),
+            },
+        })
+        .collect();
+    Ok(PackageVerdict { tx_verdicts })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_node::{self, mock_node};
+    use bitcoincore_rpc::bitcoin::hashes::Hash;
+    use bitcoincore_rpc::bitcoin::{
+        absolute, transaction, Amount, OutPoint, ScriptBuf, TxIn, TxOut,
+    };
+    use serde_json::json;
+
+    fn spending(outpoints: &[(Txid, u32)], value: u64) -> Transaction {
+        Transaction {
+            version: transaction::Version::TWO,
+            lock_time: absolute::LockTime::ZERO,
+            input: outpoints
+                .iter()
+                .map(|(txid, vout)| TxIn {
+                    previous_output: OutPoint::new(*txid, *vout),
+                    ..Default::default()
+                })
+                .collect(),
+            output: vec![TxOut {
+                value: Amount::from_sat(value),
+                script_pubkey: ScriptBuf::new(),
+            }],
+        }
+    }
+
+    fn client(url: &str) -> Client {
+        crate::rpc_client(&mock_node::settings(url), "test").expect("can connect")
+    }
+
+    #[test]
+    fn forms_packages_of_a_child_with_its_rejected_parents() {
+        let confirmed = Txid::all_zeros();
+        let grandparent = spending(&[(confirmed, 0)], 3000);
+        let parent_a = spending(&[(confirmed, 1)], 2000);
+        let parent_b = spending(&[(confirmed, 2)], 1000);
+        let child = spending(&[(parent_a.txid(), 0), (parent_b.txid(), 0)], 500);
+
+        let none = HashMap::new();
+        assert!(child_with_rejected_parents(&child, &none).is_none());
+
+        let rejected = HashMap::from([(parent_a.txid(), &parent_a), (parent_b.txid(), &parent_b)]);
+        let package = child_with_rejected_parents(&child, &rejected).unwrap();
+        let mut parents = [parent_a.txid(), parent_b.txid()];
+        parents.sort();
+        let txids: Vec<Txid> = package.iter().map(|tx| tx.txid()).collect();
+        assert_eq!(txids, [parents[0], parents[1], child.txid()]);
+
+        // Bitcoin Core doesn't accept packages with grandparents.
+        let parent = spending(&[(grandparent.txid(), 0)], 2000);
+        let child = spending(&[(parent.txid(), 0)], 1000);
+        let rejected =
+            HashMap::from([(grandparent.txid(), &grandparent), (parent.txid(), &parent)]);
+        assert!(child_with_rejected_parents(&child, &rejected).is_none());
+    }
+
+    #[test]
+    fn accepts_a_parent_paid_for_by_its_child() {
+        // The parent alone pays too little fee, but the package pays enough.
+        // It mustn't be tested with testmempoolaccept, which would reject the
+        // parent.
+        let parent = spending(&[(Txid::all_zeros(), 0)], 1000);
+        let child = spending(&[(parent.txid(), 0)], 500);
+        let url = mock_node(|method, params| match method {
+            "submitpackage" => {
+                let results: serde_json::Map<String, serde_json::Value> = params[0]
+                    .as_array()
+                    .expect("is a list of transactions")
+                    .iter()
+                    .map(|hex| {
+                        let tx = mock_node::tx(hex);
+                        (tx.wtxid().to_string(), json!({"txid": tx.txid()}))
+                    })
+                    .collect();
+                Ok(json!({"package_msg": "success", "tx-results": results}))
+            }
+            _ => Err(format!("unexpected call to {}", method)),
+        });
+
+        let verdict = submit(&client(&url), &mock_node::retry(), &[&parent, &child]).unwrap();
+        assert_eq!(verdict.tx_verdicts.len(), 2);
+        assert!(verdict.tx_verdicts.iter().all(|verdict| verdict.allowed));
+    }
+
+    #[test]
+    fn reports_the_rejected_transactions_of_a_package() {
+        let parent = spending(&[(Txid::all_zeros(), 0)], 1000);
+        let child = spending(&[(parent.txid(), 0)], 999);
+        let (parent_txid, parent_wtxid) = (parent.txid(), parent.wtxid());
+        let (child_txid, child_wtxid) = (child.txid(), child.wtxid());
+        let url = mock_node(move |_, _| {
+            Ok(json!({
+                "package_msg": "transaction failed",
+                "tx-results": {
+                    parent_wtxid.to_string(): {"txid": parent_txid},
+                    child_wtxid.to_string(): {"txid": child_txid, "error": "min relay fee not met"},
+                },
+            }))
+        });
+
+        let verdict = submit(&client(&url), &mock_node::retry(), &[&parent, &child]).unwrap();
+        assert!(verdict.tx_verdicts[0].allowed);
+        assert!(!verdict.tx_verdicts[1].allowed);
+        assert_eq!(
+            verdict.tx_verdicts[1].reject_reason.as_deref(),
+            Some("min relay fee not met")
+        );
+    }
+
+    #[test]
+    fn rejects_the_whole_package_on_an_rpc_error() {
+        // Bitcoin Core before v28.0
+        let parent = spending(&[(Txid::all_zeros(), 0)], 1000);
+        let child = spending(&[(parent.txid(), 0)], 500);
+        let url = mock_node(|_, _| Err("package-not-child-with-unconfirmed-parents".to_string()));
+
+        let verdict = submit(&client(&url), &mock_node::retry(), &[&parent, &child]).unwrap();
+        for verdict in &verdict.tx_verdicts {
+            assert!(!verdict.allowed);
+            assert_eq!(
+                verdict.reject_reason.as_deref(),
+                Some("package-not-child-with-unconfirmed-parents")
+            );
+        }
+    }
+}
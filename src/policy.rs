@@ -0,0 +1,467 @@
+use bitcoincore_rpc::bitcoin::blockdata::script::Instruction;
+use bitcoincore_rpc::bitcoin::{Amount, Script, Transaction, TxOut, Witness};
+
+// The default policy limits of Bitcoin Core v29.0 (see src/policy/policy.h
+// and src/policy/truc_policy.h). Older versions don't accept version 3
+// (TRUC) transactions.
+const TX_MAX_STANDARD_VERSION: i32 = 3;
+const TRUC_VERSION: i32 = 3;
+const TRUC_MAX_VSIZE: usize = 10_000;
+const MAX_STANDARD_TX_WEIGHT: u64 = 400_000;
+const MIN_STANDARD_TX_NONWITNESS_SIZE: usize = 65;
+const MAX_STANDARD_SCRIPTSIG_SIZE: usize = 1650;
+pub const MAX_OP_RETURN_RELAY: usize = 83;
+const MAX_STANDARD_TX_SIGOPS_COST: usize = 16_000;
+const MAX_P2SH_SIGOPS: usize = 15;
+const MAX_STANDARD_P2WSH_SCRIPT_SIZE: usize = 3600;
+const MAX_STANDARD_P2WSH_STACK_ITEMS: usize = 100;
+const MAX_STANDARD_P2WSH_STACK_ITEM_SIZE: usize = 80;
+const MAX_STANDARD_TAPSCRIPT_STACK_ITEM_SIZE: usize = 80;
+pub const MIN_RELAY_TX_FEE_SAT_PER_KVB: u64 = 1000;
+pub const TAPROOT_ANNEX_TAG: u8 = 0x50;
+const TAPROOT_LEAF_MASK: u8 = 0xfe;
+const TAPROOT_LEAF_TAPSCRIPT: u8 = 0xc0;
+
+/// Runs the standardness checks of Bitcoin Core (IsStandardTx(),
+/// AreInputsStandard(), IsWitnessStandard() and the checks of the mempool's
+/// PreChecks()) with the default policy and returns the reject reasons of
+/// all violated rules. Bitcoin Core stops at the first violation and only
+/// reports that one.
+///
+/// The checks of the inputs need the outputs spent by the transaction. They
+/// are skipped when the prevouts are unknown. Script verification with the
+/// policy flags (non-mandatory-script-verify-flag) isn't done.
+pub fn violations(tx: &Transaction, prevouts: Option<&[TxOut]>) -> Vec<&'static str> {
+    let mut violations = vec![];
+    let mut violation = |reason: &'static str| {
+        if !violations.contains(&reason) {
+            violations.push(reason);
+        }
+    };
+
+    // IsStandardTx()
+    if tx.version.0 > TX_MAX_STANDARD_VERSION || tx.version.0 < 1 {
+        violation("version");
+    }
+    if tx.weight().to_wu() > MAX_STANDARD_TX_WEIGHT {
+        violation("tx-size");
+    }
+    for input in tx.input.iter() {
+        if input.script_sig.len() > MAX_STANDARD_SCRIPTSIG_SIZE {
+            violation("scriptsig-size");
+        }
+        if !input.script_sig.is_push_only() {
+            violation("scriptsig-not-pushonly");
+        }
+    }
+    let mut data_outputs = 0;
+    for output in tx.output.iter() {
+        match output_type(&output.script_pubkey) {
+            OutputType::NonStandard => violation("scriptpubkey"),
+            OutputType::NullData => data_outputs += 1,
+            OutputType::Standard => (),
+        }
+    }
+    if data_outputs > 1 {
+        violation("multi-op-return");
+    }
+    if tx.output.iter().any(is_dust) {
+        violation("dust");
+    }
+
+    // PreChecks()
+    if tx.base_size() < MIN_STANDARD_TX_NONWITNESS_SIZE {
+        violation("tx-size-small");
+    }
+    // The TRUC rules on the ancestors and descendants need the mempool.
+    if tx.version.0 == TRUC_VERSION && tx.vsize() > TRUC_MAX_VSIZE {
+        violation("TRUC-violation");
+    }
+
+    let Some(prevouts) = prevouts else {
+        return violations;
+    };
+    if prevouts.len() != tx.input.len() {
+        return violations;
+    }
+
+    if !are_inputs_standard(tx, prevouts) {
+        violation("bad-txns-nonstandard-inputs");
+    }
+    if !is_witness_standard(tx, prevouts) {
+        violation("bad-witness-nonstandard");
+    }
+    let sigop_cost = tx.total_sigop_cost(|outpoint| {
+        tx.input
+            .iter()
+            .position(|input| input.previous_output == *outpoint)
+            .map(|index| prevouts[index].clone())
+    });
+    if sigop_cost > MAX_STANDARD_TX_SIGOPS_COST {
+        violation("bad-txns-too-many-sigops");
+    }
+
+    let input_value: Amount = prevouts.iter().map(|prevout| prevout.value).sum();
+    let output_value: Amount = tx.output.iter().map(|output| output.value).sum();
+    let min_fee = Amount::from_sat(tx.vsize() as u64 * MIN_RELAY_TX_FEE_SAT_PER_KVB / 1000);
+    if input_value < output_value + min_fee {
+        violation("min relay fee not met");
+    }
+
+    violations
+}
+
+enum OutputType {
+    Standard,
+    NullData,
+    NonStandard,
+}
+
+// The script types of Bitcoin Core's Solver() (see src/script/solver.cpp).
+enum TxoutType {
+    NonStandard,
+    PubKey,
+    PubKeyHash,
+    ScriptHash,
+    Multisig { keys: u8 },
+    NullData,
+    WitnessV0KeyHash,
+    WitnessV0ScriptHash,
+    WitnessV1Taproot,
+    Anchor,
+    WitnessUnknown,
+}
+
+// See Solver() in Bitcoin Core's solver.cpp
+fn solve(script: &Script) -> TxoutType {
+    if script.is_p2sh() {
+        return TxoutType::ScriptHash;
+    }
+    if script.is_witness_program() {
+        let program_len = script.len() - 2;
+        return match script.witness_version().map(|v| v.to_num()) {
+            Some(0) if program_len == 20 => TxoutType::WitnessV0KeyHash,
+            Some(0) if program_len == 32 => TxoutType::WitnessV0ScriptHash,
+            Some(0) => TxoutType::NonStandard,
+            Some(1) if program_len == 32 => TxoutType::WitnessV1Taproot,
+            _ if is_pay_to_anchor(script) => TxoutType::Anchor,
+            _ => TxoutType::WitnessUnknown,
+        };
+    }
+    if script.is_op_return() && Script::from_bytes(&script.as_bytes()[1..]).is_push_only() {
+        return TxoutType::NullData;
+    }
+    let bytes = script.as_bytes();
+    let is_p2pk = matches!(bytes.len(), 35 | 67)
+        && usize::from(bytes[0]) == bytes.len() - 2
+        && is_valid_public_key_size(&bytes[1..bytes.len() - 1])
+        && bytes.last() == Some(&0xac); // OP_CHECKSIG
+    if is_p2pk {
+        return TxoutType::PubKey;
+    }
+    if script.is_p2pkh() {
+        return TxoutType::PubKeyHash;
+    }
+    match multisig_keys(script) {
+        Some(keys) => TxoutType::Multisig { keys },
+        None => TxoutType::NonStandard,
+    }
+}
+
+// Whether the size of the public key matches its first byte, like Bitcoin
+// Core's CPubKey::ValidSize(). The key doesn't need to be a valid point.
+fn is_valid_public_key_size(key: &[u8]) -> bool {
+    match key.first() {
+        Some(2 | 3) => key.len() == 33,
+        Some(4 | 6 | 7) => key.len() == 65,
+        _ => false,
+    }
+}
+
+// The number of public keys of an m-of-n multisig script
+// `<m> <key>... <n> OP_CHECKMULTISIG`, with 1 <= m <= n <= 20. See
+// MatchMultisig() in Bitcoin Core's solver.cpp.
+fn multisig_keys(script: &Script) -> Option<u8> {
+    const MAX_PUBKEYS_PER_MULTISIG: u8 = 20;
+    const OP_CHECKMULTISIG: u8 = 0xae;
+    // OP_1 to OP_16, or a minimal push of 17 to 20.
+    let number = |index: usize, instruction: &Instruction| match instruction {
+        Instruction::Op(op) if (0x51..=0x60).contains(&op.to_u8()) => Some(op.to_u8() - 0x50),
+        Instruction::PushBytes(bytes)
+            if script.as_bytes()[index] == 0x01 && (17..=20).contains(&bytes.as_bytes()[0]) =>
+        {
+            Some(bytes.as_bytes()[0])
+        }
+        _ => None,
+    };
+    let instructions: Vec<(usize, Instruction)> = script
+        .instruction_indices()
+        .collect::<Result<_, _>>()
+        .ok()?;
+    let (index, instruction) = instructions.first()?;
+    let required = number(*index, instruction)?;
+    let keys = instructions[1..]
+        .iter()
+        .take_while(|(_, instruction)| match instruction {
+            Instruction::PushBytes(key) => is_valid_public_key_size(key.as_bytes()),
+            Instruction::Op(_) => false,
+        })
+        .count();
+    // The number of keys must be followed by OP_CHECKMULTISIG only.
+    match instructions.last() {
+        Some((_, Instruction::Op(op)))
+            if op.to_u8() == OP_CHECKMULTISIG && instructions.len() == keys + 3 => {}
+        _ => return None,
+    }
+    let (index, instruction) = &instructions[keys + 1];
+    let n = number(*index, instruction)?;
+    match usize::from(n) == keys && required <= n && n <= MAX_PUBKEYS_PER_MULTISIG {
+        true => Some(n),
+        false => None,
+    }
+}
+
+// The output types IsStandard() accepts. Bare multisig is accepted, as
+// -permitbaremultisig defaults to true, but only with up to three keys.
+// Unknown witness versions are standard, as they are reserved for future
+// upgrades.
+fn output_type(script: &Script) -> OutputType {
+    match solve(script) {
+        TxoutType::NonStandard => OutputType::NonStandard,
+        TxoutType::Multisig { keys } if keys > 3 => OutputType::NonStandard,
+        TxoutType::NullData if script.len() > MAX_OP_RETURN_RELAY => OutputType::NonStandard,
+        TxoutType::NullData => OutputType::NullData,
+        _ => OutputType::Standard,
+    }
+}
+
+/// Whether the script is the pay-to-anchor (P2A) output `OP_1 <0x4e73>`,
+/// which anyone can spend.
+pub fn is_pay_to_anchor(script: &Script) -> bool {
+    script.as_bytes() == [0x51, 0x02, 0x4e, 0x73]
+}
+
+fn is_dust(output: &TxOut) -> bool {
+    !output.script_pubkey.is_op_return() && output.value < output.script_pubkey.dust_value()
+}
+
+// The last item pushed by a push-only scriptSig, i.e. the redeem script of a
+// P2SH input.
+pub fn redeem_script(script_sig: &Script) -> Option<&Script> {
+    match script_sig.instructions().last()? {
+        Ok(Instruction::PushBytes(bytes)) => Some(Script::from_bytes(bytes.as_bytes())),
+        _ => None,
+    }
+}
+
+// See AreInputsStandard() in Bitcoin Core's policy.cpp. Unlike outputs,
+// any script Solver() knows can be spent, e.g. bare multisig with more than
+// three keys, except for unknown witness versions.
+fn are_inputs_standard(tx: &Transaction, prevouts: &[TxOut]) -> bool {
+    tx.input
+        .iter()
+        .zip(prevouts)
+        .all(|(input, prevout)| match solve(&prevout.script_pubkey) {
+            TxoutType::NonStandard | TxoutType::WitnessUnknown => false,
+            TxoutType::ScriptHash => match redeem_script(&input.script_sig) {
+                Some(redeem_script) => redeem_script.count_sigops() <= MAX_P2SH_SIGOPS,
+                None => false,
+            },
+            _ => true,
+        })
+}
+
+// See IsWitnessStandard() in Bitcoin Core's policy.cpp
+fn is_witness_standard(tx: &Transaction, prevouts: &[TxOut]) -> bool {
+    tx.input.iter().zip(prevouts).all(|(input, prevout)| {
+        if input.witness.is_empty() {
+            return true;
+        }
+        // Anchors are spent without a witness.
+        if is_pay_to_anchor(&prevout.script_pubkey) {
+            return false;
+        }
+        let mut script = prevout.script_pubkey.as_script();
+        let is_p2sh = script.is_p2sh();
+        if is_p2sh {
+            match redeem_script(&input.script_sig) {
+                Some(redeem_script) => script = redeem_script,
+                None => return false,
+            }
+        }
+        // Non-witness programs must not be associated with a witness.
+        if !script.is_witness_program() {
+            return false;
+        }
+        if script.is_p2wsh() {
+            return is_p2wsh_witness_standard(&input.witness);
+        }
+        if script.is_p2tr() && !is_p2sh {
+            return is_taproot_witness_standard(&input.witness);
+        }
+        true
+    })
+}
+
+fn is_p2wsh_witness_standard(witness: &Witness) -> bool {
+    let witness_script_size = witness.last().map_or(0, |script| script.len());
+    let stack_items = witness.len() - 1;
+    witness_script_size <= MAX_STANDARD_P2WSH_SCRIPT_SIZE
+        && stack_items <= MAX_STANDARD_P2WSH_STACK_ITEMS
+        && witness
+            .iter()
+            .take(stack_items)
+            .all(|item| item.len() <= MAX_STANDARD_P2WSH_STACK_ITEM_SIZE)
+}
+
+fn is_taproot_witness_standard(witness: &Witness) -> bool {
+    if witness.len() < 2 {
+        // Key path spend
+        return true;
+    }
+    // Annexes are reserved for future upgrades.
+    if witness.last().and_then(|item| item.first()) == Some(&TAPROOT_ANNEX_TAG) {
+        return false;
+    }
+    // Script path spend: the stack items before the script and the control
+    // block must be small for tapscript.
+    let control_block = witness.last().unwrap_or_default();
+    match control_block.first() {
+        None => false,
+        Some(leaf_version) if leaf_version & TAPROOT_LEAF_MASK == TAPROOT_LEAF_TAPSCRIPT => witness
+            .iter()
+            .take(witness.len() - 2)
+            .all(|item| item.len() <= MAX_STANDARD_TAPSCRIPT_STACK_ITEM_SIZE),
+        Some(_) => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoincore_rpc::bitcoin::absolute::LockTime;
+    use bitcoincore_rpc::bitcoin::hashes::Hash;
+    use bitcoincore_rpc::bitcoin::{
+        transaction, OutPoint, ScriptBuf, Sequence, TxIn, Txid, WPubkeyHash,
+    };
+
+    fn p2wpkh() -> ScriptBuf {
+        ScriptBuf::new_p2wpkh(&WPubkeyHash::all_zeros())
+    }
+
+    fn anchor() -> ScriptBuf {
+        ScriptBuf::from(vec![0x51, 0x02, 0x4e, 0x73])
+    }
+
+    fn transaction(version: i32, outputs: Vec<TxOut>) -> Transaction {
+        Transaction {
+            version: transaction::Version(version),
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::new(Txid::all_zeros(), 0),
+                script_sig: ScriptBuf::new(),
+                sequence: Sequence::MAX,
+                witness: Witness::new(),
+            }],
+            output: outputs,
+        }
+    }
+
+    fn output(value: u64, script_pubkey: ScriptBuf) -> TxOut {
+        TxOut {
+            value: Amount::from_sat(value),
+            script_pubkey,
+        }
+    }
+
+    #[test]
+    fn accepts_truc_transactions() {
+        let outputs = vec![output(10_000, p2wpkh())];
+        assert!(!violations(&transaction(3, outputs.clone()), None).contains(&"version"));
+        assert_eq!(violations(&transaction(4, outputs), None), vec!["version"]);
+    }
+
+    #[test]
+    fn checks_data_outputs_before_dust() {
+        let op_return = ScriptBuf::new_op_return([1, 2, 3]);
+        let tx = transaction(
+            2,
+            vec![
+                output(1, p2wpkh()),
+                output(0, op_return.clone()),
+                output(0, op_return),
+            ],
+        );
+        assert_eq!(violations(&tx, None), vec!["multi-op-return", "dust"]);
+    }
+
+    #[test]
+    fn accepts_spending_anchors() {
+        let tx = transaction(3, vec![output(10_000, p2wpkh())]);
+        let prevouts = [output(20_000, anchor())];
+        assert!(are_inputs_standard(&tx, &prevouts));
+        assert!(is_witness_standard(&tx, &prevouts));
+        assert_eq!(violations(&tx, Some(&prevouts)), Vec::<&str>::new());
+
+        // Anchors are spent without a witness.
+        let mut tx = tx;
+        tx.input[0].witness.push([1]);
+        assert!(!is_witness_standard(&tx, &prevouts));
+
+        // Other unknown witness programs can't be spent.
+        let unknown = ScriptBuf::from(vec![0x51, 0x02, 0x4e, 0x74]);
+        assert!(!are_inputs_standard(&tx, &[output(20_000, unknown)]));
+    }
+
+    fn multisig(required: u8, keys: usize) -> ScriptBuf {
+        let number = |n: usize| match n {
+            1..=16 => vec![0x50 + n as u8],
+            _ => vec![0x01, n as u8],
+        };
+        let mut script = number(required.into());
+        for _ in 0..keys {
+            script.push(33);
+            script.extend([2; 33]);
+        }
+        script.extend(number(keys));
+        script.push(0xae);
+        ScriptBuf::from(script)
+    }
+
+    #[test]
+    fn classifies_scripts_like_the_solver() {
+        let cases = [
+            (multisig(1, 3), true, true),
+            // Bare multisig with more than three keys can be spent, but not
+            // created.
+            (multisig(1, 4), true, false),
+            (multisig(20, 20), true, false),
+            (multisig(2, 1), false, false),
+            // P2PK with a key of the wrong size for its first byte
+            (
+                ScriptBuf::from([&[33, 4][..], &[0; 32], &[0xac]].concat()),
+                false,
+                false,
+            ),
+            // Witness version 0 with a program that isn't 20 or 32 bytes long
+            (
+                ScriptBuf::from([&[0x00, 21][..], &[0; 21]].concat()),
+                false,
+                false,
+            ),
+            // Unknown witness versions are reserved for future upgrades.
+            (
+                ScriptBuf::from([&[0x52, 32][..], &[0; 32]].concat()),
+                false,
+                true,
+            ),
+        ];
+        for (script, spendable, creatable) in cases {
+            let tx = transaction(2, vec![output(10_000, p2wpkh())]);
+            let prevouts = [output(20_000, script.clone())];
+            assert_eq!(are_inputs_standard(&tx, &prevouts), spendable, "{}", script);
+            let creates_standard = !matches!(output_type(&script), OutputType::NonStandard);
+            assert_eq!(creates_standard, creatable, "{}", script);
+        }
+    }
+}
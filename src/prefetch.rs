@@ -0,0 +1,133 @@
+use crate::error::Error;
+use crate::source::BlockSource;
+use bitcoincore_rpc::bitcoin::{Block, BlockHash, TxOut};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// A block and, if requested, the outputs spent by each of its transactions
+/// (None for the coinbase and if the source can't tell).
+pub struct PrefetchedBlock {
+    pub hash: BlockHash,
+    pub block: Block,
+    pub prevouts: Option<Vec<Option<Vec<TxOut>>>>,
+}
+
+/// Fetches the upcoming blocks from the block source on a worker thread, so
+/// that they are ready once the policy engines are done with the current
+/// block. Up to `depth` blocks are fetched ahead. With a depth of 0, blocks
+/// are fetched when requested.
+pub struct Prefetcher {
+    source: Arc<dyn BlockSource>,
+    depth: usize,
+    with_prevouts: bool,
+    poll_interval: Duration,
+    // The worker thread sends the blocks in height order, starting at
+    // next_height.
+    worker: Option<Receiver<Result<PrefetchedBlock, Error>>>,
+    next_height: u64,
+}
+
+impl Prefetcher {
+    pub fn new(
+        source: Arc<dyn BlockSource>,
+        depth: usize,
+        with_prevouts: bool,
+        poll_interval: Duration,
+    ) -> Prefetcher {
+        Prefetcher {
+            source,
+            depth,
+            with_prevouts,
+            poll_interval,
+            worker: None,
+            next_height: 0,
+        }
+    }
+
+    /// The block at the height of the source's most-work chain. When the
+    /// requested height isn't the one after the last requested height (e.g.
+    /// after a reorg), the worker starts over at the requested height.
+    pub fn get(&mut self, height: u64) -> Result<PrefetchedBlock, Error> {
+        if self.depth == 0 {
+            return fetch(self.source.as_ref(), height, self.with_prevouts);
+        }
+        if self.worker.is_none() || self.next_height != height {
+            self.start(height);
+        }
+        let worker = self.worker.as_ref().expect("the worker was just started");
+        let result = worker
+            .recv()
+            .unwrap_or_else(|_| Err(Error::State("the prefetch worker stopped".to_string())));
+        match result {
+            Ok(_) => self.next_height = height + 1,
+            // The worker stops after an error.
+            Err(_) => self.worker = None,
+        }
+        result
+    }
+
+    // Replaces the worker with one starting at the height. The old worker
+    // stops once it notices that nobody receives its blocks anymore.
+    fn start(&mut self, height: u64) {
+        let (sender, receiver) = mpsc::sync_channel(self.depth);
+        let source = self.source.clone();
+        let with_prevouts = self.with_prevouts;
+        let poll_interval = self.poll_interval;
+        thread::spawn(move || {
+            let mut height = height;
+            let mut tip = 0;
+            loop {
+                if height > tip {
+                    match source.get_block_count() {
+                        Ok(block_count) => tip = block_count,
+                        Err(e) => {
+                            let _ = sender.send(Err(e));
+                            return;
+                        }
+                    }
+                    if height > tip {
+                        thread::sleep(poll_interval);
+                        continue;
+                    }
+                }
+                let result = fetch(source.as_ref(), height, with_prevouts);
+                let failed = result.is_err();
+                if sender.send(result).is_err() || failed {
+                    return;
+                }
+                height += 1;
+            }
+        });
+        self.worker = Some(receiver);
+        self.next_height = height;
+    }
+}
+
+fn fetch(
+    source: &dyn BlockSource,
+    height: u64,
+    with_prevouts: bool,
+) -> Result<PrefetchedBlock, Error> {
+    let hash = source.get_block_hash(height)?;
+    let block = source.get_block(&hash)?;
+    let prevouts = match with_prevouts {
+        true => Some(
+            block
+                .txdata
+                .iter()
+                .map(|tx| match tx.is_coinbase() {
+                    true => Ok(None),
+                    false => source.get_prevouts(tx, &hash),
+                })
+                .collect::<Result<Vec<_>, Error>>()?,
+        ),
+        false => None,
+    };
+    Ok(PrefetchedBlock {
+        hash,
+        block,
+        prevouts,
+    })
+}
@@ -0,0 +1,414 @@
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// Why a transaction was rejected, broadly. Written to the output as
+/// as_str().
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Category {
+    /// The transaction is valid, but non-standard according to the policy.
+    Policy,
+    /// The transaction is invalid. Only expected for transactions in blocks
+    /// that are invalid themselves.
+    Consensus,
+    /// The transaction depends on the state of the mempool, e.g. its inputs
+    /// are missing or it conflicts with a mempool transaction.
+    MempoolState,
+    Unknown,
+}
+
+impl Category {
+    const ALL: [Category; 4] = [
+        Category::Policy,
+        Category::Consensus,
+        Category::MempoolState,
+        Category::Unknown,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Category::Policy => "policy",
+            Category::Consensus => "consensus",
+            Category::MempoolState => "mempool-state",
+            Category::Unknown => "unknown",
+        }
+    }
+}
+
+/// A reject reason reported by Bitcoin Core, parsed into the rule that was
+/// violated. Reasons that were renamed between Bitcoin Core versions map to
+/// the same variant. Each variant has a stable short code, which is what's
+/// written to the output.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RejectReason {
+    Version,
+    TxSize,
+    TxSizeSmall,
+    ScriptSigSize,
+    ScriptSigNotPushOnly,
+    ScriptPubKey,
+    BareMultisig,
+    Dust,
+    MultiOpReturn,
+    NonStandardInputs,
+    WitnessNonStandard,
+    TooManySigops,
+    ScriptVerifyFlag,
+    MinRelayFee,
+    MaxFeeExceeded,
+    NonFinal,
+    NonBip68Final,
+    Truc,
+    MandatoryScriptVerifyFlag,
+    ConsensusInvalid,
+    MissingInputs,
+    AlreadyInMempool,
+    MempoolConflict,
+    InsufficientFee,
+    MempoolMinFee,
+    MempoolChainLimit,
+    /// A reason we don't know (yet), normalized to a code.
+    Other(String),
+}
+
+impl RejectReason {
+    /// Parses a reject reason as returned by testmempoolaccept into the
+    /// reason and its detail, e.g. the script error of
+    /// `non-mandatory-script-verify-flag (Witness program hash mismatch)`.
+    pub fn parse(reason: &str) -> (RejectReason, Option<String>) {
+        let reason = reason.trim();
+        // Old Bitcoin Core versions prefix the reason with a reject code, e.g.
+        // `64: dust`.
+        let reason = match reason.split_once(": ") {
+            Some((code, rest)) if code.chars().all(|c| c.is_ascii_digit()) => rest,
+            _ => reason,
+        };
+        // The detail follows in parentheses or after a comma, whichever
+        // comes first. The detail itself can contain both.
+        let parenthesis = reason.find(" (");
+        let comma = reason.find(", ");
+        let (reason, detail) = match (parenthesis, comma) {
+            (Some(p), c) if c.is_none_or(|c| p < c) => {
+                let detail = &reason[p + 2..];
+                (
+                    &reason[..p],
+                    Some(detail.strip_suffix(')').unwrap_or(detail)),
+                )
+            }
+            (_, Some(c)) => (&reason[..c], Some(&reason[c + 2..])),
+            _ => (reason, None),
+        };
+        let code = normalize(reason);
+        (
+            RejectReason::from_code(&code),
+            detail.map(|detail| detail.trim().to_string()),
+        )
+    }
+
+    // Maps both the normalized reasons of Bitcoin Core and our own codes to
+    // the reason.
+    fn from_code(code: &str) -> RejectReason {
+        match code {
+            "version" => RejectReason::Version,
+            "tx-size" => RejectReason::TxSize,
+            "tx-size-small" => RejectReason::TxSizeSmall,
+            "scriptsig-size" => RejectReason::ScriptSigSize,
+            "scriptsig-not-pushonly" => RejectReason::ScriptSigNotPushOnly,
+            "scriptpubkey" => RejectReason::ScriptPubKey,
+            "bare-multisig" => RejectReason::BareMultisig,
+            "dust" => RejectReason::Dust,
+            "multi-op-return" => RejectReason::MultiOpReturn,
+            "bad-txns-nonstandard-inputs" | "nonstandard-inputs" => RejectReason::NonStandardInputs,
+            "bad-witness-nonstandard" | "witness-nonstandard" => RejectReason::WitnessNonStandard,
+            "bad-txns-too-many-sigops" | "too-many-sigops" => RejectReason::TooManySigops,
+            "non-mandatory-script-verify-flag"
+            | "mempool-script-verify-flag-failed"
+            | "script-verify-flag" => RejectReason::ScriptVerifyFlag,
+            "min-relay-fee-not-met" | "min-relay-fee" => RejectReason::MinRelayFee,
+            "max-fee-exceeded" | "absurdly-high-fee" => RejectReason::MaxFeeExceeded,
+            "non-final" => RejectReason::NonFinal,
+            "non-bip68-final" => RejectReason::NonBip68Final,
+            "mandatory-script-verify-flag-failed"
+            | "block-script-verify-flag-failed"
+            | "mandatory-script-verify-flag" => RejectReason::MandatoryScriptVerifyFlag,
+            "missing-inputs" | "bad-txns-inputs-missingorspent" => RejectReason::MissingInputs,
+            "txn-already-in-mempool"
+            | "txn-already-known"
+            | "txn-same-nonwitness-data-in-mempool"
+            | "already-in-mempool" => RejectReason::AlreadyInMempool,
+            "txn-mempool-conflict" | "bad-txns-spends-conflicting-tx" | "mempool-conflict" => {
+                RejectReason::MempoolConflict
+            }
+            "insufficient-fee" => RejectReason::InsufficientFee,
+            "mempool-min-fee-not-met" | "mempool-full" | "mempool-min-fee" => {
+                RejectReason::MempoolMinFee
+            }
+            "too-long-mempool-chain" | "too-large-cluster" | "mempool-chain-limit" => {
+                RejectReason::MempoolChainLimit
+            }
+            "truc" => RejectReason::Truc,
+            code if code.starts_with("truc-") || code.starts_with("v3-") => RejectReason::Truc,
+            "consensus-invalid" => RejectReason::ConsensusInvalid,
+            code if code.starts_with("bad-txns-") || code.starts_with("bad-witness-") => {
+                RejectReason::ConsensusInvalid
+            }
+            code => RejectReason::Other(code.to_string()),
+        }
+    }
+
+    /// The stable short code of the reason.
+    pub fn code(&self) -> &str {
+        match self {
+            RejectReason::Version => "version",
+            RejectReason::TxSize => "tx-size",
+            RejectReason::TxSizeSmall => "tx-size-small",
+            RejectReason::ScriptSigSize => "scriptsig-size",
+            RejectReason::ScriptSigNotPushOnly => "scriptsig-not-pushonly",
+            RejectReason::ScriptPubKey => "scriptpubkey",
+            RejectReason::BareMultisig => "bare-multisig",
+            RejectReason::Dust => "dust",
+            RejectReason::MultiOpReturn => "multi-op-return",
+            RejectReason::NonStandardInputs => "nonstandard-inputs",
+            RejectReason::WitnessNonStandard => "witness-nonstandard",
+            RejectReason::TooManySigops => "too-many-sigops",
+            RejectReason::ScriptVerifyFlag => "script-verify-flag",
+            RejectReason::MinRelayFee => "min-relay-fee",
+            RejectReason::MaxFeeExceeded => "max-fee-exceeded",
+            RejectReason::NonFinal => "non-final",
+            RejectReason::NonBip68Final => "non-bip68-final",
+            RejectReason::Truc => "truc",
+            RejectReason::MandatoryScriptVerifyFlag => "mandatory-script-verify-flag",
+            RejectReason::ConsensusInvalid => "consensus-invalid",
+            RejectReason::MissingInputs => "missing-inputs",
+            RejectReason::AlreadyInMempool => "already-in-mempool",
+            RejectReason::MempoolConflict => "mempool-conflict",
+            RejectReason::InsufficientFee => "insufficient-fee",
+            RejectReason::MempoolMinFee => "mempool-min-fee",
+            RejectReason::MempoolChainLimit => "mempool-chain-limit",
+            RejectReason::Other(code) => code,
+        }
+    }
+
+    pub fn category(&self) -> Category {
+        match self {
+            RejectReason::Version
+            | RejectReason::TxSize
+            | RejectReason::TxSizeSmall
+            | RejectReason::ScriptSigSize
+            | RejectReason::ScriptSigNotPushOnly
+            | RejectReason::ScriptPubKey
+            | RejectReason::BareMultisig
+            | RejectReason::Dust
+            | RejectReason::MultiOpReturn
+            | RejectReason::NonStandardInputs
+            | RejectReason::WitnessNonStandard
+            | RejectReason::TooManySigops
+            | RejectReason::ScriptVerifyFlag
+            | RejectReason::MinRelayFee
+            | RejectReason::MaxFeeExceeded
+            | RejectReason::NonFinal
+            | RejectReason::NonBip68Final
+            | RejectReason::Truc => Category::Policy,
+            RejectReason::MandatoryScriptVerifyFlag | RejectReason::ConsensusInvalid => {
+                Category::Consensus
+            }
+            RejectReason::MissingInputs
+            | RejectReason::AlreadyInMempool
+            | RejectReason::MempoolConflict
+            | RejectReason::InsufficientFee
+            | RejectReason::MempoolMinFee
+            | RejectReason::MempoolChainLimit => Category::MempoolState,
+            RejectReason::Other(code) if code.contains("mempool") => Category::MempoolState,
+            RejectReason::Other(_) => Category::Unknown,
+        }
+    }
+}
+
+// Lower case with dashes, e.g. `min relay fee not met` becomes
+// `min-relay-fee-not-met`.
+fn normalize(reason: &str) -> String {
+    reason
+        .trim()
+        .to_ascii_lowercase()
+        .split(|c: char| c.is_whitespace() || c == '_')
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<&str>>()
+        .join("-")
+}
+
+impl Serialize for Category {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for Category {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Category, D::Error> {
+        let category = String::deserialize(deserializer)?;
+        Category::ALL
+            .into_iter()
+            .find(|c| c.as_str() == category)
+            .ok_or_else(|| serde::de::Error::custom(format!("unknown category '{}'", category)))
+    }
+}
+
+impl Serialize for RejectReason {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.code())
+    }
+}
+
+impl<'de> Deserialize<'de> for RejectReason {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<RejectReason, D::Error> {
+        Ok(RejectReason::from_code(&String::deserialize(deserializer)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(reason: &str) -> (RejectReason, Option<String>) {
+        RejectReason::parse(reason)
+    }
+
+    #[test]
+    fn parses_reasons() {
+        assert_eq!(parse("dust"), (RejectReason::Dust, None));
+        assert_eq!(parse(" scriptpubkey\n"), (RejectReason::ScriptPubKey, None));
+        assert_eq!(
+            parse("min relay fee not met"),
+            (RejectReason::MinRelayFee, None)
+        );
+        assert_eq!(
+            parse("unknown-reason"),
+            (RejectReason::Other("unknown-reason".to_string()), None)
+        );
+    }
+
+    #[test]
+    fn strips_reject_codes() {
+        assert_eq!(parse("64: dust"), (RejectReason::Dust, None));
+        assert_eq!(
+            parse("18: txn-mempool-conflict"),
+            (RejectReason::MempoolConflict, None)
+        );
+        // Only numeric prefixes are reject codes.
+        assert_eq!(
+            parse("abc: dust"),
+            (RejectReason::Other("abc:-dust".to_string()), None)
+        );
+    }
+
+    #[test]
+    fn parses_details() {
+        assert_eq!(
+            parse("non-mandatory-script-verify-flag (Witness program hash mismatch)"),
+            (
+                RejectReason::ScriptVerifyFlag,
+                Some("Witness program hash mismatch".to_string())
+            )
+        );
+        assert_eq!(
+            parse("64: non-mandatory-script-verify-flag (Non-canonical DER signature)"),
+            (
+                RejectReason::ScriptVerifyFlag,
+                Some("Non-canonical DER signature".to_string())
+            )
+        );
+        assert_eq!(
+            parse("mempool min fee not met, 100 < 1000"),
+            (RejectReason::MempoolMinFee, Some("100 < 1000".to_string()))
+        );
+        assert_eq!(
+            parse("insufficient fee, rejecting replacement abc; new feerate 0.00001 <= old feerate 0.00002"),
+            (
+                RejectReason::InsufficientFee,
+                Some("rejecting replacement abc; new feerate 0.00001 <= old feerate 0.00002".to_string())
+            )
+        );
+        assert_eq!(
+            parse("TRUC-violation, version=3 tx abc (wtxid=def) is too big: 10001 > 10000 virtual bytes"),
+            (
+                RejectReason::Truc,
+                Some("version=3 tx abc (wtxid=def) is too big: 10001 > 10000 virtual bytes".to_string())
+            )
+        );
+    }
+
+    #[test]
+    fn maps_renamed_reasons() {
+        let renamed = [
+            (
+                "bad-txns-nonstandard-inputs",
+                RejectReason::NonStandardInputs,
+            ),
+            ("bad-witness-nonstandard", RejectReason::WitnessNonStandard),
+            ("bad-txns-too-many-sigops", RejectReason::TooManySigops),
+            (
+                "mempool-script-verify-flag-failed",
+                RejectReason::ScriptVerifyFlag,
+            ),
+            ("absurdly-high-fee", RejectReason::MaxFeeExceeded),
+            (
+                "mandatory-script-verify-flag-failed",
+                RejectReason::MandatoryScriptVerifyFlag,
+            ),
+            (
+                "block-script-verify-flag-failed",
+                RejectReason::MandatoryScriptVerifyFlag,
+            ),
+            (
+                "bad-txns-inputs-missingorspent",
+                RejectReason::MissingInputs,
+            ),
+            ("txn-already-known", RejectReason::AlreadyInMempool),
+            (
+                "txn-same-nonwitness-data-in-mempool",
+                RejectReason::AlreadyInMempool,
+            ),
+            (
+                "bad-txns-spends-conflicting-tx",
+                RejectReason::MempoolConflict,
+            ),
+            ("mempool full", RejectReason::MempoolMinFee),
+            ("too-large-cluster", RejectReason::MempoolChainLimit),
+            ("v3-rule-violation", RejectReason::Truc),
+            ("bad-txns-vout-negative", RejectReason::ConsensusInvalid),
+        ];
+        for (reason, expected) in renamed {
+            assert_eq!(parse(reason).0, expected, "{}", reason);
+        }
+    }
+
+    #[test]
+    fn codes_round_trip() {
+        let reasons = [
+            RejectReason::NonStandardInputs,
+            RejectReason::MempoolChainLimit,
+            RejectReason::Other("something-new".to_string()),
+        ];
+        for reason in reasons {
+            assert_eq!(RejectReason::from_code(reason.code()), reason);
+            let json = serde_json::to_string(&reason).expect("can serialize");
+            assert_eq!(json, format!("\"{}\"", reason.code()));
+            let parsed: RejectReason = serde_json::from_str(&json).expect("can deserialize");
+            assert_eq!(parsed, reason);
+        }
+    }
+
+    #[test]
+    fn categories_round_trip() {
+        for category in Category::ALL {
+            let json = serde_json::to_string(&category).expect("can serialize");
+            assert_eq!(json, format!("\"{}\"", category.as_str()));
+            let parsed: Category = serde_json::from_str(&json).expect("can deserialize");
+            assert_eq!(parsed, category);
+        }
+        assert!(serde_json::from_str::<Category>("\"other\"").is_err());
+        assert_eq!(parse("dust").0.category(), Category::Policy);
+        assert_eq!(parse("missing-inputs").0.category(), Category::MempoolState);
+        assert_eq!(
+            parse("mempool-something").0.category(),
+            Category::MempoolState
+        );
+    }
+}
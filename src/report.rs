@@ -0,0 +1,70 @@
+use crate::checkpoint::{self, Checkpoint};
+use crate::error::Error;
+use crate::output;
+use config::Config;
+use std::collections::{BTreeSet, HashMap};
+
+// The fields of the rows the report is built from.
+#[derive(serde::Deserialize)]
+struct ReportRow {
+    height: u64,
+    miner: String,
+    reject_reason: String,
+    reject_code: Option<String>,
+}
+
+/// Prints how far the scan got and how many rows were written, per reject
+/// reason and per miner.
+pub fn run(settings: &Config) -> Result<(), Error> {
+    let (output, format) = output::output_format(settings)?;
+    println!("Output: {} ({})", output, format);
+    let checkpoint_path = checkpoint::path_from_config(settings)?;
+    match Checkpoint::load(&checkpoint_path)? {
+        Some(checkpoint) => println!(
+            "Last processed block: {} (height {})",
+            checkpoint.hash, checkpoint.height
+        ),
+        None => println!("No checkpoint at {}", checkpoint_path.display()),
+    }
+
+    let mut rows = 0;
+    let mut heights = BTreeSet::new();
+    let mut reasons: HashMap<String, usize> = HashMap::new();
+    let mut miners: HashMap<String, usize> = HashMap::new();
+    output::read_rows(settings, &mut |row: ReportRow| {
+        rows += 1;
+        heights.insert(row.height);
+        *reasons
+            .entry(row.reject_code.unwrap_or(row.reject_reason))
+            .or_default() += 1;
+        *miners.entry(row.miner).or_default() += 1;
+    })?;
+
+    match (heights.first(), heights.last()) {
+        (Some(first), Some(last)) => println!(
+            "{} rows in {} blocks between height {} and {}",
+            rows,
+            heights.len(),
+            first,
+            last
+        ),
+        _ => println!("No rows"),
+    }
+    print_counts("Reject reason", reasons);
+    print_counts("Miner", miners);
+    Ok(())
+}
+
+// Prints the counts, the most frequent first.
+fn print_counts(title: &str, counts: HashMap<String, usize>) {
+    if counts.is_empty() {
+        return;
+    }
+    let mut counts: Vec<(String, usize)> = counts.into_iter().collect();
+    counts.sort_by(|(a, a_count), (b, b_count)| b_count.cmp(a_count).then(a.cmp(b)));
+    println!();
+    println!("{:>10}  {}", "Rows", title);
+    for (name, count) in counts {
+        println!("{:>10}  {}", count, name);
+    }
+}
@@ -0,0 +1,51 @@
+use crate::error::Error;
+use config::Config;
+use log::warn;
+use std::thread;
+use std::time::Duration;
+
+/// Retries calls failing with a transient error with an exponential backoff.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl RetryPolicy {
+    pub fn from_config(settings: &Config) -> Result<RetryPolicy, Error> {
+        let get = |key: &str| {
+            settings
+                .get::<u64>(&format!("retry.{}", key))
+                .map_err(|e| Error::config(format!("invalid retry.{}", key), e))
+        };
+        Ok(RetryPolicy {
+            max_retries: get("max_retries")? as u32,
+            initial_backoff: Duration::from_millis(get("initial_backoff_ms")?),
+            max_backoff: Duration::from_millis(get("max_backoff_ms")?),
+        })
+    }
+
+    pub fn retry<T, E, F>(&self, what: &str, mut f: F) -> Result<T, Error>
+    where
+        E: Into<Error>,
+        F: FnMut() -> Result<T, E>,
+    {
+        let mut backoff = self.initial_backoff;
+        let mut retries = 0;
+        loop {
+            match f().map_err(E::into) {
+                Err(e) if e.is_transient() && retries < self.max_retries => {
+                    retries += 1;
+                    warn!(
+                        "{} failed: {}. Retrying in {:?} ({}/{})..",
+                        what, e, backoff, retries, self.max_retries
+                    );
+                    thread::sleep(backoff);
+                    backoff = (backoff * 2).min(self.max_backoff);
+                }
+                result => return result,
+            }
+        }
+    }
+}
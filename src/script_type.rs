@@ -0,0 +1,373 @@
+use crate::policy::{self, TAPROOT_ANNEX_TAG};
+use bitcoincore_rpc::bitcoin::{Script, TxIn, TxOut};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// The type of a created output or of a spent input.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScriptType {
+    P2pk,
+    P2pkh,
+    P2sh,
+    P2shP2wpkh,
+    P2shP2wsh,
+    P2wpkh,
+    P2wsh,
+    /// A taproot output. Spent taproot inputs are either key or script path.
+    P2tr,
+    P2trKeyPath,
+    P2trScriptPath,
+    Anchor,
+    BareMultisig,
+    NullData,
+    /// A witness program of an unknown version or length.
+    WitnessUnknown,
+    NonStandard,
+    /// An input whose spent output isn't known.
+    Unknown,
+}
+
+impl ScriptType {
+    const ALL: [ScriptType; 16] = [
+        ScriptType::P2pk,
+        ScriptType::P2pkh,
+        ScriptType::P2sh,
+        ScriptType::P2shP2wpkh,
+        ScriptType::P2shP2wsh,
+        ScriptType::P2wpkh,
+        ScriptType::P2wsh,
+        ScriptType::P2tr,
+        ScriptType::P2trKeyPath,
+        ScriptType::P2trScriptPath,
+        ScriptType::Anchor,
+        ScriptType::BareMultisig,
+        ScriptType::NullData,
+        ScriptType::WitnessUnknown,
+        ScriptType::NonStandard,
+        ScriptType::Unknown,
+    ];
+
+    /// Classifies an output by its scriptPubKey.
+    pub fn of_output(script: &Script) -> ScriptType {
+        if script.is_p2pk() {
+            ScriptType::P2pk
+        } else if script.is_p2pkh() {
+            ScriptType::P2pkh
+        } else if script.is_p2sh() {
+            ScriptType::P2sh
+        } else if script.is_p2wpkh() {
+            ScriptType::P2wpkh
+        } else if script.is_p2wsh() {
+            ScriptType::P2wsh
+        } else if script.is_p2tr() {
+            ScriptType::P2tr
+        } else if policy::is_pay_to_anchor(script) {
+            ScriptType::Anchor
+        } else if script.is_witness_program() {
+            ScriptType::WitnessUnknown
+        } else if script.is_multisig() {
+            ScriptType::BareMultisig
+        } else if script.is_op_return() {
+            ScriptType::NullData
+        } else {
+            ScriptType::NonStandard
+        }
+    }
+
+    /// Classifies an input by the output it spends, if known, and by how it
+    /// is spent: P2SH inputs by their redeem script and taproot inputs by
+    /// the spending path.
+    pub fn of_input(input: &TxIn, prevout: Option<&TxOut>) -> ScriptType {
+        let Some(prevout) = prevout else {
+            return ScriptType::Unknown;
+        };
+        match ScriptType::of_output(&prevout.script_pubkey) {
+            ScriptType::P2sh => match policy::redeem_script(&input.script_sig) {
+                Some(script) if script.is_p2wpkh() => ScriptType::P2shP2wpkh,
+                Some(script) if script.is_p2wsh() => ScriptType::P2shP2wsh,
+                _ => ScriptType::P2sh,
+            },
+            ScriptType::P2tr => {
+                let has_annex = input.witness.len() >= 2
+                    && input.witness.last().and_then(|item| item.first())
+                        == Some(&TAPROOT_ANNEX_TAG);
+                match input.witness.len() - has_annex as usize {
+                    0 | 1 => ScriptType::P2trKeyPath,
+                    _ => ScriptType::P2trScriptPath,
+                }
+            }
+            script_type => script_type,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ScriptType::P2pk => "p2pk",
+            ScriptType::P2pkh => "p2pkh",
+            ScriptType::P2sh => "p2sh",
+            ScriptType::P2shP2wpkh => "p2sh-p2wpkh",
+            ScriptType::P2shP2wsh => "p2sh-p2wsh",
+            ScriptType::P2wpkh => "p2wpkh",
+            ScriptType::P2wsh => "p2wsh",
+            ScriptType::P2tr => "p2tr",
+            ScriptType::P2trKeyPath => "p2tr-keypath",
+            ScriptType::P2trScriptPath => "p2tr-scriptpath",
+            ScriptType::Anchor => "anchor",
+            ScriptType::BareMultisig => "bare-multisig",
+            ScriptType::NullData => "nulldata",
+            ScriptType::WitnessUnknown => "witness-unknown",
+            ScriptType::NonStandard => "nonstandard",
+            ScriptType::Unknown => "unknown",
+        }
+    }
+
+    fn from_str(name: &str) -> ScriptType {
+        ScriptType::ALL
+            .into_iter()
+            .find(|script_type| script_type.as_str() == name)
+            .unwrap_or(ScriptType::Unknown)
+    }
+}
+
+/// The types of the inputs or outputs of a transaction, in order. Written
+/// as a single column separated by ';' to CSV.
+#[derive(Debug, Clone, Default)]
+pub struct ScriptTypes(pub Vec<ScriptType>);
+
+impl ScriptTypes {
+    /// How often each type occurs, in the order the types first occur.
+    pub fn counts(&self) -> ScriptTypeCounts {
+        let mut counts: Vec<(ScriptType, usize)> = vec![];
+        for script_type in self.0.iter() {
+            match counts.iter_mut().find(|(t, _)| t == script_type) {
+                Some((_, count)) => *count += 1,
+                None => counts.push((*script_type, 1)),
+            }
+        }
+        ScriptTypeCounts(counts)
+    }
+}
+
+/// How often each type occurs. Written as `type=count` pairs separated by
+/// ';' to CSV.
+#[derive(Debug, Clone, Default)]
+pub struct ScriptTypeCounts(pub Vec<(ScriptType, usize)>);
+
+impl Serialize for ScriptTypes {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let names: Vec<&str> = self.0.iter().map(|t| t.as_str()).collect();
+        serializer.serialize_str(&names.join(";"))
+    }
+}
+
+impl<'de> Deserialize<'de> for ScriptTypes {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<ScriptTypes, D::Error> {
+        let names = String::deserialize(deserializer)?;
+        Ok(ScriptTypes(
+            names
+                .split(';')
+                .filter(|name| !name.is_empty())
+                .map(ScriptType::from_str)
+                .collect(),
+        ))
+    }
+}
+
+impl Serialize for ScriptTypeCounts {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let pairs: Vec<String> = self
+            .0
+            .iter()
+            .map(|(t, count)| format!("{}={}", t.as_str(), count))
+            .collect();
+        serializer.serialize_str(&pairs.join(";"))
+    }
+}
+
+impl<'de> Deserialize<'de> for ScriptTypeCounts {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<ScriptTypeCounts, D::Error> {
+        let pairs = String::deserialize(deserializer)?;
+        Ok(ScriptTypeCounts(
+            pairs
+                .split(';')
+                .filter_map(|pair| pair.split_once('='))
+                .map(|(name, count)| (ScriptType::from_str(name), count.parse().unwrap_or(0)))
+                .collect(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoincore_rpc::bitcoin::{Amount, ScriptBuf, Witness};
+
+    // The compressed and the uncompressed key of the generator point.
+    const KEY: [u8; 33] = [
+        0x02, 0x79, 0xbe, 0x66, 0x7e, 0xf9, 0xdc, 0xbb, 0xac, 0x55, 0xa0, 0x62, 0x95, 0xce, 0x87,
+        0x0b, 0x07, 0x02, 0x9b, 0xfc, 0xdb, 0x2d, 0xce, 0x28, 0xd9, 0x59, 0xf2, 0x81, 0x5b, 0x16,
+        0xf8, 0x17, 0x98,
+    ];
+    const UNCOMPRESSED_KEY: [u8; 65] = [
+        0x04, 0x79, 0xbe, 0x66, 0x7e, 0xf9, 0xdc, 0xbb, 0xac, 0x55, 0xa0, 0x62, 0x95, 0xce, 0x87,
+        0x0b, 0x07, 0x02, 0x9b, 0xfc, 0xdb, 0x2d, 0xce, 0x28, 0xd9, 0x59, 0xf2, 0x81, 0x5b, 0x16,
+        0xf8, 0x17, 0x98, 0x48, 0x3a, 0xda, 0x77, 0x26, 0xa3, 0xc4, 0x65, 0x5d, 0xa4, 0xfb, 0xfc,
+        0x0e, 0x11, 0x08, 0xa8, 0xfd, 0x17, 0xb4, 0x48, 0xa6, 0x85, 0x54, 0x19, 0x9c, 0x47, 0xd0,
+        0x8f, 0xfb, 0x10, 0xd4, 0xb8,
+    ];
+
+    fn script(parts: &[&[u8]]) -> ScriptBuf {
+        ScriptBuf::from(parts.concat())
+    }
+
+    fn p2wpkh() -> ScriptBuf {
+        script(&[&[0x00, 20], &[1; 20]])
+    }
+
+    fn p2wsh() -> ScriptBuf {
+        script(&[&[0x00, 32], &[1; 32]])
+    }
+
+    fn multisig() -> ScriptBuf {
+        script(&[&[0x51, 33], &KEY, &[65], &UNCOMPRESSED_KEY, &[0x52, 0xae]])
+    }
+
+    // A scriptSig that pushes the redeem script.
+    fn push(redeem_script: &Script) -> ScriptBuf {
+        script(&[&[redeem_script.len() as u8], redeem_script.as_bytes()])
+    }
+
+    #[test]
+    fn classifies_outputs() {
+        let cases = [
+            (script(&[&[33], &KEY, &[0xac]]), ScriptType::P2pk),
+            (
+                script(&[&[65], &UNCOMPRESSED_KEY, &[0xac]]),
+                ScriptType::P2pk,
+            ),
+            (
+                script(&[&[0x76, 0xa9, 20], &[1; 20], &[0x88, 0xac]]),
+                ScriptType::P2pkh,
+            ),
+            (script(&[&[0xa9, 20], &[1; 20], &[0x87]]), ScriptType::P2sh),
+            (p2wpkh(), ScriptType::P2wpkh),
+            (p2wsh(), ScriptType::P2wsh),
+            (script(&[&[0x51, 32], &[1; 32]]), ScriptType::P2tr),
+            (script(&[&[0x51, 2, 0x4e, 0x73]]), ScriptType::Anchor),
+            // Witness version 1 with a program that isn't 32 bytes long
+            (script(&[&[0x51, 20], &[1; 20]]), ScriptType::WitnessUnknown),
+            (script(&[&[0x52, 32], &[1; 32]]), ScriptType::WitnessUnknown),
+            (script(&[&[0x60, 2, 1, 1]]), ScriptType::WitnessUnknown),
+            (multisig(), ScriptType::BareMultisig),
+            (script(&[&[0x6a]]), ScriptType::NullData),
+            (script(&[&[0x6a, 5], b"hello"]), ScriptType::NullData),
+            (script(&[&[0x51]]), ScriptType::NonStandard),
+            (ScriptBuf::new(), ScriptType::NonStandard),
+        ];
+        for (script, script_type) in cases {
+            assert_eq!(ScriptType::of_output(&script), script_type, "{}", script);
+        }
+    }
+
+    #[test]
+    fn classifies_inputs() {
+        let p2sh = |redeem_script: &Script| redeem_script.to_p2sh();
+        let p2tr = script(&[&[0x51, 32], &[1; 32]]);
+        let annex: &[u8] = &[TAPROOT_ANNEX_TAG, 1];
+        let signature: &[u8] = &[1; 64];
+        let control_block: &[u8] = &[0xc0; 33];
+        let tapscript: &[u8] = &[0x51];
+        let cases = [
+            // The spent output is unknown.
+            (ScriptBuf::new(), Witness::new(), None, ScriptType::Unknown),
+            (
+                script(&[&[72], &[1; 72]]),
+                Witness::new(),
+                Some(script(&[&[33], &KEY, &[0xac]])),
+                ScriptType::P2pk,
+            ),
+            (
+                ScriptBuf::new(),
+                Witness::from_slice(&[signature, &KEY]),
+                Some(p2wpkh()),
+                ScriptType::P2wpkh,
+            ),
+            (
+                push(&p2wpkh()),
+                Witness::from_slice(&[signature, &KEY]),
+                Some(p2sh(&p2wpkh())),
+                ScriptType::P2shP2wpkh,
+            ),
+            (
+                push(&p2wsh()),
+                Witness::from_slice(&[tapscript]),
+                Some(p2sh(&p2wsh())),
+                ScriptType::P2shP2wsh,
+            ),
+            (
+                push(&multisig()),
+                Witness::new(),
+                Some(p2sh(&multisig())),
+                ScriptType::P2sh,
+            ),
+            (
+                ScriptBuf::new(),
+                Witness::new(),
+                Some(multisig()),
+                ScriptType::BareMultisig,
+            ),
+            (
+                ScriptBuf::new(),
+                Witness::from_slice(&[signature]),
+                Some(p2tr.clone()),
+                ScriptType::P2trKeyPath,
+            ),
+            (
+                ScriptBuf::new(),
+                Witness::from_slice(&[signature, annex]),
+                Some(p2tr.clone()),
+                ScriptType::P2trKeyPath,
+            ),
+            (
+                ScriptBuf::new(),
+                Witness::from_slice(&[tapscript, control_block]),
+                Some(p2tr.clone()),
+                ScriptType::P2trScriptPath,
+            ),
+            (
+                ScriptBuf::new(),
+                Witness::from_slice(&[signature, tapscript, control_block, annex]),
+                Some(p2tr.clone()),
+                ScriptType::P2trScriptPath,
+            ),
+            (
+                ScriptBuf::new(),
+                Witness::new(),
+                Some(script(&[&[0x51, 2, 0x4e, 0x73]])),
+                ScriptType::Anchor,
+            ),
+            (
+                ScriptBuf::new(),
+                Witness::from_slice(&[signature]),
+                Some(script(&[&[0x52, 32], &[1; 32]])),
+                ScriptType::WitnessUnknown,
+            ),
+        ];
+        for (script_sig, witness, script_pubkey, script_type) in cases {
+            let input = TxIn {
+                script_sig,
+                witness,
+                ..Default::default()
+            };
+            let prevout = script_pubkey.map(|script_pubkey| TxOut {
+                value: Amount::from_sat(10_000),
+                script_pubkey,
+            });
+            assert_eq!(
+                ScriptType::of_input(&input, prevout.as_ref()),
+                script_type,
+                "{:?}",
+                prevout
+            );
+        }
+    }
+}
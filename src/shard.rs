@@ -0,0 +1,843 @@
+use crate::checkpoint::{Checkpoint, PendingBlock, Shard};
+use crate::engine::{NativeEngine, PolicyEngine, RpcEngine};
+use crate::error::Error;
+use crate::interrupt;
+use crate::output::OutputSink;
+use crate::prefetch::{PrefetchedBlock, Prefetcher};
+use crate::retry::RetryPolicy;
+use crate::source::BlockSource;
+use crate::tester::{self, BlockTester};
+use bitcoin_pool_identification::default_data;
+use bitcoincore_rpc::bitcoin::Network;
+use config::Config;
+use log::info;
+use std::collections::{BTreeMap, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender, SyncSender};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// Scans the chain with several test nodes at once. Every `[nodes.shard_*]`
+/// section configures a test node (a shard node) that was prepared at a
+/// different height, e.g. with `-stopatheight`. On the first run, each shard
+/// node is assigned the blocks from its height up to the height of the next
+/// shard node, and the last one the blocks up to the tip of the data source
+/// (or the end height).
+/// The shard nodes test their blocks concurrently and the rows are written
+/// to the output in height order.
+pub struct ShardedScan<'a> {
+    pub settings: &'a Config,
+    pub retry: &'a RetryPolicy,
+    pub data_source: Arc<dyn BlockSource>,
+    pub shard_nodes: Vec<String>,
+    pub checkpoint_path: &'a Path,
+    pub prefetch_blocks: usize,
+    pub poll_interval: Duration,
+    pub package_acceptance: bool,
+    pub cross_check_native: bool,
+    pub test_batch_size: usize,
+    pub out_of_band_max_position: usize,
+    /// Caps the shard of the last shard node on the first run. A resumed
+    /// scan must have the same end height, if any.
+    pub end_height: Option<u64>,
+}
+
+// What the shard threads tell the coordinator.
+enum Message {
+    // The shard node tested the block. The block is submitted once the
+    // coordinator stored its rows.
+    Tested {
+        shard: usize,
+        block: PendingBlock,
+        stored: SyncSender<()>,
+    },
+    Submitted {
+        shard: usize,
+        height: u64,
+        was_unknown: bool,
+    },
+    Finished {
+        shard: usize,
+        result: Result<(), Error>,
+    },
+}
+
+impl ShardedScan<'_> {
+    pub fn run(self, output: &mut dyn OutputSink) -> Result<(), Error> {
+        let mut engines = vec![];
+        for name in self.shard_nodes.iter() {
+            let engine = RpcEngine::new(self.settings, name, self.retry.clone())?;
+            let height = engine.block_count()?.unwrap_or_default();
+            println!("The '{}' node is at height {}", name, height);
+            engines.push((name.clone(), engine, height));
+        }
+
+        let mut checkpoint = match Checkpoint::load(self.checkpoint_path)? {
+            Some(checkpoint) => {
+                // The shards were assigned on the first run, so the end
+                // can't be moved.
+                let last_end_height = checkpoint.shards.last().map(|shard| shard.end_height);
+                if let Some(end_height) = self.end_height.filter(|h| Some(*h) != last_end_height) {
+                    return Err(Error::Config(format!(
+                        "the shards of the checkpoint {} end at height {}, not at the end height {}",
+                        self.checkpoint_path.display(),
+                        last_end_height.unwrap_or_default(),
+                        end_height
+                    )));
+                }
+                checkpoint
+            }
+            None => self.assign(&engines, output)?,
+        };
+        let mut names: Vec<&String> = checkpoint.shards.iter().map(|s| &s.name).collect();
+        names.sort();
+        if names != self.shard_nodes.iter().collect::<Vec<_>>() {
+            return Err(Error::Config(format!(
+                "the checkpoint {} was written with the shard nodes {:?}, but {:?} are configured",
+                self.checkpoint_path.display(),
+                names,
+                self.shard_nodes
+            )));
+        }
+        // The engines in the order of the shards.
+        engines.sort_by_key(|(name, _, _)| {
+            checkpoint
+                .shards
+                .iter()
+                .position(|shard| &shard.name == name)
+        });
+
+        // Remove rows that a previous run wrote after the last checkpoint.
+        output.truncate(checkpoint.output_len)?;
+
+        let mut logs = vec![];
+        for (shard, (name, engine, height)) in checkpoint.shards.iter().zip(engines.iter()) {
+            logs.push(self.recover(shard, engine, *height, checkpoint.height)?);
+            if *height < shard.end_height {
+                info!(
+                    "Shard node '{}' resumes at height {} and stops at height {}",
+                    name,
+                    height + 1,
+                    shard.end_height
+                );
+            }
+        }
+
+        let shards = checkpoint.shards.clone();
+        let mut coordinator = Coordinator {
+            checkpoint_path: self.checkpoint_path,
+            shards: &shards,
+            current: 0,
+            blocks: BTreeMap::new(),
+        };
+        coordinator.load_current(&checkpoint)?;
+        coordinator.write_ready(&mut checkpoint, output)?;
+
+        let stop = AtomicBool::new(false);
+        thread::scope(|scope| {
+            let (sender, receiver) = mpsc::channel();
+            let mut running = 0;
+            for (index, (shard, (_, engine, height))) in shards.iter().zip(engines).enumerate() {
+                if height >= shard.end_height {
+                    continue;
+                }
+                let sender = sender.clone();
+                let (this, stop) = (&self, &stop);
+                scope.spawn(move || {
+                    let result = this.scan_shard(index, shard, &engine, height, &sender, stop);
+                    let _ = sender.send(Message::Finished {
+                        shard: index,
+                        result,
+                    });
+                });
+                running += 1;
+            }
+            drop(sender);
+            let result =
+                coordinator.coordinate(receiver, running, &mut checkpoint, &mut logs, output);
+            // Stop the other shard nodes when one of them fails.
+            if result.is_err() {
+                stop.store(true, Ordering::Relaxed);
+            }
+            result
+        })?;
+        output.finish()
+    }
+
+    // Assigns the blocks to the shard nodes on the first run.
+    fn assign(
+        &self,
+        engines: &[(String, RpcEngine, u64)],
+        output: &mut dyn OutputSink,
+    ) -> Result<Checkpoint, Error> {
+        let mut nodes: Vec<(&String, u64)> = engines
+            .iter()
+            .map(|(name, _, height)| (name, *height))
+            .collect();
+        nodes.sort_by_key(|(_, height)| *height);
+        for pair in nodes.windows(2) {
+            if pair[0].1 == pair[1].1 {
+                return Err(Error::Config(format!(
+                    "the shard nodes '{}' and '{}' are both at height {}: each shard node needs to be at another height",
+                    pair[0].0, pair[1].0, pair[0].1
+                )));
+            }
+        }
+        let mut tip = self.data_source.get_block_count()?;
+        if let Some(end_height) = self.end_height {
+            if let Some((name, height)) = nodes.iter().find(|(_, height)| *height > end_height) {
+                return Err(Error::Config(format!(
+                    "the shard node '{}' is at height {}, after the end height {}",
+                    name, height, end_height
+                )));
+            }
+            tip = tip.min(end_height);
+        }
+        let mut shards = vec![];
+        for (index, (name, height)) in nodes.iter().enumerate() {
+            let shard = Shard {
+                name: name.to_string(),
+                start_height: height + 1,
+                end_height: match nodes.get(index + 1) {
+                    Some((_, next_height)) => *next_height,
+                    None => tip.max(*height),
+                },
+            };
+            info!(
+                "Assigning heights {} to {} to the '{}' node",
+                shard.start_height, shard.end_height, shard.name
+            );
+            // Logs of an earlier scan without a checkpoint are stale.
+            remove_log(&log_path(self.checkpoint_path, &shard.name))?;
+            shards.push(shard);
+        }
+        let (_, height) = nodes[0];
+        Ok(Checkpoint {
+            height,
+            hash: self.data_source.get_block_hash(height)?,
+            output_len: output.position()?,
+            pending: None,
+            history: VecDeque::new(),
+            shards,
+        })
+    }
+
+    // Opens the log of the shard. If the previous run crashed after the shard
+    // node got a block, but before that was logged, it's logged now.
+    fn recover(
+        &self,
+        shard: &Shard,
+        engine: &RpcEngine,
+        height: u64,
+        written_height: u64,
+    ) -> Result<ShardLog, Error> {
+        if height + 1 < shard.start_height || height > shard.end_height {
+            return Err(Error::State(format!(
+                "The '{}' node is at height {}, but its shard spans the heights {} to {}",
+                shard.name, height, shard.start_height, shard.end_height
+            )));
+        }
+        let (mut log, entries) = ShardLog::open(log_path(self.checkpoint_path, &shard.name))?;
+        let mut blocks = BTreeMap::new();
+        for entry in entries {
+            apply(&mut blocks, entry);
+        }
+        for (block_height, tested) in blocks.range_mut(..=height) {
+            if tested.was_unknown.is_some() {
+                continue;
+            }
+            if engine.block_hash(*block_height)? != Some(tested.block.hash) {
+                return Err(Error::State(format!(
+                    "The '{}' node has another block than {} at height {}",
+                    shard.name, tested.block.hash, block_height
+                )));
+            }
+            info!(
+                "Block {} was submitted to the '{}' node by the previous run",
+                block_height, shard.name
+            );
+            log.append(&LogEntry::Submitted {
+                height: *block_height,
+                was_unknown: true,
+            })?;
+            tested.was_unknown = Some(true);
+        }
+        for block_height in shard.start_height.max(written_height + 1)..=height {
+            if blocks
+                .get(&block_height)
+                .is_none_or(|tested| tested.was_unknown.is_none())
+            {
+                return Err(Error::State(format!(
+                    "The '{}' node is at height {}, but the rows of block {} are missing from {}",
+                    shard.name,
+                    height,
+                    block_height,
+                    log.path.display()
+                )));
+            }
+        }
+        Ok(log)
+    }
+
+    // Tests the blocks of the shard on the shard node. Each block is only
+    // submitted once the coordinator logged its rows.
+    fn scan_shard(
+        &self,
+        index: usize,
+        shard: &Shard,
+        engine: &RpcEngine,
+        height: u64,
+        messages: &Sender<Message>,
+        stop: &AtomicBool,
+    ) -> Result<(), Error> {
+        let tester = BlockTester {
+            policy_engine: engine,
+            relaxed_engine: None,
+            compared_engines: vec![],
+            native_engine: match self.cross_check_native {
+                true => Some(NativeEngine),
+                false => None,
+            },
+            data_source: self.data_source.as_ref(),
+            package_acceptance: self.package_acceptance,
+            test_batch_size: self.test_batch_size,
+            out_of_band_max_position: self.out_of_band_max_position,
+        };
+        let pools = default_data(Network::Bitcoin);
+        let mut prefetcher = Prefetcher::new(
+            self.data_source.clone(),
+            self.prefetch_blocks,
+            self.cross_check_native,
+            self.poll_interval,
+        );
+        let coordinator_stopped = || Error::State("the coordinator stopped".to_string());
+
+        let mut last_hash = engine.block_hash(height)?;
+        for height in height + 1..=shard.end_height {
+            if stop.load(Ordering::Relaxed) || interrupt::requested() {
+                break;
+            }
+            let PrefetchedBlock {
+                hash,
+                block,
+                prevouts,
+            } = prefetcher.get(height)?;
+            if Some(block.header.prev_blockhash) != last_hash {
+                return Err(Error::State(format!(
+                    "Block {} (height {}) doesn't build on the block of the '{}' node at height {}",
+                    hash,
+                    height,
+                    shard.name,
+                    height - 1
+                )));
+            }
+
+            let miner = tester::pool_name(&block, &pools);
+            let rows = tester.test_block(height, hash, &block, prevouts.as_deref(), &miner)?;
+            let (stored, is_stored) = mpsc::sync_channel(1);
+            messages
+                .send(Message::Tested {
+                    shard: index,
+                    block: PendingBlock {
+                        height,
+                        hash,
+                        miner,
+                        rows,
+                    },
+                    stored,
+                })
+                .map_err(|_| coordinator_stopped())?;
+            is_stored.recv().map_err(|_| coordinator_stopped())?;
+
+            let was_unknown = engine.connect_block(&block, height)?;
+            messages
+                .send(Message::Submitted {
+                    shard: index,
+                    height,
+                    was_unknown,
+                })
+                .map_err(|_| coordinator_stopped())?;
+            last_hash = Some(hash);
+        }
+        Ok(())
+    }
+}
+
+// Writes the rows of the blocks the shard nodes got to the output in height
+// order. Only the blocks of the shard that is currently written are kept in
+// memory. The blocks of later shards are read from their logs once it's
+// their turn.
+struct Coordinator<'a> {
+    checkpoint_path: &'a Path,
+    shards: &'a [Shard],
+    // The index of the shard with the next block to write.
+    current: usize,
+    blocks: BTreeMap<u64, TestedBlock>,
+}
+
+impl Coordinator<'_> {
+    fn coordinate(
+        &mut self,
+        receiver: Receiver<Message>,
+        running: usize,
+        checkpoint: &mut Checkpoint,
+        logs: &mut [ShardLog],
+        output: &mut dyn OutputSink,
+    ) -> Result<(), Error> {
+        let mut running = running;
+        while running > 0 {
+            let message = receiver
+                .recv()
+                .map_err(|_| Error::State("the shard threads stopped".to_string()))?;
+            match message {
+                Message::Tested {
+                    shard,
+                    block,
+                    stored,
+                } => {
+                    let entry = LogEntry::Tested(block);
+                    logs[shard].append(&entry)?;
+                    if shard == self.current {
+                        apply(&mut self.blocks, entry);
+                    }
+                    // The shard thread stops once it notices that we stopped.
+                    let _ = stored.send(());
+                }
+                Message::Submitted {
+                    shard,
+                    height,
+                    was_unknown,
+                } => {
+                    let entry = LogEntry::Submitted {
+                        height,
+                        was_unknown,
+                    };
+                    logs[shard].append(&entry)?;
+                    if shard == self.current {
+                        apply(&mut self.blocks, entry);
+                        self.write_ready(checkpoint, output)?;
+                    }
+                }
+                Message::Finished { shard, result } => {
+                    result?;
+                    info!(
+                        "The '{}' node tested all blocks of its shard",
+                        self.shards[shard].name
+                    );
+                    running -= 1;
+                }
+            }
+        }
+        self.write_ready(checkpoint, output)?;
+        if interrupt::requested() {
+            info!("Stopped at height {}", checkpoint.height);
+            return Ok(());
+        }
+
+        let end_height = self.shards.last().map(|shard| shard.end_height);
+        if end_height.is_some_and(|end_height| checkpoint.height < end_height) {
+            return Err(Error::State(format!(
+                "The shard nodes stopped, but the rows of block {} are missing",
+                checkpoint.height + 1
+            )));
+        }
+        Ok(())
+    }
+
+    // Writes the submitted blocks that follow the last written block.
+    fn write_ready(
+        &mut self,
+        checkpoint: &mut Checkpoint,
+        output: &mut dyn OutputSink,
+    ) -> Result<(), Error> {
+        loop {
+            let height = checkpoint.height + 1;
+            match self.shards.get(self.current) {
+                Some(shard) if height > shard.end_height => {
+                    self.load_current(checkpoint)?;
+                    continue;
+                }
+                Some(_) => {}
+                None => break,
+            }
+            match self.blocks.get(&height) {
+                Some(tested) if tested.was_unknown.is_some() => {}
+                _ => break,
+            }
+            let TestedBlock { block, was_unknown } =
+                self.blocks.remove(&height).expect("the block is there");
+            let output_len = if was_unknown == Some(true) {
+                for row in block.rows.iter() {
+                    info!(
+                        "Transaction rejected in block {}: txid: {} reason: {:?} pool: {}",
+                        row.height, row.txid, row.reject_reason, row.miner,
+                    );
+                }
+                output.write_block(block.height, &block.hash, &block.miner, &block.rows)?
+            } else {
+                checkpoint.output_len
+            };
+            checkpoint.advance(block.height, block.hash, output_len);
+            checkpoint.store(self.checkpoint_path)?;
+        }
+        Ok(())
+    }
+
+    // Moves on to the shard with the block after the last written block and
+    // reads the blocks its node already got from its log. The logs of the
+    // written shards are removed.
+    fn load_current(&mut self, checkpoint: &Checkpoint) -> Result<(), Error> {
+        self.current = self
+            .shards
+            .iter()
+            .position(|shard| shard.end_height > checkpoint.height)
+            .unwrap_or(self.shards.len());
+        for shard in self.shards[..self.current].iter() {
+            remove_log(&log_path(self.checkpoint_path, &shard.name))?;
+        }
+        self.blocks.clear();
+        if let Some(shard) = self.shards.get(self.current) {
+            let (entries, _) = read_log(&log_path(self.checkpoint_path, &shard.name))?;
+            for entry in entries {
+                apply(&mut self.blocks, entry);
+            }
+            self.blocks.retain(|height, _| *height > checkpoint.height);
+        }
+        Ok(())
+    }
+}
+
+/// An entry of the log of a shard. A block is logged once its node tested
+/// it and again once the block was submitted to the node.
+#[derive(serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum LogEntry {
+    Tested(PendingBlock),
+    Submitted { height: u64, was_unknown: bool },
+}
+
+// A block of a shard and, once it was submitted to the shard node, whether
+// the node didn't know it before.
+struct TestedBlock {
+    block: PendingBlock,
+    was_unknown: Option<bool>,
+}
+
+// A block tested again (e.g. when the previous run crashed before it was
+// submitted) replaces the earlier entry.
+fn apply(blocks: &mut BTreeMap<u64, TestedBlock>, entry: LogEntry) {
+    match entry {
+        LogEntry::Tested(block) => {
+            blocks.insert(
+                block.height,
+                TestedBlock {
+                    block,
+                    was_unknown: None,
+                },
+            );
+        }
+        LogEntry::Submitted {
+            height,
+            was_unknown,
+        } => {
+            if let Some(tested) = blocks.get_mut(&height) {
+                tested.was_unknown = Some(was_unknown);
+            }
+        }
+    }
+}
+
+/// The blocks tested by a shard node, one JSON entry per line, next to the
+/// checkpoint. It keeps the rows of the blocks until all blocks before them
+/// are written to the output.
+struct ShardLog {
+    path: PathBuf,
+    file: File,
+}
+
+impl ShardLog {
+    // Opens the log for appending and returns its entries. A partially
+    // written last entry is removed.
+    fn open(path: PathBuf) -> io::Result<(ShardLog, Vec<LogEntry>)> {
+        let (entries, len) = read_log(&path)?;
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        file.set_len(len)?;
+        Ok((ShardLog { path, file }, entries))
+    }
+
+    fn append(&mut self, entry: &LogEntry) -> io::Result<()> {
+        let mut line = serde_json::to_vec(entry).map_err(io::Error::from)?;
+        line.push(b'\n');
+        self.file.write_all(&line)?;
+        self.file.sync_data()
+    }
+}
+
+// Reads the complete entries of the log and their length in bytes.
+fn read_log(path: &Path) -> io::Result<(Vec<LogEntry>, u64)> {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok((vec![], 0)),
+        Err(e) => return Err(e),
+    };
+    let mut entries = vec![];
+    let mut len = 0;
+    for line in content.split_inclusive('\n') {
+        if !line.ends_with('\n') {
+            break;
+        }
+        entries.push(
+            serde_json::from_str(line)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+        );
+        len += line.len() as u64;
+    }
+    Ok((entries, len))
+}
+
+fn remove_log(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+fn log_path(checkpoint_path: &Path, shard: &str) -> PathBuf {
+    let mut path = checkpoint_path.as_os_str().to_owned();
+    path.push(format!(".{}", shard));
+    PathBuf::from(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_node::{self, mock_node};
+    use crate::source::RpcSource;
+    use crate::ResultRow;
+    use bitcoincore_rpc::bitcoin::hashes::Hash;
+    use bitcoincore_rpc::bitcoin::BlockHash;
+    use serde_json::json;
+
+    // Records the heights of the written blocks.
+    #[derive(Default)]
+    struct Output(Vec<u64>);
+
+    impl OutputSink for Output {
+        fn position(&mut self) -> Result<u64, Error> {
+            Ok(self.0.len() as u64)
+        }
+
+        fn write_block(
+            &mut self,
+            height: u64,
+            _hash: &BlockHash,
+            _miner: &str,
+            _rows: &[ResultRow],
+        ) -> Result<u64, Error> {
+            self.0.push(height);
+            Ok(self.0.len() as u64)
+        }
+
+        fn truncate(&mut self, position: u64) -> Result<(), Error> {
+            self.0.truncate(position as usize);
+            Ok(())
+        }
+    }
+
+    fn dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "non-standard-shard-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn tested(height: u64) -> LogEntry {
+        LogEntry::Tested(PendingBlock {
+            height,
+            hash: BlockHash::from_byte_array([height as u8; 32]),
+            miner: "Unknown".to_string(),
+            rows: vec![],
+        })
+    }
+
+    fn submitted(height: u64) -> LogEntry {
+        LogEntry::Submitted {
+            height,
+            was_unknown: true,
+        }
+    }
+
+    fn shard(name: &str, start_height: u64, end_height: u64) -> Shard {
+        Shard {
+            name: name.to_string(),
+            start_height,
+            end_height,
+        }
+    }
+
+    #[test]
+    fn replays_the_log_without_a_partial_entry() {
+        let dir = dir("log");
+        let path = dir.join("log");
+        let (mut log, entries) = ShardLog::open(path.clone()).unwrap();
+        assert!(entries.is_empty());
+        for entry in [tested(1), submitted(1), tested(2), tested(2)] {
+            log.append(&entry).unwrap();
+        }
+        log.file.write_all(b"{\"submitted\":{\"hei").unwrap();
+
+        let (mut log, entries) = ShardLog::open(path.clone()).unwrap();
+        assert_eq!(entries.len(), 4);
+        let mut blocks = BTreeMap::new();
+        for entry in entries {
+            apply(&mut blocks, entry);
+        }
+        assert_eq!(blocks.len(), 2);
+        assert_eq!(blocks[&1].was_unknown, Some(true));
+        assert_eq!(blocks[&2].was_unknown, None);
+
+        // Entries appended after the partial entry was removed are read.
+        log.append(&submitted(2)).unwrap();
+        let (entries, _) = read_log(&path).unwrap();
+        assert_eq!(entries.len(), 5);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn resumes_writing_after_a_partially_tested_shard() {
+        let dir = dir("resume");
+        let checkpoint_path = dir.join("checkpoint");
+        let shards = vec![shard("shard_a", 1, 2), shard("shard_b", 3, 4)];
+        // The previous run crashed after the node of the first shard tested
+        // block 2, but before it was submitted. The node of the second shard
+        // already got block 3.
+        let (mut log_a, _) = ShardLog::open(log_path(&checkpoint_path, "shard_a")).unwrap();
+        for entry in [tested(1), submitted(1), tested(2)] {
+            log_a.append(&entry).unwrap();
+        }
+        let (mut log_b, _) = ShardLog::open(log_path(&checkpoint_path, "shard_b")).unwrap();
+        for entry in [tested(3), submitted(3)] {
+            log_b.append(&entry).unwrap();
+        }
+
+        let mut checkpoint = Checkpoint {
+            height: 0,
+            hash: BlockHash::all_zeros(),
+            output_len: 0,
+            pending: None,
+            history: VecDeque::new(),
+            shards: shards.clone(),
+        };
+        let mut output = Output::default();
+        let mut coordinator = Coordinator {
+            checkpoint_path: &checkpoint_path,
+            shards: &shards,
+            current: 0,
+            blocks: BTreeMap::new(),
+        };
+        coordinator.load_current(&checkpoint).unwrap();
+        coordinator
+            .write_ready(&mut checkpoint, &mut output)
+            .unwrap();
+        assert_eq!(output.0, vec![1]);
+        assert_eq!(checkpoint.height, 1);
+
+        // Block 2 is tested again and submitted on the next run.
+        let mut logs = vec![log_a, log_b];
+        let (sender, receiver) = mpsc::channel();
+        let (stored, _is_stored) = mpsc::sync_channel(1);
+        let tested = match tested(2) {
+            LogEntry::Tested(block) => block,
+            LogEntry::Submitted { .. } => unreachable!(),
+        };
+        for message in [
+            Message::Tested {
+                shard: 0,
+                block: tested,
+                stored,
+            },
+            Message::Submitted {
+                shard: 0,
+                height: 2,
+                was_unknown: true,
+            },
+            Message::Finished {
+                shard: 0,
+                result: Ok(()),
+            },
+        ] {
+            sender.send(message).unwrap();
+        }
+        let result = coordinator.coordinate(receiver, 1, &mut checkpoint, &mut logs, &mut output);
+        // Block 4 was never tested.
+        assert!(matches!(result, Err(Error::State(_))));
+        assert_eq!(output.0, vec![1, 2, 3]);
+        assert_eq!(checkpoint.height, 3);
+        assert!(!log_path(&checkpoint_path, "shard_a").exists());
+        assert_eq!(
+            Checkpoint::load(&checkpoint_path).unwrap().unwrap().height,
+            3
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn refuses_to_resume_with_another_end_height() {
+        let dir = dir("end");
+        let checkpoint_path = dir.join("checkpoint");
+        Checkpoint {
+            height: 0,
+            hash: BlockHash::all_zeros(),
+            output_len: 0,
+            pending: None,
+            history: VecDeque::new(),
+            shards: vec![shard("shard_a", 1, 2), shard("shard_b", 3, 4)],
+        }
+        .store(&checkpoint_path)
+        .unwrap();
+        let url = mock_node(|method, _| match method {
+            "getblockcount" => Ok(json!(0)),
+            _ => Err(format!("unexpected call to {}", method)),
+        });
+        let settings = Config::builder()
+            .set_override("nodes.shard_a.rpc_url", url.clone())
+            .and_then(|b| b.set_override("nodes.shard_a.rpc_auth", "user:pass"))
+            .and_then(|b| b.set_override("nodes.shard_b.rpc_url", url.clone()))
+            .and_then(|b| b.set_override("nodes.shard_b.rpc_auth", "user:pass"))
+            .and_then(|b| b.set_override("nodes.data.rpc_url", url))
+            .and_then(|b| b.set_override("nodes.data.rpc_auth", "user:pass"))
+            .and_then(|b| b.build())
+            .unwrap();
+        let retry = mock_node::retry();
+        let scan = ShardedScan {
+            settings: &settings,
+            retry: &retry,
+            data_source: Arc::new(RpcSource::new(
+                crate::rpc_client(&settings, "data").unwrap(),
+                retry.clone(),
+            )),
+            shard_nodes: vec!["shard_a".to_string(), "shard_b".to_string()],
+            checkpoint_path: &checkpoint_path,
+            prefetch_blocks: 0,
+            poll_interval: Duration::from_millis(1),
+            package_acceptance: false,
+            cross_check_native: false,
+            test_batch_size: 1,
+            out_of_band_max_position: 0,
+            end_height: Some(10),
+        };
+        match scan.run(&mut Output::default()) {
+            Err(Error::Config(message)) => assert!(message.contains("end at height 4")),
+            _ => panic!("resumed with another end height"),
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,661 @@
+use super::leveldb;
+use super::BlockSource;
+use crate::error::Error;
+use bitcoincore_rpc::bitcoin::block::Header;
+use bitcoincore_rpc::bitcoin::consensus::{deserialize, Decodable};
+use bitcoincore_rpc::bitcoin::hashes::Hash;
+use bitcoincore_rpc::bitcoin::pow::Work;
+use bitcoincore_rpc::bitcoin::secp256k1::PublicKey;
+use bitcoincore_rpc::bitcoin::{
+    Amount, Block, BlockHash, PubkeyHash, ScriptBuf, ScriptHash, Transaction, TxOut, Txid, VarInt,
+};
+use log::info;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Cursor, Read};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+// Block status flags from Bitcoin Core's chain.h
+const BLOCK_VALID_MASK: u64 = 7;
+const BLOCK_VALID_SCRIPTS: u64 = 5;
+const BLOCK_HAVE_DATA: u64 = 8;
+const BLOCK_HAVE_UNDO: u64 = 16;
+const BLOCK_FAILED_MASK: u64 = 32 | 64;
+
+const MAX_SCRIPT_SIZE: u64 = 10_000;
+// The number of special scripts in Bitcoin Core's script compression.
+const SPECIAL_SCRIPTS: u64 = 6;
+const OP_CHECKSIG: u8 = 0xac;
+const OP_RETURN: u8 = 0x6a;
+
+// The outputs spent by each transaction of a block.
+type Prevouts = HashMap<Txid, Vec<TxOut>>;
+
+#[derive(Debug)]
+struct IndexEntry {
+    height: u64,
+    status: u64,
+    file: u64,
+    data_pos: u64,
+    undo_pos: u64,
+    header: Header,
+}
+
+/// Reads blocks from the blk*.dat files of a Bitcoin Core `blocks/`
+/// directory. The block index in `blocks/index` is used to find blocks and
+/// the undo data in the rev*.dat files to calculate fees. The directory must
+/// not be used by a running Bitcoin Core node.
+pub struct BlocksDirSource {
+    dir: PathBuf,
+    xor_key: [u8; 8],
+    index: HashMap<BlockHash, IndexEntry>,
+    // The block hashes of the most-work chain by height.
+    chain: Vec<BlockHash>,
+    // The spent outputs of the transactions in the last block we calculated
+    // fees for.
+    prevouts: Mutex<Option<(BlockHash, Prevouts)>>,
+}
+
+impl BlocksDirSource {
+    pub fn open(dir: &Path) -> Result<BlocksDirSource, Error> {
+        // Since Bitcoin Core v28.0, the block and undo files are XOR-ed with
+        // the key in xor.dat. Older versions don't have the file.
+        let mut xor_key = [0u8; 8];
+        match File::open(dir.join("xor.dat")) {
+            Ok(mut file) => file.read_exact(&mut xor_key)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e.into()),
+        }
+
+        info!("Loading the block index from {}..", dir.display());
+        let mut index = HashMap::new();
+        for (key, value) in leveldb::read_prefix(&dir.join("index"), b"b")? {
+            let hash = BlockHash::from_slice(&key[1..])
+                .map_err(|e| Error::Source(format!("invalid block index key: {}", e)))?;
+            index.insert(hash, parse_index_entry(&value)?);
+        }
+
+        let chain = most_work_chain(&index)?;
+        info!(
+            "Loaded {} block index entries. The most-work chain has a height of {}",
+            index.len(),
+            chain.len() - 1
+        );
+        Ok(BlocksDirSource {
+            dir: dir.to_path_buf(),
+            xor_key,
+            index,
+            chain,
+            prevouts: Mutex::new(None),
+        })
+    }
+
+    fn entry(&self, hash: &BlockHash) -> Result<&IndexEntry, Error> {
+        self.index
+            .get(hash)
+            .ok_or_else(|| Error::Source(format!("block {} is not in the block index", hash)))
+    }
+
+    // Reads a record from a blk*.dat or rev*.dat file. Records are prefixed
+    // with the network magic and their size.
+    fn read_record(&self, prefix: &str, file: u64, pos: u64) -> Result<Vec<u8>, Error> {
+        let path = self.dir.join(format!("{}{:05}.dat", prefix, file));
+        let file = File::open(path)?;
+        let mut size = [0u8; 4];
+        file.read_exact_at(&mut size, pos - 4)?;
+        self.xor(&mut size, pos - 4);
+        let mut data = vec![0u8; u32::from_le_bytes(size) as usize];
+        file.read_exact_at(&mut data, pos)?;
+        self.xor(&mut data, pos);
+        Ok(data)
+    }
+
+    fn xor(&self, data: &mut [u8], offset: u64) {
+        if self.xor_key == [0u8; 8] {
+            return;
+        }
+        for (i, byte) in data.iter_mut().enumerate() {
+            *byte ^= self.xor_key[(offset as usize + i) % self.xor_key.len()];
+        }
+    }
+
+    // Maps the transactions of the block to the outputs they spend, using
+    // the block's undo data.
+    fn block_prevouts(&self, block_hash: &BlockHash) -> Result<Prevouts, Error> {
+        let entry = self.entry(block_hash)?;
+        if entry.status & BLOCK_HAVE_UNDO == 0 {
+            return Err(Error::Source(format!(
+                "no undo data for block {}",
+                block_hash
+            )));
+        }
+        let block = self.get_block(block_hash)?;
+        let undo = parse_block_undo(&self.read_record("rev", entry.file, entry.undo_pos)?)?;
+        // The undo data doesn't contain an entry for the coinbase transaction.
+        if undo.len() + 1 != block.txdata.len() {
+            return Err(Error::Source(format!(
+                "undo data of block {} doesn't match the block",
+                block_hash
+            )));
+        }
+        Ok(block
+            .txdata
+            .iter()
+            .skip(1)
+            .map(|tx| tx.txid())
+            .zip(undo)
+            .collect())
+    }
+}
+
+impl BlockSource for BlocksDirSource {
+    fn get_block_count(&self) -> Result<u64, Error> {
+        Ok(self.chain.len() as u64 - 1)
+    }
+
+    fn get_block_hash(&self, height: u64) -> Result<BlockHash, Error> {
+        self.chain
+            .get(height as usize)
+            .copied()
+            .ok_or_else(|| Error::Source(format!("no block at height {}", height)))
+    }
+
+    fn get_block(&self, hash: &BlockHash) -> Result<Block, Error> {
+        let entry = self.entry(hash)?;
+        if entry.status & BLOCK_HAVE_DATA == 0 {
+            return Err(Error::Source(format!("no data for block {}", hash)));
+        }
+        let data = self.read_record("blk", entry.file, entry.data_pos)?;
+        deserialize(&data).map_err(|e| Error::Source(format!("invalid block {}: {}", hash, e)))
+    }
+
+    fn get_fee(&self, tx: &Transaction, block_hash: &BlockHash) -> Result<Option<Amount>, Error> {
+        Ok(self.get_prevouts(tx, block_hash)?.map(|prevouts| {
+            let input: Amount = prevouts.iter().map(|txout| txout.value).sum();
+            let output: Amount = tx.output.iter().map(|txout| txout.value).sum();
+            input - output
+        }))
+    }
+
+    fn get_prevouts(
+        &self,
+        tx: &Transaction,
+        block_hash: &BlockHash,
+    ) -> Result<Option<Vec<TxOut>>, Error> {
+        let mut cache = self.prevouts.lock().expect("prevouts lock poisoned");
+        if cache.as_ref().map(|(hash, _)| hash) != Some(block_hash) {
+            *cache = Some((*block_hash, self.block_prevouts(block_hash)?));
+        }
+        let (_, prevouts) = cache.as_ref().expect("prevouts were just cached");
+        Ok(prevouts.get(&tx.txid()).cloned())
+    }
+
+    fn get_median_feerate(
+        &self,
+        block: &Block,
+        block_hash: &BlockHash,
+    ) -> Result<Option<f64>, Error> {
+        let mut feerates = vec![];
+        for tx in block.txdata.iter().skip(1) {
+            let Some(fee) = self.get_fee(tx, block_hash)? else {
+                return Ok(None);
+            };
+            feerates.push((fee.to_sat() as f64 / tx.vsize() as f64, tx.weight().to_wu()));
+        }
+        Ok(super::median_feerate(feerates))
+    }
+
+    // Would need the transaction index of the datadir.
+    fn get_transaction(
+        &self,
+        _txid: &Txid,
+    ) -> Result<Option<(Transaction, Option<BlockHash>)>, Error> {
+        Ok(None)
+    }
+}
+
+// Picks the chain with the most work from the fully validated blocks in the
+// index.
+fn most_work_chain(index: &HashMap<BlockHash, IndexEntry>) -> Result<Vec<BlockHash>, Error> {
+    let mut by_height: Vec<(&BlockHash, &IndexEntry)> = index.iter().collect();
+    by_height.sort_by_key(|(_, entry)| entry.height);
+
+    let mut chain_work: HashMap<BlockHash, Work> = HashMap::new();
+    let mut tip: Option<(BlockHash, Work)> = None;
+    for (hash, entry) in by_height {
+        let work = match chain_work.get(&entry.header.prev_blockhash) {
+            Some(prev_work) => *prev_work + entry.header.work(),
+            None if entry.height == 0 => entry.header.work(),
+            // A block without a known parent (e.g. a pruned or stale header).
+            None => continue,
+        };
+        chain_work.insert(*hash, work);
+
+        let valid = entry.status & BLOCK_VALID_MASK >= BLOCK_VALID_SCRIPTS
+            && entry.status & BLOCK_HAVE_DATA != 0
+            && entry.status & BLOCK_FAILED_MASK == 0;
+        let more_work = match tip {
+            Some((_, tip_work)) => work > tip_work,
+            None => true,
+        };
+        if valid && more_work {
+            tip = Some((*hash, work));
+        }
+    }
+
+    let (mut hash, _) =
+        tip.ok_or_else(|| Error::Source("no valid blocks in the block index".to_string()))?;
+    let mut chain = vec![];
+    loop {
+        chain.push(hash);
+        let entry = &index[&hash];
+        if entry.height == 0 {
+            break;
+        }
+        hash = entry.header.prev_blockhash;
+    }
+    chain.reverse();
+    Ok(chain)
+}
+
+// Parses a CDiskBlockIndex entry of Bitcoin Core's block index.
+fn parse_index_entry(value: &[u8]) -> Result<IndexEntry, Error> {
+    let mut reader = Cursor::new(value);
+    let _client_version = read_core_varint(&mut reader)?;
+    let height = read_core_varint(&mut reader)?;
+    let status = read_core_varint(&mut reader)?;
+    let _tx_count = read_core_varint(&mut reader)?;
+    let mut file = 0;
+    let mut data_pos = 0;
+    let mut undo_pos = 0;
+    if status & (BLOCK_HAVE_DATA | BLOCK_HAVE_UNDO) != 0 {
+        file = read_core_varint(&mut reader)?;
+    }
+    if status & BLOCK_HAVE_DATA != 0 {
+        data_pos = read_core_varint(&mut reader)?;
+    }
+    if status & BLOCK_HAVE_UNDO != 0 {
+        undo_pos = read_core_varint(&mut reader)?;
+    }
+    let header = Header::consensus_decode(&mut reader)
+        .map_err(|e| Error::Source(format!("invalid block index entry: {}", e)))?;
+    Ok(IndexEntry {
+        height,
+        status,
+        file,
+        data_pos,
+        undo_pos,
+        header,
+    })
+}
+
+// Parses a CBlockUndo: for each transaction (except the coinbase) the
+// outputs spent by its inputs.
+fn parse_block_undo(data: &[u8]) -> Result<Vec<Vec<TxOut>>, Error> {
+    let mut reader = Cursor::new(data);
+    let tx_count = read_compact_size(&mut reader)?;
+    let mut undo = Vec::with_capacity(tx_count as usize);
+    for _ in 0..tx_count {
+        let input_count = read_compact_size(&mut reader)?;
+        let mut prevouts = Vec::with_capacity(input_count as usize);
+        for _ in 0..input_count {
+            let code = read_core_varint(&mut reader)?;
+            // Old versions stored the transaction version for non-zero heights.
+            if code >> 1 > 0 {
+                let _version = read_core_varint(&mut reader)?;
+            }
+            let value = Amount::from_sat(decompress_amount(read_core_varint(&mut reader)?));
+            let script_pubkey = read_compressed_script(&mut reader)?;
+            prevouts.push(TxOut {
+                value,
+                script_pubkey,
+            });
+        }
+        undo.push(prevouts);
+    }
+    Ok(undo)
+}
+
+fn read_compact_size(reader: &mut Cursor<&[u8]>) -> Result<u64, Error> {
+    VarInt::consensus_decode(reader)
+        .map(|v| v.0)
+        .map_err(|e| Error::Source(format!("invalid undo data: {}", e)))
+}
+
+// Bitcoin Core's VARINT: a base 128 encoding with the most significant
+// digit first, where each continuation adds one.
+fn read_core_varint(reader: &mut Cursor<&[u8]>) -> Result<u64, Error> {
+    let mut n: u64 = 0;
+    loop {
+        let mut byte = [0u8; 1];
+        reader.read_exact(&mut byte)?;
+        n = n
+            .checked_mul(128)
+            .ok_or_else(|| Error::Source("VARINT too large".to_string()))?
+            | (byte[0] & 0x7f) as u64;
+        if byte[0] & 0x80 == 0 {
+            return Ok(n);
+        }
+        n += 1;
+    }
+}
+
+// See DecompressAmount() in Bitcoin Core's compressor.cpp
+fn decompress_amount(x: u64) -> u64 {
+    if x == 0 {
+        return 0;
+    }
+    let mut x = x - 1;
+    let mut e = x % 10;
+    x /= 10;
+    let mut n = if e < 9 {
+        let d = (x % 9) + 1;
+        x /= 9;
+        x * 10 + d
+    } else {
+        x + 1
+    };
+    while e > 0 {
+        n *= 10;
+        e -= 1;
+    }
+    n
+}
+
+// See ScriptCompression and DecompressScript() in Bitcoin Core's compressor.h
+fn read_compressed_script(reader: &mut Cursor<&[u8]>) -> Result<ScriptBuf, Error> {
+    let size = read_core_varint(reader)?;
+    let mut read = |len: usize| -> Result<Vec<u8>, Error> {
+        let mut bytes = vec![0u8; len];
+        reader.read_exact(&mut bytes)?;
+        Ok(bytes)
+    };
+    let len = size.saturating_sub(SPECIAL_SCRIPTS);
+    if size >= SPECIAL_SCRIPTS && len > MAX_SCRIPT_SIZE {
+        // Overly long scripts are replaced with a short unspendable one.
+        read(len as usize)?;
+        return Ok(ScriptBuf::from(vec![OP_RETURN]));
+    }
+    let invalid = |e: &dyn std::fmt::Display| Error::Source(format!("invalid script: {}", e));
+    match size {
+        0x00 => {
+            let hash = PubkeyHash::from_slice(&read(20)?).map_err(|e| invalid(&e))?;
+            Ok(ScriptBuf::new_p2pkh(&hash))
+        }
+        0x01 => {
+            let hash = ScriptHash::from_slice(&read(20)?).map_err(|e| invalid(&e))?;
+            Ok(ScriptBuf::new_p2sh(&hash))
+        }
+        0x02 | 0x03 => {
+            let mut script = vec![33, size as u8];
+            script.extend(read(32)?);
+            script.push(OP_CHECKSIG);
+            Ok(ScriptBuf::from(script))
+        }
+        0x04 | 0x05 => {
+            let mut compressed = vec![size as u8 - 2];
+            compressed.extend(read(32)?);
+            // Bitcoin Core leaves the script empty if the key can't be
+            // decompressed.
+            Ok(match PublicKey::from_slice(&compressed) {
+                Ok(key) => {
+                    let mut script = vec![65];
+                    script.extend(key.serialize_uncompressed());
+                    script.push(OP_CHECKSIG);
+                    ScriptBuf::from(script)
+                }
+                Err(_) => ScriptBuf::new(),
+            })
+        }
+        _ => Ok(ScriptBuf::from(read(len as usize)?)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoincore_rpc::bitcoin::hashes::hex::FromHex;
+    use bitcoincore_rpc::bitcoin::{block, CompactTarget, TxMerkleNode};
+    use std::fs;
+
+    fn hex(s: &str) -> Vec<u8> {
+        Vec::<u8>::from_hex(s).expect("valid hex")
+    }
+
+    fn varint(data: &[u8]) -> u64 {
+        read_core_varint(&mut Cursor::new(data)).expect("valid VARINT")
+    }
+
+    // Bitcoin Core's WriteVarInt().
+    fn encode_varint(mut n: u64) -> Vec<u8> {
+        let mut bytes = vec![];
+        loop {
+            let continuation = if bytes.is_empty() { 0 } else { 0x80 };
+            bytes.push((n & 0x7f) as u8 | continuation);
+            if n <= 0x7f {
+                break;
+            }
+            n = (n >> 7) - 1;
+        }
+        bytes.reverse();
+        bytes
+    }
+
+    fn script(data: &[u8]) -> ScriptBuf {
+        read_compressed_script(&mut Cursor::new(data)).expect("valid compressed script")
+    }
+
+    // From varints_bitpatterns in Bitcoin Core's serialize_tests.cpp
+    #[test]
+    fn core_varint_bit_patterns() {
+        let vectors: [(&str, u64); 10] = [
+            ("00", 0),
+            ("7f", 0x7f),
+            ("8000", 0x80),
+            ("a334", 0x1234),
+            ("82fe7f", 0xffff),
+            ("c7e756", 0x123456),
+            ("86ffc7e756", 0x80123456),
+            ("8efefefe7f", 0xffffffff),
+            ("fefefefefefefefe7f", 0x7fffffffffffffff),
+            ("80fefefefefefefefe7f", 0xffffffffffffffff),
+        ];
+        for (encoded, n) in vectors {
+            assert_eq!(varint(&hex(encoded)), n, "{}", encoded);
+            assert_eq!(encode_varint(n), hex(encoded));
+        }
+        assert!(read_core_varint(&mut Cursor::new(&hex("ffffffffffffffffff7f")[..])).is_err());
+        assert!(read_core_varint(&mut Cursor::new(&hex("80")[..])).is_err());
+    }
+
+    // From compress_amounts in Bitcoin Core's compress_tests.cpp
+    #[test]
+    fn decompresses_amounts() {
+        const COIN: u64 = 100_000_000;
+        const CENT: u64 = 1_000_000;
+        let vectors = [
+            (0x0, 0),
+            (0x1, 1),
+            (0x7, CENT),
+            (0x9, COIN),
+            (0x32, 50 * COIN),
+            (0x1406f40, 21_000_000 * COIN),
+        ];
+        for (compressed, amount) in vectors {
+            assert_eq!(decompress_amount(compressed), amount);
+        }
+    }
+
+    #[test]
+    fn decompresses_special_scripts() {
+        let hash = "1d0f172a0ecb48aee1be1f2687d2963ae33f71a1";
+        assert_eq!(
+            script(&hex(&format!("00{}", hash))),
+            ScriptBuf::from(hex(&format!("76a914{}88ac", hash)))
+        );
+        assert_eq!(
+            script(&hex(&format!("01{}", hash))),
+            ScriptBuf::from(hex(&format!("a914{}87", hash)))
+        );
+
+        let x = "79be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798";
+        let y = "483ada7726a3c4655da4fbfc0e1108a8fd17b448a68554199c47d08ffb10d4b8";
+        for prefix in ["02", "03"] {
+            assert_eq!(
+                script(&hex(&format!("{}{}", prefix, x))),
+                ScriptBuf::from(hex(&format!("21{}{}ac", prefix, x)))
+            );
+        }
+        // The generator has an even y coordinate.
+        assert_eq!(
+            script(&hex(&format!("04{}", x))),
+            ScriptBuf::from(hex(&format!("4104{}{}ac", x, y)))
+        );
+        let odd = script(&hex(&format!("05{}", x)));
+        assert_eq!(odd.len(), 67);
+        assert_eq!(&odd.as_bytes()[..34], &hex(&format!("4104{}", x))[..]);
+        assert_ne!(odd, script(&hex(&format!("04{}", x))));
+        // x = 5 isn't on the curve.
+        let not_on_curve = format!("04{:064x}", 5);
+        assert_eq!(script(&hex(&not_on_curve)), ScriptBuf::new());
+    }
+
+    #[test]
+    fn decompresses_other_scripts() {
+        // Other scripts are prefixed with their size plus the number of
+        // special scripts.
+        assert_eq!(script(&hex("096a0142")), ScriptBuf::from(hex("6a0142")));
+        assert_eq!(script(&hex("06")), ScriptBuf::new());
+
+        let mut too_long = encode_varint(SPECIAL_SCRIPTS + MAX_SCRIPT_SIZE + 1);
+        too_long.extend(vec![0x51; MAX_SCRIPT_SIZE as usize + 1]);
+        assert_eq!(script(&too_long), ScriptBuf::from(vec![OP_RETURN]));
+
+        assert!(read_compressed_script(&mut Cursor::new(&hex("0a6a01")[..])).is_err());
+    }
+
+    #[test]
+    fn parses_block_undo() {
+        let hash = "1d0f172a0ecb48aee1be1f2687d2963ae33f71a1";
+        let mut undo = vec![2]; // transactions
+        undo.push(1); // inputs
+        undo.extend(encode_varint(2 * 170 + 1)); // height 170, coinbase
+        undo.extend(encode_varint(1)); // the version stored by old versions
+        undo.extend(encode_varint(0x32)); // 50 BTC
+        undo.extend(hex(&format!("00{}", hash)));
+        undo.push(2); // inputs
+        for _ in 0..2 {
+            undo.push(0); // height 0, no version
+            undo.extend(encode_varint(0x9)); // 1 BTC
+            undo.extend(hex("096a0142"));
+        }
+
+        let undo = parse_block_undo(&undo).expect("valid undo data");
+        assert_eq!(undo.len(), 2);
+        assert_eq!(
+            undo[0],
+            vec![TxOut {
+                value: Amount::from_btc(50.0).expect("valid amount"),
+                script_pubkey: ScriptBuf::from(hex(&format!("76a914{}88ac", hash))),
+            }]
+        );
+        assert_eq!(undo[1].len(), 2);
+        assert_eq!(undo[1][1].value, Amount::ONE_BTC);
+        assert_eq!(undo[1][1].script_pubkey, ScriptBuf::from(hex("6a0142")));
+
+        assert!(parse_block_undo(&[1, 1]).is_err());
+    }
+
+    const GENESIS_HEADER: &str = "0100000000000000000000000000000000000000000000000000000000000000000000003ba3edfd7a7b12b27ac72c3e67768f617fc81bc3888a51323a9fb8aa4b1e5e4a29ab5f49ffff001d1dac2b7c";
+
+    #[test]
+    fn parses_index_entry() {
+        let status = BLOCK_VALID_SCRIPTS | BLOCK_HAVE_DATA | BLOCK_HAVE_UNDO;
+        let mut value = vec![];
+        for n in [259900, 0, status, 1, 3, 8, 1234] {
+            value.extend(encode_varint(n));
+        }
+        value.extend(hex(GENESIS_HEADER));
+
+        let entry = parse_index_entry(&value).expect("valid index entry");
+        assert_eq!(entry.height, 0);
+        assert_eq!(entry.status, status);
+        assert_eq!((entry.file, entry.data_pos, entry.undo_pos), (3, 8, 1234));
+        assert_eq!(
+            entry.header.block_hash().to_string(),
+            "000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f"
+        );
+
+        // Without data, neither the file nor the positions are stored.
+        let mut value = vec![];
+        for n in [259900, 1, BLOCK_VALID_SCRIPTS, 1] {
+            value.extend(encode_varint(n));
+        }
+        value.extend(hex(GENESIS_HEADER));
+        let entry = parse_index_entry(&value).expect("valid index entry");
+        assert_eq!((entry.file, entry.data_pos, entry.undo_pos), (0, 0, 0));
+    }
+
+    #[test]
+    fn reads_xored_records() {
+        let dir = std::env::temp_dir().join(format!("non-standard-blocks-{}", std::process::id()));
+        fs::create_dir_all(&dir).expect("can create the directory");
+        let xor_key = hex("0123456789abcdef");
+        let record = hex("deadbeef00112233445566");
+        // Another record before the one we read, so the key is applied at
+        // an offset.
+        let mut file = hex("f9beb4d90300000000aabb");
+        file.extend(hex("f9beb4d9"));
+        file.extend((record.len() as u32).to_le_bytes());
+        let pos = file.len() as u64;
+        file.extend(record.iter());
+        let xored: Vec<u8> = file
+            .iter()
+            .enumerate()
+            .map(|(i, byte)| byte ^ xor_key[i % xor_key.len()])
+            .collect();
+        fs::write(dir.join("blk00002.dat"), xored).expect("can write the file");
+
+        let source = BlocksDirSource {
+            dir: dir.clone(),
+            xor_key: xor_key.try_into().expect("8 bytes"),
+            index: HashMap::new(),
+            chain: vec![],
+            prevouts: Mutex::new(None),
+        };
+        let result = source.read_record("blk", 2, pos);
+        fs::remove_dir_all(&dir).expect("can remove the directory");
+        assert_eq!(result.expect("the record can be read"), record);
+    }
+
+    // The header of a block of the fixture in testdata/blocks.
+    fn fixture_header(height: u32, branch: u8, prev_blockhash: BlockHash) -> Header {
+        let mut merkle_root = [0u8; 32];
+        merkle_root[..3].copy_from_slice(&[height as u8, (height >> 8) as u8, branch]);
+        Header {
+            version: block::Version::from_consensus(0x20000000),
+            prev_blockhash,
+            merkle_root: TxMerkleNode::from_byte_array(merkle_root),
+            time: 1296688602 + height * 600,
+            bits: CompactTarget::from_consensus(0x207fffff),
+            nonce: height,
+        }
+    }
+
+    #[test]
+    fn loads_the_most_work_chain_from_the_block_index() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata/blocks");
+        let source = BlocksDirSource::open(&dir).expect("valid blocks directory");
+        assert_eq!(source.index.len(), 951);
+        // The fork has more work, but was marked as failed after it was
+        // compacted.
+        let mut tip = BlockHash::all_zeros();
+        for height in 0..=650 {
+            tip = fixture_header(height, 0, tip).block_hash();
+        }
+        assert_eq!(source.get_block_count().expect("has a chain"), 650);
+        assert_eq!(source.get_block_hash(650).expect("has a chain"), tip);
+    }
+}
@@ -0,0 +1,367 @@
+use super::BlockSource;
+use crate::error::Error;
+use crate::retry::RetryPolicy;
+use bitcoincore_rpc::bitcoin::consensus::deserialize;
+use bitcoincore_rpc::bitcoin::hashes::hex::FromHex;
+use bitcoincore_rpc::bitcoin::{Amount, Block, BlockHash, ScriptBuf, Transaction, TxOut, Txid};
+use std::io::Read;
+use std::str::FromStr;
+use std::time::Duration;
+
+const HTTP_TIMEOUT: Duration = Duration::from_secs(60);
+
+// The part of the Esplora transaction response we are interested in.
+#[derive(serde::Deserialize)]
+struct EsploraTx {
+    fee: Option<u64>,
+    vin: Vec<EsploraInput>,
+}
+
+// The prevout is null for the coinbase input.
+#[derive(serde::Deserialize)]
+struct EsploraInput {
+    prevout: Option<EsploraPrevout>,
+}
+
+#[derive(serde::Deserialize)]
+struct EsploraPrevout {
+    scriptpubkey: ScriptBuf,
+    value: u64,
+}
+
+// The block hash is missing for unconfirmed transactions.
+#[derive(serde::Deserialize)]
+struct EsploraTxStatus {
+    block_hash: Option<BlockHash>,
+}
+
+/// Reads blocks from an Esplora (or Electrs) compatible REST API, e.g. a
+/// self-hosted instance or https://blockstream.info/api.
+pub struct EsploraSource {
+    base_url: String,
+    agent: ureq::Agent,
+    retry: RetryPolicy,
+}
+
+impl EsploraSource {
+    pub fn new(base_url: &str, retry: RetryPolicy) -> EsploraSource {
+        EsploraSource {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            agent: ureq::AgentBuilder::new().timeout(HTTP_TIMEOUT).build(),
+            retry,
+        }
+    }
+
+    fn get(&self, path: &str) -> Result<ureq::Response, Error> {
+        let url = format!("{}{}", self.base_url, path);
+        self.retry.retry(&format!("GET {}", url), || {
+            self.agent.get(&url).call().map_err(Error::from)
+        })
+    }
+
+    fn get_text(&self, path: &str) -> Result<String, Error> {
+        Ok(self.get(path)?.into_string()?.trim().to_string())
+    }
+
+    fn get_tx(&self, tx: &Transaction) -> Result<EsploraTx, Error> {
+        let response = self.get(&format!("/tx/{}", tx.txid()))?;
+        serde_json::from_reader(response.into_reader())
+            .map_err(|e| Error::Source(format!("invalid transaction {}: {}", tx.txid(), e)))
+    }
+}
+
+impl BlockSource for EsploraSource {
+    fn get_block_count(&self) -> Result<u64, Error> {
+        let height = self.get_text("/blocks/tip/height")?;
+        height
+            .parse()
+            .map_err(|e| Error::Source(format!("invalid tip height '{}': {}", height, e)))
+    }
+
+    fn get_block_hash(&self, height: u64) -> Result<BlockHash, Error> {
+        let hash = self.get_text(&format!("/block-height/{}", height))?;
+        BlockHash::from_str(&hash)
+            .map_err(|e| Error::Source(format!("invalid block hash '{}': {}", hash, e)))
+    }
+
+    fn get_block(&self, hash: &BlockHash) -> Result<Block, Error> {
+        let mut raw = vec![];
+        self.get(&format!("/block/{}/raw", hash))?
+            .into_reader()
+            .read_to_end(&mut raw)?;
+        deserialize(&raw).map_err(|e| Error::Source(format!("invalid block {}: {}", hash, e)))
+    }
+
+    fn get_fee(&self, tx: &Transaction, _block_hash: &BlockHash) -> Result<Option<Amount>, Error> {
+        Ok(self.get_tx(tx)?.fee.map(Amount::from_sat))
+    }
+
+    fn get_prevouts(
+        &self,
+        tx: &Transaction,
+        _block_hash: &BlockHash,
+    ) -> Result<Option<Vec<TxOut>>, Error> {
+        Ok(self
+            .get_tx(tx)?
+            .vin
+            .into_iter()
+            .map(|input| {
+                input.prevout.map(|prevout| TxOut {
+                    value: Amount::from_sat(prevout.value),
+                    script_pubkey: prevout.scriptpubkey,
+                })
+            })
+            .collect())
+    }
+
+    // Would need the fees of all transactions of the block, which takes a
+    // request per 25 transactions.
+    fn get_median_feerate(
+        &self,
+        _block: &Block,
+        _block_hash: &BlockHash,
+    ) -> Result<Option<f64>, Error> {
+        Ok(None)
+    }
+
+    fn get_transaction(
+        &self,
+        txid: &Txid,
+    ) -> Result<Option<(Transaction, Option<BlockHash>)>, Error> {
+        let hex = self.get_text(&format!("/tx/{}/hex", txid))?;
+        let tx = Vec::<u8>::from_hex(&hex)
+            .map_err(|e| e.to_string())
+            .and_then(|bytes| deserialize(&bytes).map_err(|e| e.to_string()))
+            .map_err(|e| Error::Source(format!("invalid transaction {}: {}", txid, e)))?;
+        let response = self.get(&format!("/tx/{}/status", txid))?;
+        let status: EsploraTxStatus = serde_json::from_reader(response.into_reader())
+            .map_err(|e| Error::Source(format!("invalid status of {}: {}", txid, e)))?;
+        Ok(Some((tx, status.block_hash)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoincore_rpc::bitcoin::absolute::LockTime;
+    use bitcoincore_rpc::bitcoin::block::{Header, Version};
+    use bitcoincore_rpc::bitcoin::consensus::encode::serialize_hex;
+    use bitcoincore_rpc::bitcoin::consensus::serialize;
+    use bitcoincore_rpc::bitcoin::hashes::Hash;
+    use bitcoincore_rpc::bitcoin::{
+        transaction, CompactTarget, OutPoint, Sequence, TxIn, TxMerkleNode, Txid, Witness,
+    };
+    use std::collections::HashMap;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    // The responses (status and body) of the mock server by path. The last
+    // response for a path is repeated.
+    type Responses = HashMap<String, Vec<(u16, Vec<u8>)>>;
+
+    // Serves the responses over HTTP on a local port and returns the base URL
+    // and the requested paths.
+    fn mock_server(responses: Responses) -> (String, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("can bind");
+        let base_url = format!("http://{}", listener.local_addr().expect("has an address"));
+        let requests = Arc::new(Mutex::new(vec![]));
+        let requested = requests.clone();
+        thread::spawn(move || {
+            let mut responses = responses;
+            for stream in listener.incoming() {
+                let mut stream = stream.expect("can accept");
+                let mut reader = BufReader::new(stream.try_clone().expect("can clone"));
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).expect("can read");
+                loop {
+                    let mut header = String::new();
+                    reader.read_line(&mut header).expect("can read");
+                    if header == "\r\n" || header.is_empty() {
+                        break;
+                    }
+                }
+                let path = request_line
+                    .split(' ')
+                    .nth(1)
+                    .expect("has a path")
+                    .to_string();
+                requested.lock().expect("not poisoned").push(path.clone());
+                let (status, body) = match responses.get_mut(&path) {
+                    Some(queue) if queue.len() > 1 => queue.remove(0),
+                    Some(queue) => queue[0].clone(),
+                    None => (404, b"Not Found".to_vec()),
+                };
+                let _ = write!(
+                    stream,
+                    "HTTP/1.1 {} Status\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    status,
+                    body.len()
+                );
+                let _ = stream.write_all(&body);
+            }
+        });
+        (base_url, requests)
+    }
+
+    fn retry(max_retries: u32) -> RetryPolicy {
+        RetryPolicy {
+            max_retries,
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(1),
+        }
+    }
+
+    fn transaction() -> Transaction {
+        Transaction {
+            version: transaction::Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::new(Txid::all_zeros(), 1),
+                script_sig: ScriptBuf::new(),
+                sequence: Sequence::MAX,
+                witness: Witness::new(),
+            }],
+            output: vec![TxOut {
+                value: Amount::from_sat(9000),
+                script_pubkey: ScriptBuf::new_op_return([1, 2, 3]),
+            }],
+        }
+    }
+
+    fn ok(body: &str) -> Vec<(u16, Vec<u8>)> {
+        vec![(200, body.as_bytes().to_vec())]
+    }
+
+    #[test]
+    fn reads_blocks() {
+        let block = Block {
+            header: Header {
+                version: Version::ONE,
+                prev_blockhash: BlockHash::all_zeros(),
+                merkle_root: TxMerkleNode::all_zeros(),
+                time: 1231006505,
+                bits: CompactTarget::from_consensus(0x1d00ffff),
+                nonce: 2083236893,
+            },
+            txdata: vec![transaction()],
+        };
+        let hash = block.block_hash();
+        let (base_url, _) = mock_server(HashMap::from([
+            ("/blocks/tip/height".to_string(), ok("840000\n")),
+            ("/block-height/839999".to_string(), ok(&hash.to_string())),
+            (
+                format!("/block/{}/raw", hash),
+                vec![(200, serialize(&block))],
+            ),
+        ]));
+        // A trailing slash is ignored.
+        let source = EsploraSource::new(&format!("{}/", base_url), retry(0));
+        assert_eq!(source.get_block_count().expect("has a tip"), 840000);
+        assert_eq!(source.get_block_hash(839999).expect("has a hash"), hash);
+        assert_eq!(source.get_block(&hash).expect("has the block"), block);
+    }
+
+    #[test]
+    fn reads_fees_and_prevouts() {
+        let tx = transaction();
+        let response = r#"{
+            "txid": "ignored",
+            "fee": 1000,
+            "vin": [{"prevout": {"scriptpubkey": "0014751e76e8199196d454941c45d1b3a323f1433bd6", "value": 10000}}]
+        }"#;
+        let (base_url, requests) = mock_server(HashMap::from([(
+            format!("/tx/{}", tx.txid()),
+            ok(response),
+        )]));
+        let source = EsploraSource::new(&base_url, retry(0));
+        let block_hash = BlockHash::all_zeros();
+        assert_eq!(
+            source.get_fee(&tx, &block_hash).expect("has a fee"),
+            Some(Amount::from_sat(1000))
+        );
+        let prevouts = source
+            .get_prevouts(&tx, &block_hash)
+            .expect("has prevouts")
+            .expect("knows the prevouts");
+        assert_eq!(prevouts.len(), 1);
+        assert_eq!(prevouts[0].value, Amount::from_sat(10000));
+        assert!(prevouts[0].script_pubkey.is_p2wpkh());
+        assert_eq!(requests.lock().expect("not poisoned").len(), 2);
+    }
+
+    #[test]
+    fn coinbase_has_no_prevouts() {
+        let tx = transaction();
+        let response = r#"{"fee": null, "vin": [{"prevout": null}]}"#;
+        let (base_url, _) = mock_server(HashMap::from([(
+            format!("/tx/{}", tx.txid()),
+            ok(response),
+        )]));
+        let source = EsploraSource::new(&base_url, retry(0));
+        let block_hash = BlockHash::all_zeros();
+        assert_eq!(source.get_fee(&tx, &block_hash).expect("no error"), None);
+        assert_eq!(
+            source.get_prevouts(&tx, &block_hash).expect("no error"),
+            None
+        );
+    }
+
+    #[test]
+    fn looks_up_transactions() {
+        let tx = transaction();
+        let block_hash = BlockHash::from_byte_array([1; 32]);
+        let (base_url, _) = mock_server(HashMap::from([
+            (format!("/tx/{}/hex", tx.txid()), ok(&serialize_hex(&tx))),
+            (
+                format!("/tx/{}/status", tx.txid()),
+                ok(&format!(
+                    r#"{{"confirmed": true, "block_hash": "{}"}}"#,
+                    block_hash
+                )),
+            ),
+        ]));
+        let source = EsploraSource::new(&base_url, retry(0));
+        assert_eq!(
+            source
+                .get_transaction(&tx.txid())
+                .expect("has the transaction"),
+            Some((tx, Some(block_hash)))
+        );
+    }
+
+    #[test]
+    fn not_found_is_not_retried() {
+        let (base_url, requests) = mock_server(HashMap::new());
+        let source = EsploraSource::new(&base_url, retry(3));
+        match source.get_block_hash(1) {
+            Err(Error::Source(message)) => assert!(message.contains("404"), "{}", message),
+            result => panic!("expected a source error, got {:?}", result),
+        }
+        assert_eq!(requests.lock().expect("not poisoned").len(), 1);
+    }
+
+    #[test]
+    fn server_errors_are_retried() {
+        let responses = vec![
+            (503, b"Service Unavailable".to_vec()),
+            (500, b"Internal Server Error".to_vec()),
+            (200, b"840000".to_vec()),
+        ];
+        let (base_url, requests) = mock_server(HashMap::from([(
+            "/blocks/tip/height".to_string(),
+            responses,
+        )]));
+        let source = EsploraSource::new(&base_url, retry(2));
+        assert_eq!(source.get_block_count().expect("retried"), 840000);
+        assert_eq!(requests.lock().expect("not poisoned").len(), 3);
+
+        let (base_url, _) = mock_server(HashMap::from([(
+            "/blocks/tip/height".to_string(),
+            vec![(502, b"Bad Gateway".to_vec())],
+        )]));
+        let source = EsploraSource::new(&base_url, retry(1));
+        assert!(matches!(source.get_block_count(), Err(Error::Transport(_))));
+    }
+}
@@ -0,0 +1,640 @@
+//! A minimal, read-only LevelDB reader. It only supports what's needed to
+//! load Bitcoin Core's block index: reading all entries with a given key
+//! prefix from the live table files and the write-ahead log of a database
+//! that isn't opened by another process. It's tested against a block index
+//! written by LevelDB itself (see testdata/blocks).
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+const TABLE_MAGIC: u64 = 0xdb4775248b80fb57;
+const FOOTER_SIZE: usize = 48;
+const BLOCK_TRAILER_SIZE: usize = 5;
+const NO_COMPRESSION: u8 = 0;
+const SNAPPY_COMPRESSION: u8 = 1;
+
+const LOG_BLOCK_SIZE: usize = 32 * 1024;
+const LOG_HEADER_SIZE: usize = 7;
+const LOG_FULL: u8 = 1;
+const LOG_FIRST: u8 = 2;
+const LOG_MIDDLE: u8 = 3;
+const LOG_LAST: u8 = 4;
+
+const TYPE_DELETION: u8 = 0;
+const TYPE_VALUE: u8 = 1;
+
+// The tags of the fields of a VersionEdit in the MANIFEST.
+const TAG_COMPARATOR: u64 = 1;
+const TAG_LOG_NUMBER: u64 = 2;
+const TAG_NEXT_FILE_NUMBER: u64 = 3;
+const TAG_LAST_SEQUENCE: u64 = 4;
+const TAG_COMPACT_POINTER: u64 = 5;
+const TAG_DELETED_FILE: u64 = 6;
+const TAG_NEW_FILE: u64 = 7;
+const TAG_PREV_LOG_NUMBER: u64 = 9;
+
+fn corrupt(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+// An entry of the database: the sequence number it was written with and the
+// value (None for deletions).
+type Entry = (u64, Option<Vec<u8>>);
+
+/// Reads the latest values of all keys starting with `prefix`.
+pub fn read_prefix(dir: &Path, prefix: &[u8]) -> io::Result<HashMap<Vec<u8>, Vec<u8>>> {
+    let mut entries: HashMap<Vec<u8>, Entry> = HashMap::new();
+    let mut insert = |key: &[u8], sequence: u64, value: Option<Vec<u8>>| {
+        if !key.starts_with(prefix) {
+            return;
+        }
+        match entries.get(key) {
+            Some((existing, _)) if *existing > sequence => (),
+            _ => {
+                entries.insert(key.to_vec(), (sequence, value));
+            }
+        }
+    };
+
+    // Compactions leave obsolete table files and logs behind until they are
+    // removed. Their entries might have been deleted since, so only the files
+    // of the current version are read.
+    let version = read_manifest(dir)?;
+    for number in version.tables.iter() {
+        let ldb = dir.join(format!("{:06}.ldb", number));
+        let path = match ldb.exists() {
+            true => ldb,
+            false => dir.join(format!("{:06}.sst", number)),
+        };
+        read_table(&fs::read(&path)?, &mut insert)?;
+    }
+    for dir_entry in fs::read_dir(dir)? {
+        let path = dir_entry?.path();
+        if path.extension().and_then(|e| e.to_str()) != Some("log") {
+            continue;
+        }
+        let number = path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .and_then(|stem| stem.parse::<u64>().ok());
+        if let Some(number) = number {
+            if number >= version.log_number || number == version.prev_log_number {
+                read_log(&fs::read(&path)?, &mut insert)?;
+            }
+        }
+    }
+
+    Ok(entries
+        .into_iter()
+        .filter_map(|(key, (_, value))| value.map(|value| (key, value)))
+        .collect())
+}
+
+// The files of the current version of the database.
+#[derive(Debug, Default)]
+struct Version {
+    tables: Vec<u64>,
+    // Logs older than this one were compacted into the tables.
+    log_number: u64,
+    prev_log_number: u64,
+}
+
+// Replays the version edits in the MANIFEST named in the CURRENT file.
+fn read_manifest(dir: &Path) -> io::Result<Version> {
+    let current = fs::read_to_string(dir.join("CURRENT"))?;
+    let manifest = current.trim_end_matches('\n');
+    if manifest.is_empty() || manifest.contains('/') {
+        return Err(corrupt("bad CURRENT file"));
+    }
+    let mut version = Version::default();
+    let mut tables: HashSet<(u64, u64)> = HashSet::new();
+    for record in log_records(&fs::read(dir.join(manifest))?) {
+        let mut reader = Reader::new(&record);
+        while !reader.is_empty() {
+            match reader.varint()? {
+                TAG_COMPARATOR => {
+                    let len = reader.varint()? as usize;
+                    reader.bytes(len)?;
+                }
+                TAG_LOG_NUMBER => version.log_number = reader.varint()?,
+                TAG_PREV_LOG_NUMBER => version.prev_log_number = reader.varint()?,
+                TAG_NEXT_FILE_NUMBER | TAG_LAST_SEQUENCE => {
+                    reader.varint()?;
+                }
+                TAG_COMPACT_POINTER => {
+                    let _level = reader.varint()?;
+                    let len = reader.varint()? as usize;
+                    reader.bytes(len)?;
+                }
+                TAG_DELETED_FILE => {
+                    let level = reader.varint()?;
+                    tables.remove(&(level, reader.varint()?));
+                }
+                TAG_NEW_FILE => {
+                    let level = reader.varint()?;
+                    let number = reader.varint()?;
+                    let _file_size = reader.varint()?;
+                    for _key in ["smallest", "largest"] {
+                        let len = reader.varint()? as usize;
+                        reader.bytes(len)?;
+                    }
+                    tables.insert((level, number));
+                }
+                _ => return Err(corrupt("unknown tag in version edit")),
+            }
+        }
+    }
+    version.tables = tables.into_iter().map(|(_, number)| number).collect();
+    version.tables.sort();
+    Ok(version)
+}
+
+fn read_table<F>(data: &[u8], insert: &mut F) -> io::Result<()>
+where
+    F: FnMut(&[u8], u64, Option<Vec<u8>>),
+{
+    if data.len() < FOOTER_SIZE {
+        return Err(corrupt("table file too short"));
+    }
+    let footer = &data[data.len() - FOOTER_SIZE..];
+    let magic = u64::from_le_bytes(footer[40..].try_into().expect("8 bytes"));
+    if magic != TABLE_MAGIC {
+        return Err(corrupt("bad table magic"));
+    }
+    let mut reader = Reader::new(footer);
+    let _metaindex = (reader.varint()?, reader.varint()?);
+    let index_handle = (reader.varint()?, reader.varint()?);
+
+    let index = read_block(data, index_handle)?;
+    for (_, handle) in block_entries(&index)? {
+        let mut reader = Reader::new(&handle);
+        let handle = (reader.varint()?, reader.varint()?);
+        let block = read_block(data, handle)?;
+        for (internal_key, value) in block_entries(&block)? {
+            let (key, sequence, value_type) = parse_internal_key(&internal_key)?;
+            match value_type {
+                TYPE_VALUE => insert(key, sequence, Some(value)),
+                TYPE_DELETION => insert(key, sequence, None),
+                _ => return Err(corrupt("unknown value type")),
+            }
+        }
+    }
+    Ok(())
+}
+
+fn read_block(data: &[u8], (offset, size): (u64, u64)) -> io::Result<Vec<u8>> {
+    let start = offset as usize;
+    let end = start + size as usize;
+    if end + BLOCK_TRAILER_SIZE > data.len() {
+        return Err(corrupt("block handle out of range"));
+    }
+    let contents = &data[start..end];
+    match data[end] {
+        NO_COMPRESSION => Ok(contents.to_vec()),
+        SNAPPY_COMPRESSION => snap::raw::Decoder::new()
+            .decompress_vec(contents)
+            .map_err(|e| corrupt(&e.to_string())),
+        _ => Err(corrupt("unknown block compression")),
+    }
+}
+
+// Decodes the prefix-compressed entries of a table block.
+fn block_entries(block: &[u8]) -> io::Result<Vec<(Vec<u8>, Vec<u8>)>> {
+    if block.len() < 4 {
+        return Err(corrupt("block too short"));
+    }
+    let num_restarts =
+        u32::from_le_bytes(block[block.len() - 4..].try_into().expect("4 bytes")) as usize;
+    let restarts_offset = block
+        .len()
+        .checked_sub(4 + num_restarts * 4)
+        .ok_or_else(|| corrupt("bad restart count"))?;
+
+    let mut entries = vec![];
+    let mut key: Vec<u8> = vec![];
+    let mut reader = Reader::new(&block[..restarts_offset]);
+    while !reader.is_empty() {
+        let shared = reader.varint()? as usize;
+        let non_shared = reader.varint()? as usize;
+        let value_len = reader.varint()? as usize;
+        if shared > key.len() {
+            return Err(corrupt("bad shared key length"));
+        }
+        key.truncate(shared);
+        key.extend_from_slice(reader.bytes(non_shared)?);
+        let value = reader.bytes(value_len)?.to_vec();
+        entries.push((key.clone(), value));
+    }
+    Ok(entries)
+}
+
+fn parse_internal_key(internal_key: &[u8]) -> io::Result<(&[u8], u64, u8)> {
+    if internal_key.len() < 8 {
+        return Err(corrupt("internal key too short"));
+    }
+    let (key, tag) = internal_key.split_at(internal_key.len() - 8);
+    let tag = u64::from_le_bytes(tag.try_into().expect("8 bytes"));
+    Ok((key, tag >> 8, (tag & 0xff) as u8))
+}
+
+fn read_log<F>(data: &[u8], insert: &mut F) -> io::Result<()>
+where
+    F: FnMut(&[u8], u64, Option<Vec<u8>>),
+{
+    for record in log_records(data) {
+        read_write_batch(&record, insert)?;
+    }
+    Ok(())
+}
+
+// The records of a log file (the write-ahead log or the MANIFEST), which
+// are split into fragments at the block boundaries.
+fn log_records(data: &[u8]) -> Vec<Vec<u8>> {
+    let mut records = vec![];
+    let mut record: Vec<u8> = vec![];
+    let mut offset = 0;
+    while offset + LOG_HEADER_SIZE <= data.len() {
+        let remaining_in_block = LOG_BLOCK_SIZE - offset % LOG_BLOCK_SIZE;
+        if remaining_in_block < LOG_HEADER_SIZE {
+            // The rest of the block is padding.
+            offset += remaining_in_block;
+            continue;
+        }
+        let length = u16::from_le_bytes([data[offset + 4], data[offset + 5]]) as usize;
+        let record_type = data[offset + 6];
+        let start = offset + LOG_HEADER_SIZE;
+        if start + length > data.len() {
+            // A partially written record at the end of the log.
+            break;
+        }
+        let fragment = &data[start..start + length];
+        offset = start + length;
+
+        match record_type {
+            LOG_FULL => records.push(fragment.to_vec()),
+            LOG_FIRST => record = fragment.to_vec(),
+            LOG_MIDDLE => record.extend_from_slice(fragment),
+            LOG_LAST => {
+                record.extend_from_slice(fragment);
+                records.push(std::mem::take(&mut record));
+            }
+            // Zero-length records are used for preallocated space.
+            _ => (),
+        }
+    }
+    records
+}
+
+fn read_write_batch<F>(batch: &[u8], insert: &mut F) -> io::Result<()>
+where
+    F: FnMut(&[u8], u64, Option<Vec<u8>>),
+{
+    let mut reader = Reader::new(batch);
+    let sequence = u64::from_le_bytes(reader.bytes(8)?.try_into().expect("8 bytes"));
+    let count = u32::from_le_bytes(reader.bytes(4)?.try_into().expect("4 bytes"));
+    for i in 0..count as u64 {
+        let value_type = reader.bytes(1)?[0];
+        let key_len = reader.varint()? as usize;
+        let key = reader.bytes(key_len)?;
+        match value_type {
+            TYPE_VALUE => {
+                let value_len = reader.varint()? as usize;
+                let value = reader.bytes(value_len)?.to_vec();
+                insert(key, sequence + i, Some(value));
+            }
+            TYPE_DELETION => insert(key, sequence + i, None),
+            _ => return Err(corrupt("unknown value type in write batch")),
+        }
+    }
+    Ok(())
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Reader<'a> {
+        Reader { data }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    fn bytes(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        if len > self.data.len() {
+            return Err(corrupt("unexpected end of data"));
+        }
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    // LevelDB's little-endian base 128 varint.
+    fn varint(&mut self) -> io::Result<u64> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.bytes(1)?[0];
+            value |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(corrupt("varint too long"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn varint(mut n: u64) -> Vec<u8> {
+        let mut bytes = vec![];
+        while n >= 0x80 {
+            bytes.push((n & 0x7f) as u8 | 0x80);
+            n >>= 7;
+        }
+        bytes.push(n as u8);
+        bytes
+    }
+
+    fn slice(data: &[u8]) -> Vec<u8> {
+        let mut bytes = varint(data.len() as u64);
+        bytes.extend(data);
+        bytes
+    }
+
+    // A log file of the given records. The checksums aren't verified.
+    fn log_file(records: &[Vec<u8>]) -> Vec<u8> {
+        let mut data = vec![];
+        for record in records {
+            let mut fragments = record.chunks(LOG_BLOCK_SIZE / 2).peekable();
+            let mut first = true;
+            while let Some(fragment) = fragments.next() {
+                let remaining_in_block = LOG_BLOCK_SIZE - data.len() % LOG_BLOCK_SIZE;
+                if remaining_in_block < LOG_HEADER_SIZE {
+                    data.extend(vec![0; remaining_in_block]);
+                }
+                let record_type = match (first, fragments.peek().is_none()) {
+                    (true, true) => LOG_FULL,
+                    (true, false) => LOG_FIRST,
+                    (false, false) => LOG_MIDDLE,
+                    (false, true) => LOG_LAST,
+                };
+                first = false;
+                data.extend([0; 4]);
+                data.extend((fragment.len() as u16).to_le_bytes());
+                data.push(record_type);
+                data.extend(fragment);
+            }
+        }
+        data
+    }
+
+    fn write_batch(sequence: u64, entries: &[(&[u8], Option<&[u8]>)]) -> Vec<u8> {
+        let mut batch = sequence.to_le_bytes().to_vec();
+        batch.extend((entries.len() as u32).to_le_bytes());
+        for (key, value) in entries {
+            match value {
+                Some(value) => {
+                    batch.push(TYPE_VALUE);
+                    batch.extend(slice(key));
+                    batch.extend(slice(value));
+                }
+                None => {
+                    batch.push(TYPE_DELETION);
+                    batch.extend(slice(key));
+                }
+            }
+        }
+        batch
+    }
+
+    fn internal_key(key: &[u8], sequence: u64, value_type: u8) -> Vec<u8> {
+        let mut internal_key = key.to_vec();
+        internal_key.extend((sequence << 8 | value_type as u64).to_le_bytes());
+        internal_key
+    }
+
+    // A block with a single restart point, with each key sharing its prefix
+    // with the key before it.
+    fn block(entries: &[(Vec<u8>, Vec<u8>)]) -> Vec<u8> {
+        let mut block = vec![];
+        let mut last_key: &[u8] = &[];
+        for (key, value) in entries {
+            let shared = key.iter().zip(last_key).take_while(|(a, b)| a == b).count();
+            block.extend(varint(shared as u64));
+            block.extend(varint((key.len() - shared) as u64));
+            block.extend(varint(value.len() as u64));
+            block.extend(&key[shared..]);
+            block.extend(value);
+            last_key = key;
+        }
+        block.extend(0u32.to_le_bytes());
+        block.extend(1u32.to_le_bytes());
+        block
+    }
+
+    // Appends the block with its trailer and returns its handle.
+    fn append_block(data: &mut Vec<u8>, block: Vec<u8>, compress: bool) -> Vec<u8> {
+        let (contents, compression) = match compress {
+            true => (
+                snap::raw::Encoder::new()
+                    .compress_vec(&block)
+                    .expect("can compress"),
+                SNAPPY_COMPRESSION,
+            ),
+            false => (block, NO_COMPRESSION),
+        };
+        let handle = [varint(data.len() as u64), varint(contents.len() as u64)].concat();
+        data.extend(contents);
+        data.push(compression);
+        data.extend([0; 4]);
+        handle
+    }
+
+    // A table with a single data block, snappy compressed if `compress`.
+    fn table(entries: &[(Vec<u8>, Vec<u8>)], compress: bool) -> Vec<u8> {
+        let mut data = vec![];
+        let data_handle = append_block(&mut data, block(entries), compress);
+        let last_key = entries.last().expect("entries").0.clone();
+        let index_handle = append_block(&mut data, block(&[(last_key, data_handle)]), false);
+        let metaindex_handle = append_block(&mut data, block(&[]), false);
+        let mut footer = [metaindex_handle, index_handle].concat();
+        footer.resize(FOOTER_SIZE - 8, 0);
+        footer.extend(TABLE_MAGIC.to_le_bytes());
+        data.extend(footer);
+        data
+    }
+
+    fn new_file(level: u64, number: u64) -> Vec<u8> {
+        let mut edit = varint(TAG_NEW_FILE);
+        edit.extend(varint(level));
+        edit.extend(varint(number));
+        edit.extend(varint(1000));
+        edit.extend(slice(&internal_key(b"a", 1, TYPE_VALUE)));
+        edit.extend(slice(&internal_key(b"z", 1, TYPE_VALUE)));
+        edit
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "non-standard-leveldb-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).expect("can create the directory");
+        dir
+    }
+
+    #[test]
+    fn reads_varints() {
+        for n in [0, 1, 127, 128, 300, u32::MAX as u64, u64::MAX] {
+            assert_eq!(Reader::new(&varint(n)).varint().expect("valid varint"), n);
+        }
+        assert!(Reader::new(&[0x80]).varint().is_err());
+        assert!(Reader::new(&[0xff; 11]).varint().is_err());
+    }
+
+    #[test]
+    fn reads_records_split_across_log_blocks() {
+        let large = vec![7u8; LOG_BLOCK_SIZE * 2];
+        let records = vec![b"first".to_vec(), large, b"last".to_vec()];
+        assert_eq!(log_records(&log_file(&records)), records);
+
+        // A partially written record at the end is ignored.
+        let mut data = log_file(&records[..1]);
+        data.extend(log_file(&[b"torn".to_vec()]));
+        data.truncate(data.len() - 2);
+        assert_eq!(log_records(&data), records[..1].to_vec());
+    }
+
+    #[test]
+    fn reads_write_batches() {
+        let batch = write_batch(
+            10,
+            &[(b"ba", Some(b"1")), (b"bb", None), (b"c", Some(b"3"))],
+        );
+        let mut entries = vec![];
+        read_log(&log_file(&[batch]), &mut |key: &[u8], sequence, value| {
+            entries.push((key.to_vec(), sequence, value))
+        })
+        .expect("valid log");
+        assert_eq!(
+            entries,
+            vec![
+                (b"ba".to_vec(), 10, Some(b"1".to_vec())),
+                (b"bb".to_vec(), 11, None),
+                (b"c".to_vec(), 12, Some(b"3".to_vec())),
+            ]
+        );
+    }
+
+    #[test]
+    fn reads_tables() {
+        let entries = vec![
+            (internal_key(b"baa", 5, TYPE_VALUE), b"1".to_vec()),
+            (internal_key(b"bab", 6, TYPE_DELETION), vec![]),
+            (internal_key(b"bb", 7, TYPE_VALUE), b"3".to_vec()),
+        ];
+        for compress in [false, true] {
+            let mut read = vec![];
+            read_table(
+                &table(&entries, compress),
+                &mut |key: &[u8], sequence, value| read.push((key.to_vec(), sequence, value)),
+            )
+            .expect("valid table");
+            assert_eq!(
+                read,
+                vec![
+                    (b"baa".to_vec(), 5, Some(b"1".to_vec())),
+                    (b"bab".to_vec(), 6, None),
+                    (b"bb".to_vec(), 7, Some(b"3".to_vec())),
+                ]
+            );
+        }
+        let mut corrupt = table(&entries, false);
+        let len = corrupt.len();
+        corrupt[len - 1] ^= 1;
+        assert!(read_table(&corrupt, &mut |_: &[u8], _, _| ()).is_err());
+    }
+
+    #[test]
+    fn only_reads_live_files() {
+        let dir = temp_dir("live");
+        // An obsolete table and log left behind by a compaction into table 7.
+        // Table 7 doesn't contain "bc" anymore, as it was deleted.
+        let obsolete = vec![
+            (internal_key(b"ba", 1, TYPE_VALUE), b"old".to_vec()),
+            (internal_key(b"bc", 2, TYPE_VALUE), b"deleted".to_vec()),
+        ];
+        fs::write(dir.join("000005.ldb"), table(&obsolete, false)).expect("can write");
+        let obsolete_log = write_batch(3, &[(b"bd", Some(b"deleted"))]);
+        fs::write(dir.join("000004.log"), log_file(&[obsolete_log])).expect("can write");
+        let live = vec![
+            (internal_key(b"a", 4, TYPE_VALUE), b"other".to_vec()),
+            (internal_key(b"ba", 4, TYPE_VALUE), b"new".to_vec()),
+        ];
+        fs::write(dir.join("000007.sst"), table(&live, true)).expect("can write");
+        let log = write_batch(8, &[(b"bb", Some(b"logged")), (b"ba", None)]);
+        let log = [log, write_batch(10, &[(b"ba", Some(b"newest"))])];
+        fs::write(dir.join("000009.log"), log_file(&log)).expect("can write");
+
+        let mut edit = slice(b"leveldb.BytewiseComparator");
+        edit.insert(0, TAG_COMPARATOR as u8);
+        edit.extend(new_file(0, 5));
+        let mut compaction = varint(TAG_DELETED_FILE);
+        compaction.extend([varint(0), varint(5)].concat());
+        compaction.extend(new_file(1, 7));
+        compaction.extend([varint(TAG_LOG_NUMBER), varint(9)].concat());
+        compaction.extend([varint(TAG_NEXT_FILE_NUMBER), varint(10)].concat());
+        compaction.extend([varint(TAG_LAST_SEQUENCE), varint(11)].concat());
+        fs::write(dir.join("MANIFEST-000006"), log_file(&[edit, compaction])).expect("can write");
+        fs::write(dir.join("CURRENT"), "MANIFEST-000006\n").expect("can write");
+
+        let result = read_prefix(&dir, b"b");
+        fs::remove_dir_all(&dir).expect("can remove the directory");
+        let entries = result.expect("valid database");
+        assert_eq!(
+            entries,
+            HashMap::from([
+                (b"ba".to_vec(), b"newest".to_vec()),
+                (b"bb".to_vec(), b"logged".to_vec()),
+            ])
+        );
+    }
+
+    #[test]
+    fn needs_current() {
+        let dir = temp_dir("current");
+        let result = read_prefix(&dir, b"b");
+        fs::remove_dir_all(&dir).expect("can remove the directory");
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn reads_a_block_index_written_by_leveldb() {
+        // See testdata/blocks/README.md
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata/blocks/index");
+        // The last write batch is split across log blocks.
+        let log = fs::read(dir.join("000013.log")).expect("can read");
+        assert!(log.len() > LOG_BLOCK_SIZE);
+        assert_eq!(log_records(&log).len(), 1);
+
+        // 651 blocks of the main chain and 300 of a fork. A header without a
+        // parent was deleted.
+        let blocks = read_prefix(&dir, b"b").expect("valid database");
+        assert_eq!(blocks.len(), 951);
+        assert!(blocks.keys().all(|key| key.len() == 33));
+        // The block file info in the compacted table was updated with 651
+        // blocks (a VARINT of Bitcoin Core) in the log.
+        let files = read_prefix(&dir, b"f").expect("valid database");
+        assert_eq!(files.len(), 1);
+        assert_eq!(files.values().next().expect("one entry")[..2], [0x84, 0x0b]);
+    }
+}
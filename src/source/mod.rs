@@ -0,0 +1,112 @@
+use crate::error::Error;
+use crate::retry::RetryPolicy;
+use bitcoincore_rpc::bitcoin::{Amount, Block, BlockHash, Network, Transaction, TxOut, Txid};
+use config::Config;
+use std::str::FromStr;
+
+mod blocks;
+mod esplora;
+mod leveldb;
+mod p2p;
+mod rpc;
+
+pub use blocks::BlocksDirSource;
+pub use esplora::EsploraSource;
+pub use p2p::P2pSource;
+pub use rpc::RpcSource;
+
+/// Provides the blocks (and information about their transactions) that we
+/// test against the test node.
+pub trait BlockSource: Send + Sync {
+    /// The height of the most-work chain known to the source.
+    fn get_block_count(&self) -> Result<u64, Error>;
+    fn get_block_hash(&self, height: u64) -> Result<BlockHash, Error>;
+    fn get_block(&self, hash: &BlockHash) -> Result<Block, Error>;
+    /// The fee paid by a transaction in the block. None if the source can't
+    /// tell.
+    fn get_fee(&self, tx: &Transaction, block_hash: &BlockHash) -> Result<Option<Amount>, Error>;
+    /// The outputs spent by the inputs of a transaction in the block, in the
+    /// order of the inputs. None if the source can't tell.
+    fn get_prevouts(
+        &self,
+        tx: &Transaction,
+        block_hash: &BlockHash,
+    ) -> Result<Option<Vec<TxOut>>, Error>;
+    /// The median feerate of the transactions in the block in sat/vB,
+    /// weighted by their weight like Bitcoin Core's getblockstats. None if
+    /// the source can't tell.
+    fn get_median_feerate(
+        &self,
+        block: &Block,
+        block_hash: &BlockHash,
+    ) -> Result<Option<f64>, Error>;
+    /// Looks up a transaction by its txid and returns it together with the
+    /// hash of the block it's confirmed in, if any. None if the source can't
+    /// look up transactions.
+    fn get_transaction(
+        &self,
+        txid: &Txid,
+    ) -> Result<Option<(Transaction, Option<BlockHash>)>, Error>;
+}
+
+/// Builds the block source selected with `data_source` in the configuration.
+pub fn from_config(settings: &Config, retry: &RetryPolicy) -> Result<Box<dyn BlockSource>, Error> {
+    let data_source = settings
+        .get::<String>("data_source")
+        .map_err(|e| Error::config("invalid data_source".to_string(), e))?;
+    match data_source.as_str() {
+        "node" => Ok(Box::new(RpcSource::new(
+            crate::rpc_client(settings, "data")?,
+            retry.clone(),
+        ))),
+        "blocks" => {
+            let blocks_dir = settings.get::<String>("blocks_dir").map_err(|e| {
+                Error::config(
+                    "need a blocks_dir for the 'blocks' data source".to_string(),
+                    e,
+                )
+            })?;
+            Ok(Box::new(BlocksDirSource::open(blocks_dir.as_ref())?))
+        }
+        "esplora" => {
+            let esplora_url = settings.get::<String>("esplora_url").map_err(|e| {
+                Error::config(
+                    "need an esplora_url for the 'esplora' data source".to_string(),
+                    e,
+                )
+            })?;
+            Ok(Box::new(EsploraSource::new(&esplora_url, retry.clone())))
+        }
+        "p2p" => {
+            let peer = settings.get::<String>("p2p_peer").map_err(|e| {
+                Error::config("need a p2p_peer for the 'p2p' data source".to_string(), e)
+            })?;
+            let network = settings
+                .get::<String>("p2p_network")
+                .map_err(|e| Error::config("invalid p2p_network".to_string(), e))?;
+            let network = Network::from_str(&network)
+                .map_err(|e| Error::Config(format!("invalid p2p_network '{}': {}", network, e)))?;
+            Ok(Box::new(P2pSource::new(&peer, network, retry.clone())))
+        }
+        other => Err(Error::Config(format!(
+            "unknown data_source '{}': expected 'node', 'blocks', 'esplora' or 'p2p'",
+            other
+        ))),
+    }
+}
+
+// The feerate at which half of the weight of the transactions pays at most
+// this feerate. See CalculatePercentilesByWeight() in Bitcoin Core's
+// rpc/blockchain.cpp. The feerates are in sat/vB and paired with the weight.
+fn median_feerate(mut feerates: Vec<(f64, u64)>) -> Option<f64> {
+    feerates.sort_by(|a, b| a.0.total_cmp(&b.0));
+    let total_weight: u64 = feerates.iter().map(|(_, weight)| weight).sum();
+    let mut cumulative_weight = 0;
+    for (feerate, weight) in feerates {
+        cumulative_weight += weight;
+        if cumulative_weight * 2 >= total_weight {
+            return Some(feerate);
+        }
+    }
+    None
+}
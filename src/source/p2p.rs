@@ -0,0 +1,461 @@
+use super::BlockSource;
+use crate::error::Error;
+use crate::retry::RetryPolicy;
+use bitcoincore_rpc::bitcoin::block::Header;
+use bitcoincore_rpc::bitcoin::consensus::{encode, Decodable, Encodable};
+use bitcoincore_rpc::bitcoin::constants::genesis_block;
+use bitcoincore_rpc::bitcoin::hashes::Hash;
+use bitcoincore_rpc::bitcoin::p2p::address::Address;
+use bitcoincore_rpc::bitcoin::p2p::message::{NetworkMessage, RawNetworkMessage};
+use bitcoincore_rpc::bitcoin::p2p::message_blockdata::{GetHeadersMessage, Inventory};
+use bitcoincore_rpc::bitcoin::p2p::message_network::VersionMessage;
+use bitcoincore_rpc::bitcoin::p2p::{Magic, ServiceFlags, PROTOCOL_VERSION};
+use bitcoincore_rpc::bitcoin::{Amount, Block, BlockHash, Network, Transaction, TxOut, Txid};
+use log::{debug, info};
+use std::collections::HashMap;
+use std::io::{BufReader, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const P2P_TIMEOUT: Duration = Duration::from_secs(60 * 5); // 5 minutes
+const USER_AGENT: &str = "/non-standard:0.1.0/";
+// A headers message contains at most 2000 headers. If we receive fewer, the
+// peer has no more headers for us.
+const MAX_HEADERS: usize = 2000;
+
+/// Reads blocks from a peer via the Bitcoin P2P protocol. The headers of the
+/// peer's best chain are synced with `getheaders` and blocks are requested
+/// with `getdata`. The peer is trusted to serve the most-work chain. As the
+/// P2P protocol doesn't provide the outputs spent by a transaction, the fees
+/// of transactions and the outputs they spend are unknown.
+pub struct P2pSource {
+    peer: String,
+    network: Network,
+    retry: RetryPolicy,
+    connection: Mutex<Option<Connection>>,
+    chain: Mutex<HeaderChain>,
+}
+
+// The block hashes of the peer's best chain by height.
+struct HeaderChain {
+    hashes: Vec<BlockHash>,
+    heights: HashMap<BlockHash, usize>,
+}
+
+impl HeaderChain {
+    fn new(genesis: BlockHash) -> HeaderChain {
+        HeaderChain {
+            hashes: vec![genesis],
+            heights: HashMap::from([(genesis, 0)]),
+        }
+    }
+
+    // Block hashes from the tip backwards with exponentially increasing
+    // steps, so the peer can find the fork point with our chain.
+    fn locator(&self) -> Vec<BlockHash> {
+        let mut locator = vec![];
+        let mut height = self.hashes.len() - 1;
+        let mut step = 1;
+        loop {
+            locator.push(self.hashes[height]);
+            if height == 0 {
+                break;
+            }
+            if locator.len() >= 10 {
+                step *= 2;
+            }
+            height = height.saturating_sub(step);
+        }
+        locator
+    }
+
+    // Connects the headers to our chain. If they build on an earlier block
+    // than our tip, the blocks after it are replaced.
+    fn connect(&mut self, headers: &[Header]) -> Result<(), Error> {
+        for header in headers {
+            let prev_height = *self.heights.get(&header.prev_blockhash).ok_or_else(|| {
+                Error::Source(format!(
+                    "header {} doesn't connect to our chain",
+                    header.block_hash()
+                ))
+            })?;
+            for stale in self.hashes.drain(prev_height + 1..) {
+                self.heights.remove(&stale);
+            }
+            let hash = header.block_hash();
+            self.heights.insert(hash, self.hashes.len());
+            self.hashes.push(hash);
+        }
+        Ok(())
+    }
+}
+
+struct Connection {
+    magic: Magic,
+    writer: TcpStream,
+    reader: BufReader<TcpStream>,
+}
+
+impl Connection {
+    fn open(peer: &str, network: Network) -> Result<Connection, Error> {
+        let address = peer
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| Error::Config(format!("can't resolve p2p_peer {}", peer)))?;
+        let stream = TcpStream::connect_timeout(&address, P2P_TIMEOUT)?;
+        stream.set_read_timeout(Some(P2P_TIMEOUT))?;
+        stream.set_write_timeout(Some(P2P_TIMEOUT))?;
+        let mut connection = Connection {
+            magic: network.magic(),
+            reader: BufReader::new(stream.try_clone()?),
+            writer: stream,
+        };
+        connection.handshake(address)?;
+        info!("Connected to peer {}", peer);
+        Ok(connection)
+    }
+
+    fn handshake(&mut self, address: SocketAddr) -> Result<(), Error> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs() as i64)
+            .unwrap_or_default();
+        let local = SocketAddr::from(([0, 0, 0, 0], 0));
+        self.send(NetworkMessage::Version(VersionMessage::new(
+            ServiceFlags::NONE,
+            timestamp,
+            Address::new(&address, ServiceFlags::NONE),
+            Address::new(&local, ServiceFlags::NONE),
+            timestamp as u64,
+            USER_AGENT.to_string(),
+            0,
+        )))?;
+
+        let mut got_version = false;
+        let mut got_verack = false;
+        while !(got_version && got_verack) {
+            match self.receive()? {
+                NetworkMessage::Version(version) => {
+                    if !version.services.has(ServiceFlags::WITNESS) {
+                        return Err(Error::Source(
+                            "the peer doesn't serve witness data".to_string(),
+                        ));
+                    }
+                    got_version = true;
+                    self.send(NetworkMessage::Verack)?;
+                }
+                NetworkMessage::Verack => got_verack = true,
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+
+    fn send(&mut self, message: NetworkMessage) -> Result<(), Error> {
+        let message = RawNetworkMessage::new(self.magic, message);
+        let mut bytes = vec![];
+        message.consensus_encode(&mut bytes)?;
+        self.writer.write_all(&bytes)?;
+        Ok(())
+    }
+
+    // Receives the next message. Pings are answered right away.
+    fn receive(&mut self) -> Result<NetworkMessage, Error> {
+        loop {
+            let message =
+                RawNetworkMessage::consensus_decode(&mut self.reader).map_err(|e| match e {
+                    encode::Error::Io(e) => Error::Transport(Box::new(e)),
+                    e => Error::Source(format!("invalid message from peer: {}", e)),
+                })?;
+            if *message.magic() != self.magic {
+                return Err(Error::Source("the peer is on another network".to_string()));
+            }
+            match message.payload() {
+                NetworkMessage::Ping(nonce) => self.send(NetworkMessage::Pong(*nonce))?,
+                payload => {
+                    debug!("Received '{}' message from peer", payload.cmd());
+                    return Ok(payload.clone());
+                }
+            }
+        }
+    }
+}
+
+impl P2pSource {
+    pub fn new(peer: &str, network: Network, retry: RetryPolicy) -> P2pSource {
+        P2pSource {
+            peer: peer.to_string(),
+            network,
+            retry,
+            connection: Mutex::new(None),
+            chain: Mutex::new(HeaderChain::new(genesis_block(network).block_hash())),
+        }
+    }
+
+    // Runs `f` with a connection to the peer. The connection is (re-)opened
+    // when needed and dropped when `f` fails, so a retry starts over with a
+    // fresh connection.
+    fn with_connection<T, F>(&self, what: &str, mut f: F) -> Result<T, Error>
+    where
+        F: FnMut(&mut Connection) -> Result<T, Error>,
+    {
+        self.retry.retry(what, || {
+            let mut connection = self.connection.lock().expect("connection lock poisoned");
+            if connection.is_none() {
+                *connection = Some(Connection::open(&self.peer, self.network)?);
+            }
+            let result = f(connection.as_mut().expect("connection was just opened"));
+            if result.is_err() {
+                *connection = None;
+            }
+            result
+        })
+    }
+
+    // Requests headers from the peer until it has no more headers for us.
+    fn sync_headers(&self) -> Result<(), Error> {
+        let mut chain = self.chain.lock().expect("chain lock poisoned");
+        loop {
+            let locator = chain.locator();
+            let headers = self.with_connection("getheaders", |connection| {
+                connection.send(NetworkMessage::GetHeaders(GetHeadersMessage {
+                    version: PROTOCOL_VERSION,
+                    locator_hashes: locator.clone(),
+                    stop_hash: BlockHash::all_zeros(),
+                }))?;
+                loop {
+                    if let NetworkMessage::Headers(headers) = connection.receive()? {
+                        return Ok(headers);
+                    }
+                }
+            })?;
+            chain.connect(&headers)?;
+            if headers.len() < MAX_HEADERS {
+                return Ok(());
+            }
+            info!("Synced headers up to height {}..", chain.hashes.len() - 1);
+        }
+    }
+}
+
+impl BlockSource for P2pSource {
+    fn get_block_count(&self) -> Result<u64, Error> {
+        self.sync_headers()?;
+        let chain = self.chain.lock().expect("chain lock poisoned");
+        Ok(chain.hashes.len() as u64 - 1)
+    }
+
+    fn get_block_hash(&self, height: u64) -> Result<BlockHash, Error> {
+        // The headers are synced by get_block_count(). If the height is past
+        // the tip we know, the peer might have new blocks.
+        let known = self.chain.lock().expect("chain lock poisoned").hashes.len() as u64;
+        if height >= known {
+            self.sync_headers()?;
+        }
+        let chain = self.chain.lock().expect("chain lock poisoned");
+        chain
+            .hashes
+            .get(height as usize)
+            .copied()
+            .ok_or_else(|| Error::Source(format!("no block at height {}", height)))
+    }
+
+    fn get_block(&self, hash: &BlockHash) -> Result<Block, Error> {
+        self.with_connection("getdata", |connection| {
+            connection.send(NetworkMessage::GetData(vec![Inventory::WitnessBlock(
+                *hash,
+            )]))?;
+            loop {
+                match connection.receive()? {
+                    NetworkMessage::Block(block) if block.block_hash() == *hash => {
+                        return Ok(block)
+                    }
+                    NetworkMessage::NotFound(_) => {
+                        return Err(Error::Source(format!(
+                            "the peer doesn't have block {}",
+                            hash
+                        )))
+                    }
+                    _ => (),
+                }
+            }
+        })
+    }
+
+    fn get_fee(&self, _tx: &Transaction, _block_hash: &BlockHash) -> Result<Option<Amount>, Error> {
+        Ok(None)
+    }
+
+    fn get_prevouts(
+        &self,
+        _tx: &Transaction,
+        _block_hash: &BlockHash,
+    ) -> Result<Option<Vec<TxOut>>, Error> {
+        Ok(None)
+    }
+
+    fn get_median_feerate(
+        &self,
+        _block: &Block,
+        _block_hash: &BlockHash,
+    ) -> Result<Option<f64>, Error> {
+        Ok(None)
+    }
+
+    // Peers only serve transactions they announced.
+    fn get_transaction(
+        &self,
+        _txid: &Txid,
+    ) -> Result<Option<(Transaction, Option<BlockHash>)>, Error> {
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_node;
+    use bitcoincore_rpc::bitcoin::{block, CompactTarget, TxMerkleNode};
+    use std::net::TcpListener;
+    use std::thread;
+
+    // Headers building on the block, with the branch in the merkle root to
+    // tell chains apart.
+    fn headers(prev_blockhash: BlockHash, count: u32, branch: u8) -> Vec<Header> {
+        let mut headers: Vec<Header> = vec![];
+        for nonce in 0..count {
+            headers.push(Header {
+                version: block::Version::from_consensus(0x20000000),
+                prev_blockhash: headers
+                    .last()
+                    .map_or(prev_blockhash, |header| header.block_hash()),
+                merkle_root: TxMerkleNode::from_byte_array([branch; 32]),
+                time: 1296688602 + nonce * 600,
+                bits: CompactTarget::from_consensus(0x207fffff),
+                nonce,
+            });
+        }
+        headers
+    }
+
+    fn hashes(headers: &[Header]) -> Vec<BlockHash> {
+        headers.iter().map(|header| header.block_hash()).collect()
+    }
+
+    #[test]
+    fn connects_headers() {
+        let genesis = genesis_block(Network::Regtest).block_hash();
+        let mut chain = HeaderChain::new(genesis);
+        let main = headers(genesis, 5, 0);
+        chain.connect(&main[..3]).unwrap();
+        chain.connect(&main[3..]).unwrap();
+        assert_eq!(chain.hashes[1..], hashes(&main));
+
+        // The peer reorged to a fork from height 2, which replaces the blocks
+        // after it.
+        let fork = headers(main[1].block_hash(), 4, 1);
+        chain.connect(&fork).unwrap();
+        assert_eq!(chain.hashes.len(), 7);
+        assert_eq!(chain.hashes[3..], hashes(&fork));
+        assert!(!chain.heights.contains_key(&main[4].block_hash()));
+        assert_eq!(chain.heights[&fork[3].block_hash()], 6);
+
+        // Headers of another chain don't connect.
+        let unknown = headers(BlockHash::all_zeros(), 1, 2);
+        assert!(matches!(chain.connect(&unknown), Err(Error::Source(_))));
+        assert_eq!(chain.hashes.len(), 7);
+    }
+
+    #[test]
+    fn locates_the_tip_with_increasing_steps() {
+        let genesis = genesis_block(Network::Regtest).block_hash();
+        let mut chain = HeaderChain::new(genesis);
+        assert_eq!(chain.locator(), vec![genesis]);
+        chain.connect(&headers(genesis, 30, 0)).unwrap();
+        let heights: Vec<usize> = chain
+            .locator()
+            .iter()
+            .map(|hash| chain.heights[hash])
+            .collect();
+        assert_eq!(
+            heights,
+            [30, 29, 28, 27, 26, 25, 24, 23, 22, 21, 19, 15, 7, 0]
+        );
+    }
+
+    // Serves the blocks on a local port like a peer and returns its address.
+    fn mock_peer(blocks: Vec<Block>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("can bind");
+        let address = listener.local_addr().expect("has an address").to_string();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().expect("can accept");
+            let mut reader = BufReader::new(stream.try_clone().expect("can clone"));
+            let mut writer = stream;
+            let mut send = |message: NetworkMessage| {
+                let mut bytes = vec![];
+                RawNetworkMessage::new(Network::Regtest.magic(), message)
+                    .consensus_encode(&mut bytes)
+                    .expect("can encode");
+                writer.write_all(&bytes).expect("can send");
+            };
+            while let Ok(message) = RawNetworkMessage::consensus_decode(&mut reader) {
+                match message.payload() {
+                    NetworkMessage::Version(_) => {
+                        let local = SocketAddr::from(([127, 0, 0, 1], 0));
+                        send(NetworkMessage::Version(VersionMessage::new(
+                            ServiceFlags::NETWORK | ServiceFlags::WITNESS,
+                            0,
+                            Address::new(&local, ServiceFlags::NONE),
+                            Address::new(&local, ServiceFlags::NONE),
+                            0,
+                            "/mock:0.1.0/".to_string(),
+                            blocks.len() as i32,
+                        )));
+                        send(NetworkMessage::Verack);
+                    }
+                    NetworkMessage::GetHeaders(_) => send(NetworkMessage::Headers(
+                        blocks.iter().map(|block| block.header).collect(),
+                    )),
+                    NetworkMessage::GetData(inventory) => {
+                        for item in inventory {
+                            if let Inventory::WitnessBlock(hash) = item {
+                                let block = blocks.iter().find(|block| block.block_hash() == *hash);
+                                match block {
+                                    Some(block) => send(NetworkMessage::Block(block.clone())),
+                                    None => send(NetworkMessage::NotFound(vec![*item])),
+                                }
+                            }
+                        }
+                    }
+                    _ => (),
+                }
+            }
+        });
+        address
+    }
+
+    #[test]
+    fn syncs_headers_and_gets_blocks_from_a_peer() {
+        let genesis = genesis_block(Network::Regtest).block_hash();
+        let blocks: Vec<Block> = headers(genesis, 3, 0)
+            .into_iter()
+            .map(|header| Block {
+                header,
+                txdata: vec![],
+            })
+            .collect();
+        let peer = mock_peer(blocks.clone());
+        let source = P2pSource::new(&peer, Network::Regtest, mock_node::retry());
+
+        // The headers are synced when a height past the known tip is needed.
+        assert_eq!(source.get_block_hash(2).unwrap(), blocks[1].block_hash());
+        assert_eq!(source.get_block_count().unwrap(), 3);
+        assert!(matches!(source.get_block_hash(4), Err(Error::Source(_))));
+
+        let hash = blocks[2].block_hash();
+        assert_eq!(source.get_block(&hash).unwrap().block_hash(), hash);
+        let unknown = BlockHash::all_zeros();
+        assert!(matches!(source.get_block(&unknown), Err(Error::Source(_))));
+    }
+}
@@ -0,0 +1,129 @@
+use super::BlockSource;
+use crate::error::Error;
+use crate::retry::RetryPolicy;
+use bitcoincore_rpc::bitcoin::{Amount, Block, BlockHash, ScriptBuf, Transaction, TxOut, Txid};
+use bitcoincore_rpc::json::GetBlockStatsResultPartial;
+use bitcoincore_rpc::{Client, RpcApi};
+
+// The parts of the getrawtransaction response with verbosity 2 we are
+// interested in. The prevout is missing for the coinbase input.
+#[derive(serde::Deserialize)]
+struct RawTransactionWithPrevouts {
+    vin: Vec<RawInput>,
+}
+
+#[derive(serde::Deserialize)]
+struct RawInput {
+    prevout: Option<RawPrevout>,
+}
+
+#[derive(serde::Deserialize)]
+struct RawPrevout {
+    #[serde(with = "bitcoincore_rpc::bitcoin::amount::serde::as_btc")]
+    value: Amount,
+    #[serde(rename = "scriptPubKey")]
+    script_pubkey: RawScriptPubKey,
+}
+
+#[derive(serde::Deserialize)]
+struct RawScriptPubKey {
+    hex: ScriptBuf,
+}
+
+/// Reads blocks from the data node via RPC.
+pub struct RpcSource {
+    client: Client,
+    retry: RetryPolicy,
+}
+
+impl RpcSource {
+    pub fn new(client: Client, retry: RetryPolicy) -> RpcSource {
+        RpcSource { client, retry }
+    }
+}
+
+impl BlockSource for RpcSource {
+    fn get_block_count(&self) -> Result<u64, Error> {
+        self.retry
+            .retry("getblockcount", || self.client.get_block_count())
+    }
+
+    fn get_block_hash(&self, height: u64) -> Result<BlockHash, Error> {
+        self.retry
+            .retry("getblockhash", || self.client.get_block_hash(height))
+    }
+
+    fn get_block(&self, hash: &BlockHash) -> Result<Block, Error> {
+        self.retry.retry("getblock", || self.client.get_block(hash))
+    }
+
+    fn get_fee(&self, tx: &Transaction, block_hash: &BlockHash) -> Result<Option<Amount>, Error> {
+        let info = self.retry.retry("getrawtransaction", || {
+            self.client
+                .get_raw_transaction_info_with_fee(&tx.txid(), Some(block_hash))
+        })?;
+        Ok(info.fee)
+    }
+
+    // Requires Bitcoin Core v25.0 or newer on the data node and its undo data
+    // for the block.
+    fn get_prevouts(
+        &self,
+        tx: &Transaction,
+        block_hash: &BlockHash,
+    ) -> Result<Option<Vec<TxOut>>, Error> {
+        let raw: RawTransactionWithPrevouts = self.retry.retry("getrawtransaction", || {
+            self.client.call(
+                "getrawtransaction",
+                &[
+                    tx.txid().to_string().into(),
+                    2.into(),
+                    block_hash.to_string().into(),
+                ],
+            )
+        })?;
+        Ok(raw
+            .vin
+            .into_iter()
+            .map(|input| {
+                input.prevout.map(|prevout| TxOut {
+                    value: prevout.value,
+                    script_pubkey: prevout.script_pubkey.hex,
+                })
+            })
+            .collect())
+    }
+
+    fn get_median_feerate(
+        &self,
+        _block: &Block,
+        block_hash: &BlockHash,
+    ) -> Result<Option<f64>, Error> {
+        let stats: GetBlockStatsResultPartial = self.retry.retry("getblockstats", || {
+            self.client.call(
+                "getblockstats",
+                &[
+                    block_hash.to_string().into(),
+                    vec!["feerate_percentiles"].into(),
+                ],
+            )
+        })?;
+        Ok(stats
+            .fee_rate_percentiles
+            .map(|percentiles| percentiles.fr_50th.to_sat() as f64))
+    }
+
+    // Confirmed transactions need -txindex on the data node.
+    fn get_transaction(
+        &self,
+        txid: &Txid,
+    ) -> Result<Option<(Transaction, Option<BlockHash>)>, Error> {
+        let info = self.retry.retry("getrawtransaction", || {
+            self.client.get_raw_transaction_info(txid, None)
+        })?;
+        let tx = info
+            .transaction()
+            .map_err(|e| Error::Source(format!("invalid transaction {}: {}", txid, e)))?;
+        Ok(Some((tx, info.blockhash)))
+    }
+}
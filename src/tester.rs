@@ -0,0 +1,504 @@
+use crate::embedding;
+use crate::engine::{NativeEngine, PolicyEngine, Verdict};
+use crate::error::Error;
+use crate::out_of_band;
+use crate::package::{self, PackageVerdict};
+use crate::policy;
+use crate::reject::RejectReason;
+use crate::script_type::{ScriptType, ScriptTypes};
+use crate::source::BlockSource;
+use crate::ResultRow;
+use bitcoin_pool_identification::{Pool, PoolIdentification};
+use bitcoincore_rpc::bitcoin::{Block, BlockHash, Network, Transaction, TxOut, Txid};
+use log::{info, warn};
+use std::collections::{HashMap, HashSet, VecDeque};
+
+/// Tests the transactions of a block against the policy engines and builds
+/// the rows of the rejected transactions (and of the transactions the
+/// compared test nodes disagree on).
+pub struct BlockTester<'a> {
+    pub policy_engine: &'a dyn PolicyEngine,
+    /// Re-tests the descendants of rejected transactions.
+    pub relaxed_engine: Option<&'a dyn PolicyEngine>,
+    pub compared_engines: Vec<&'a dyn PolicyEngine>,
+    /// Cross-checks the verdicts of the policy engine.
+    pub native_engine: Option<NativeEngine>,
+    pub data_source: &'a dyn BlockSource,
+    pub package_acceptance: bool,
+    pub test_batch_size: usize,
+    pub out_of_band_max_position: usize,
+}
+
+impl BlockTester<'_> {
+    /// Tests the transactions of the block in block order. The prevouts are
+    /// the outputs spent by each transaction of the block, if fetched ahead.
+    /// Accepted transactions are kept by the engines, but the block isn't
+    /// connected.
+    pub fn test_block(
+        &self,
+        height: u64,
+        block_hash: BlockHash,
+        block: &Block,
+        block_prevouts: Option<&[Option<Vec<TxOut>>]>,
+        pool_name: &str,
+    ) -> Result<Vec<ResultRow>, Error> {
+        let mut rows: Vec<ResultRow> = vec![];
+        // Transactions of the block that were rejected and aren't in the
+        // mempool of the test node.
+        let mut rejected: HashMap<Txid, &Transaction> = HashMap::new();
+        // For each rejected transaction of the block, the rejected transaction
+        // that caused it and its reject reason. Transactions spending outputs
+        // of rejected transactions are rejected as missing-inputs, but the
+        // root cause is the rejection of their ancestor.
+        let mut root_causes: HashMap<Txid, (Txid, String)> = HashMap::new();
+        // Only fetched once a transaction of the block is written.
+        let mut block_median_feerate: Option<Option<f64>> = None;
+        // The verdicts of the test node, the compared test nodes and the
+        // relaxed node for the transactions of the current batch.
+        let mut batch: VecDeque<(Verdict, Vec<Verdict>, Option<Verdict>)> = VecDeque::new();
+        let mut batch_end = 0;
+        let txids: Vec<Txid> = block.txdata.iter().map(|tx| tx.txid()).collect();
+        let positions: HashMap<Txid, usize> = txids
+            .iter()
+            .enumerate()
+            .map(|(position, txid)| (*txid, position))
+            .collect();
+        for (position, tx) in block.txdata.iter().enumerate() {
+            if tx.is_coinbase() {
+                continue;
+            }
+
+            if position >= batch_end {
+                batch_end = next_batch_end(
+                    &block.txdata,
+                    &txids,
+                    &positions,
+                    position,
+                    self.test_batch_size,
+                    &rejected,
+                );
+                let txs: Vec<&Transaction> = block.txdata[position..batch_end].iter().collect();
+                let batch_prevouts: Vec<Option<&[TxOut]>> = (position..batch_end)
+                    .map(|i| block_prevouts.and_then(|p| p[i].as_deref()))
+                    .collect();
+                let no_prevouts = vec![None; txs.len()];
+                let verdicts = self.policy_engine.test_batch(&txs, &batch_prevouts)?;
+                let mut node_verdicts = vec![vec![]; txs.len()];
+                for compared_engine in self.compared_engines.iter() {
+                    let verdicts = compared_engine.test_batch(&txs, &no_prevouts)?;
+                    for (node_verdicts, verdict) in node_verdicts.iter_mut().zip(verdicts) {
+                        node_verdicts.push(verdict);
+                    }
+                }
+                // All transactions of the block are sent to the relaxed node,
+                // so the ancestors of a descendant are in its mempool.
+                let relaxed_verdicts = match self.relaxed_engine {
+                    Some(relaxed_engine) => relaxed_engine
+                        .test_batch(&txs, &no_prevouts)?
+                        .into_iter()
+                        .map(Some)
+                        .collect(),
+                    None => vec![None; txs.len()],
+                };
+                batch = verdicts
+                    .into_iter()
+                    .zip(node_verdicts)
+                    .zip(relaxed_verdicts)
+                    .map(|((verdict, node_verdicts), relaxed_verdict)| {
+                        (verdict, node_verdicts, relaxed_verdict)
+                    })
+                    .collect();
+            }
+            let (verdict, node_verdicts, relaxed_verdict) =
+                batch.pop_front().expect("every transaction is in a batch");
+
+            let prevouts = block_prevouts.map(|p| p[position].clone());
+            if let Some(native_engine) = &self.native_engine {
+                let native_verdict =
+                    native_engine.test(tx, prevouts.as_ref().and_then(|p| p.as_deref()))?;
+                cross_check(tx, &verdict, &native_verdict);
+            }
+
+            let policies_disagree = node_verdicts
+                .iter()
+                .any(|node_verdict| node_verdict.allowed != verdict.allowed);
+
+            if !verdict.allowed || policies_disagree {
+                // If a previously aborted run left transactions in the mempool,
+                // a transaction will be rejected for already being in the mempool.
+                // We don't care about these cases.
+                let reject_reason = verdict.reject_reason.clone().unwrap_or_default();
+                let reject = (!verdict.allowed).then(|| RejectReason::parse(&reject_reason));
+                if matches!(reject, Some((RejectReason::AlreadyInMempool, _))) {
+                    continue;
+                }
+
+                let fee = self.data_source.get_fee(tx, &block_hash)?;
+                let feerate = fee.map(|fee| fee.to_sat() as f64 / tx.vsize() as f64);
+                let out_of_band_evidence =
+                    out_of_band::evidence(tx, position, fee, block, self.out_of_band_max_position);
+                let median_feerate = match block_median_feerate {
+                    Some(median_feerate) => median_feerate,
+                    None => {
+                        let median_feerate =
+                            self.data_source.get_median_feerate(block, &block_hash)?;
+                        block_median_feerate = Some(median_feerate);
+                        median_feerate
+                    }
+                };
+                // Bitcoin Core only reports the first rule a transaction
+                // violates. Check for the others ourselves.
+                let prevouts = match prevouts {
+                    Some(prevouts) => prevouts,
+                    None => self.data_source.get_prevouts(tx, &block_hash)?,
+                };
+                let policy_violations = policy::violations(tx, prevouts.as_deref()).join(";");
+                let embedding = embedding::detect(tx, prevouts.as_deref());
+                let input_types = ScriptTypes(
+                    tx.input
+                        .iter()
+                        .enumerate()
+                        .map(|(index, input)| {
+                            let prevout = prevouts.as_ref().and_then(|p| p.get(index));
+                            ScriptType::of_input(input, prevout)
+                        })
+                        .collect(),
+                );
+                let output_types = ScriptTypes(
+                    tx.output
+                        .iter()
+                        .map(|output| ScriptType::of_output(&output.script_pubkey))
+                        .collect(),
+                );
+
+                let rejected_ancestor = match reject {
+                    Some((RejectReason::MissingInputs, _)) => tx
+                        .input
+                        .iter()
+                        .find_map(|input| root_causes.get(&input.previous_output.txid))
+                        .cloned(),
+                    _ => None,
+                };
+                if !verdict.allowed {
+                    root_causes.insert(
+                        tx.txid(),
+                        rejected_ancestor
+                            .clone()
+                            .unwrap_or((tx.txid(), reject_reason.clone())),
+                    );
+                }
+                if policies_disagree {
+                    info!(
+                        "The compared test nodes disagree on transaction {} in block {}",
+                        tx.txid(),
+                        height
+                    );
+                }
+
+                // When using -stopatheight=X, Bitcoin Core might already know
+                // about blocks at a height >X. In this case, transactions are
+                // rejected because they are "already known" (as the blocks
+                // are already known). We don't care about these cases and
+                // filter them out when we receive an error on submitblock.
+                rows.push(ResultRow {
+                    height,
+                    miner: pool_name.to_string(),
+                    block_position: position,
+                    txid: tx.txid(),
+                    reject_reason,
+                    reject_category: reject.as_ref().map(|(reason, _)| reason.category()),
+                    reject_code: reject.as_ref().map(|(reason, _)| reason.clone()),
+                    reject_detail: reject.and_then(|(_, detail)| detail),
+                    vsize: tx.vsize(),
+                    weight: tx.weight().to_wu(),
+                    base_size: tx.base_size(),
+                    witness_size: tx.total_size() - tx.base_size(),
+                    inputs: tx.input.len(),
+                    outputs: tx.output.len(),
+                    input_type_counts: input_types.counts(),
+                    input_types,
+                    output_type_counts: output_types.counts(),
+                    output_types,
+                    fee: fee.unwrap_or_default().to_sat(),
+                    fee_known: fee.is_some(),
+                    feerate,
+                    block_median_feerate: median_feerate,
+                    median_feerate_ratio: match (feerate, median_feerate) {
+                        (Some(feerate), Some(median)) if median > 0.0 => Some(feerate / median),
+                        _ => None,
+                    },
+                    package_allowed: None,
+                    package_reject_reason: None,
+                    rejected_ancestor: rejected_ancestor.as_ref().map(|(txid, _)| *txid),
+                    ancestor_reject_reason: rejected_ancestor.map(|(_, reason)| reason),
+                    relaxed_allowed: None,
+                    relaxed_reject_reason: None,
+                    likely_out_of_band: out_of_band::is_likely(&out_of_band_evidence),
+                    out_of_band_evidence: out_of_band_evidence.join(";"),
+                    data_embedding: embedding.protocols.join(";"),
+                    data_payload_size: embedding.payload_size,
+                    policy_violations,
+                    policies_disagree: (!self.compared_engines.is_empty())
+                        .then_some(policies_disagree),
+                    node_verdicts,
+                });
+
+                // A transaction spending outputs of rejected transactions
+                // (e.g. a child paying for a low-fee parent) is tested
+                // together with them as a package.
+                let package = match self.package_acceptance && !verdict.allowed {
+                    true => package::child_with_rejected_parents(tx, &rejected),
+                    false => None,
+                };
+                if !verdict.allowed {
+                    rejected.insert(tx.txid(), tx);
+                }
+                if let Some(package) = package {
+                    let verdict = self.policy_engine.test_package(&package)?;
+                    record_package_verdict(&mut rows, &package, &verdict, &mut rejected);
+                }
+            }
+
+            if let Some(relaxed_verdict) = relaxed_verdict {
+                let txid = tx.txid();
+                if let Some(row) = rows
+                    .iter_mut()
+                    .find(|row| row.txid == txid && row.rejected_ancestor.is_some())
+                {
+                    row.relaxed_allowed = Some(relaxed_verdict.allowed);
+                    row.relaxed_reject_reason = relaxed_verdict.reject_reason;
+                }
+            }
+        }
+
+        Ok(rows)
+    }
+}
+
+// Records the verdicts on the transactions of a package in their rows. A
+// parent can form packages with several children. Its row keeps the verdict
+// of the first package, unless a later package got it accepted. Accepted
+// transactions are in the mempool now.
+fn record_package_verdict(
+    rows: &mut [ResultRow],
+    package: &[&Transaction],
+    verdict: &PackageVerdict,
+    rejected: &mut HashMap<Txid, &Transaction>,
+) {
+    for (member, tx_verdict) in package.iter().zip(verdict.tx_verdicts.iter()) {
+        let txid = member.txid();
+        if let Some(row) = rows.iter_mut().find(|row| row.txid == txid) {
+            if row.package_allowed.is_none() || tx_verdict.allowed {
+                row.package_allowed = Some(tx_verdict.allowed);
+                row.package_reject_reason = tx_verdict.reject_reason.clone();
+            }
+        }
+        if tx_verdict.allowed {
+            rejected.remove(&txid);
+        }
+    }
+}
+
+/// The name of the pool that mined the block, or "Unknown".
+pub fn pool_name(block: &Block, pools: &[Pool]) -> String {
+    match block.identify_pool(Network::Bitcoin, pools) {
+        Some(result) => result.pool.name,
+        None => "Unknown".to_string(),
+    }
+}
+
+// The end of the batch of transactions starting at `start`: the transactions
+// up to the first one spending an output of a transaction in the batch or of a
+// rejected transaction, or sharing an ancestor in the block with a
+// transaction in the batch. The verdict on such a transaction depends on the
+// verdicts on the transactions before it (or on the package test of a
+// rejected transaction with another child). Transactions sharing an
+// unconfirmed ancestor can each pass the ancestor limits on their own, but
+// not together. `txids` and `positions` are the txids of the transactions of
+// the block and their positions.
+fn next_batch_end(
+    txs: &[Transaction],
+    txids: &[Txid],
+    positions: &HashMap<Txid, usize>,
+    start: usize,
+    max_size: usize,
+    rejected: &HashMap<Txid, &Transaction>,
+) -> usize {
+    let mut batch: HashSet<Txid> = HashSet::new();
+    let mut batch_ancestors: HashSet<Txid> = HashSet::new();
+    let mut end = start;
+    while end < txs.len() && end - start < max_size {
+        let tx = &txs[end];
+        let depends_on_batch = tx.input.iter().any(|input| {
+            let txid = input.previous_output.txid;
+            batch.contains(&txid) || rejected.contains_key(&txid)
+        });
+        let ancestors = ancestors(tx, txs, positions);
+        if end > start && (depends_on_batch || !ancestors.is_disjoint(&batch_ancestors)) {
+            break;
+        }
+        batch.insert(txids[end]);
+        batch_ancestors.extend(ancestors);
+        end += 1;
+    }
+    end
+}
+
+// The ancestors of the transaction earlier in the block, which are in the
+// mempool of the test node or were rejected. The outputs of earlier blocks
+// are confirmed, so transactions spending them don't share an ancestor.
+fn ancestors(
+    tx: &Transaction,
+    txs: &[Transaction],
+    positions: &HashMap<Txid, usize>,
+) -> HashSet<Txid> {
+    let mut ancestors = HashSet::new();
+    let mut pending = vec![tx];
+    while let Some(tx) = pending.pop() {
+        for input in tx.input.iter() {
+            let txid = input.previous_output.txid;
+            if let Some(position) = positions.get(&txid) {
+                if ancestors.insert(txid) {
+                    pending.push(&txs[*position]);
+                }
+            }
+        }
+    }
+    ancestors
+}
+
+// Logs when the test node and the native engine disagree on a transaction.
+// Transactions the test node rejects for missing inputs or for already being
+// in the mempool are skipped, as the native engine doesn't know the mempool.
+fn cross_check(tx: &Transaction, verdict: &Verdict, native_verdict: &Verdict) {
+    let node_reason = verdict
+        .reject_reason
+        .as_deref()
+        .map(|reason| RejectReason::parse(reason).0);
+    if verdict.allowed == native_verdict.allowed
+        || matches!(
+            node_reason,
+            Some(RejectReason::MissingInputs | RejectReason::AlreadyInMempool)
+        )
+    {
+        return;
+    }
+    warn!(
+        "The test node and the native engine disagree on transaction {}: test node: {:?}, native engine: {:?}",
+        tx.txid(),
+        verdict.reject_reason.as_deref().unwrap_or("allowed"),
+        native_verdict.reject_reason.as_deref().unwrap_or("allowed"),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoincore_rpc::bitcoin::hashes::Hash;
+    use bitcoincore_rpc::bitcoin::{absolute, transaction, Amount, OutPoint, ScriptBuf, TxIn};
+
+    fn tx(spends: &[(Txid, u32)], tag: u64) -> Transaction {
+        Transaction {
+            version: transaction::Version::TWO,
+            lock_time: absolute::LockTime::ZERO,
+            input: spends
+                .iter()
+                .map(|(txid, vout)| TxIn {
+                    previous_output: OutPoint::new(*txid, *vout),
+                    ..Default::default()
+                })
+                .collect(),
+            output: vec![TxOut {
+                value: Amount::from_sat(tag),
+                script_pubkey: ScriptBuf::new(),
+            }],
+        }
+    }
+
+    fn batch_end(
+        txs: &[Transaction],
+        start: usize,
+        rejected: &HashMap<Txid, &Transaction>,
+    ) -> usize {
+        let txids: Vec<Txid> = txs.iter().map(|tx| tx.txid()).collect();
+        let positions = txids
+            .iter()
+            .enumerate()
+            .map(|(position, txid)| (*txid, position))
+            .collect();
+        next_batch_end(txs, &txids, &positions, start, 100, rejected)
+    }
+
+    #[test]
+    fn keeps_children_of_a_confirmed_transaction_in_a_batch() {
+        let confirmed = Txid::all_zeros();
+        let txs = vec![
+            tx(&[(confirmed, 0)], 1),
+            tx(&[(confirmed, 1)], 2),
+            tx(&[(Txid::from_byte_array([1; 32]), 0)], 3),
+        ];
+        assert_eq!(batch_end(&txs, 0, &HashMap::new()), 3);
+    }
+
+    #[test]
+    fn splits_batches_on_shared_ancestors() {
+        let confirmed = Txid::all_zeros();
+        let rejected = HashMap::new();
+        // Two children of a transaction earlier in the block.
+        let parent = tx(&[(confirmed, 0)], 1);
+        let child = tx(&[(parent.txid(), 0)], 2);
+        let other_child = tx(&[(parent.txid(), 1)], 3);
+        let unrelated = tx(&[(confirmed, 1)], 4);
+        let txs = vec![parent, child, other_child, unrelated];
+        assert_eq!(batch_end(&txs, 1, &rejected), 2);
+        assert_eq!(batch_end(&txs, 2, &rejected), 4);
+
+        // A child and a grandchild of a transaction earlier in the block.
+        let grandchild = tx(&[(txs[1].txid(), 0)], 5);
+        let txs = vec![txs[0].clone(), txs[1].clone(), grandchild, txs[2].clone()];
+        assert_eq!(batch_end(&txs, 2, &rejected), 3);
+
+        // A child of a rejected transaction.
+        let rejected = HashMap::from([(txs[0].txid(), &txs[0])]);
+        let unrelated = tx(&[(confirmed, 1)], 4);
+        let txs = vec![unrelated, txs[3].clone()];
+        assert_eq!(batch_end(&txs, 0, &rejected), 1);
+    }
+
+    #[test]
+    fn keeps_the_first_package_verdict_unless_a_later_package_is_accepted() {
+        let parent = tx(&[(Txid::all_zeros(), 0)], 1);
+        let child = tx(&[(parent.txid(), 0)], 2);
+        let other_child = tx(&[(parent.txid(), 1)], 3);
+        let mut rows = vec![ResultRow::rejected(1, parent.txid())];
+        let mut rejected = HashMap::from([(parent.txid(), &parent)]);
+        let rejected_verdict = |reason: &str| Verdict {
+            allowed: false,
+            reject_reason: Some(reason.to_string()),
+        };
+
+        let verdict = PackageVerdict {
+            tx_verdicts: vec![rejected_verdict("first"), rejected_verdict("first")],
+        };
+        record_package_verdict(&mut rows, &[&parent, &child], &verdict, &mut rejected);
+        let verdict = PackageVerdict {
+            tx_verdicts: vec![rejected_verdict("second"), rejected_verdict("second")],
+        };
+        record_package_verdict(&mut rows, &[&parent, &other_child], &verdict, &mut rejected);
+        assert_eq!(rows[0].package_allowed, Some(false));
+        assert_eq!(rows[0].package_reject_reason.as_deref(), Some("first"));
+        assert!(rejected.contains_key(&parent.txid()));
+
+        let accepted = Verdict {
+            allowed: true,
+            reject_reason: None,
+        };
+        let verdict = PackageVerdict {
+            tx_verdicts: vec![accepted.clone(), accepted],
+        };
+        record_package_verdict(&mut rows, &[&parent, &other_child], &verdict, &mut rejected);
+        assert_eq!(rows[0].package_allowed, Some(true));
+        assert_eq!(rows[0].package_reject_reason, None);
+        assert!(rejected.is_empty());
+    }
+}
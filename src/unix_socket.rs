@@ -0,0 +1,200 @@
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use bitcoincore_rpc::jsonrpc::{self, Request, Response, Transport};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::error;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::time::Duration;
+
+const HTTP_UNAUTHORIZED: u16 = 401;
+const HTTP_FORBIDDEN: u16 = 403;
+
+/// Sends the JSON-RPC requests over HTTP to a unix domain socket. Bitcoin
+/// Core only serves JSON-RPC over TCP, so the socket must be one of a proxy
+/// in front of the RPC server of the node (e.g. socat or nginx). Every
+/// request uses a new connection.
+pub struct UnixSocketTransport {
+    path: PathBuf,
+    // The value of the Authorization header.
+    authorization: String,
+    timeout: Duration,
+}
+
+impl UnixSocketTransport {
+    pub fn new(path: PathBuf, user: &str, pass: &str, timeout: Duration) -> UnixSocketTransport {
+        UnixSocketTransport {
+            path,
+            authorization: format!("Basic {}", BASE64.encode(format!("{}:{}", user, pass))),
+            timeout,
+        }
+    }
+
+    fn post<T: Serialize, R: DeserializeOwned>(&self, body: &T) -> Result<R, jsonrpc::Error> {
+        let body = serde_json::to_vec(body).map_err(jsonrpc::Error::Json)?;
+        let response = self
+            .exchange(&body)
+            .map_err(|e| jsonrpc::Error::Transport(Box::new(e)))?;
+        let (status, body) = parse_response(&response)
+            .ok_or_else(|| transport_error("invalid HTTP response".to_string()))?;
+        if status == HTTP_UNAUTHORIZED || status == HTTP_FORBIDDEN {
+            return Err(jsonrpc::Error::Transport(Box::new(AuthError { status })));
+        }
+        // Bitcoin Core answers failed calls with an error status and the
+        // error in a JSON-RPC response.
+        serde_json::from_slice(&body).map_err(|e| match status {
+            200 => jsonrpc::Error::Json(e),
+            status => transport_error(format!("HTTP error {}", status)),
+        })
+    }
+
+    fn exchange(&self, body: &[u8]) -> io::Result<Vec<u8>> {
+        let mut stream = UnixStream::connect(&self.path)?;
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_write_timeout(Some(self.timeout))?;
+        write!(
+            stream,
+            "POST / HTTP/1.1\r\nHost: localhost\r\nAuthorization: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            self.authorization,
+            body.len()
+        )?;
+        stream.write_all(body)?;
+        let mut response = vec![];
+        stream.read_to_end(&mut response)?;
+        Ok(response)
+    }
+}
+
+impl Transport for UnixSocketTransport {
+    fn send_request(&self, request: Request) -> Result<Response, jsonrpc::Error> {
+        self.post(&request)
+    }
+
+    fn send_batch(&self, requests: &[Request]) -> Result<Vec<Response>, jsonrpc::Error> {
+        self.post(&requests)
+    }
+
+    fn fmt_target(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.path.display())
+    }
+}
+
+/// The RPC server (or the proxy) refused the credentials. Unlike other
+/// transport errors, retrying doesn't help.
+#[derive(Debug)]
+pub struct AuthError {
+    status: u16,
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "the credentials were refused (HTTP {})", self.status)
+    }
+}
+
+impl error::Error for AuthError {}
+
+fn transport_error(message: String) -> jsonrpc::Error {
+    jsonrpc::Error::Transport(Box::new(io::Error::other(message)))
+}
+
+// The status code and the body of the HTTP response. The connection is
+// closed after the response, so the body is everything after the header,
+// unless it's sent in chunks.
+fn parse_response(response: &[u8]) -> Option<(u16, Vec<u8>)> {
+    let header_end = response.windows(4).position(|w| w == b"\r\n\r\n")?;
+    let header = std::str::from_utf8(&response[..header_end]).ok()?;
+    let body = &response[header_end + 4..];
+    let mut lines = header.split("\r\n");
+    let status = lines.next()?.split(' ').nth(1)?.parse().ok()?;
+    let chunked = lines.any(|line| {
+        line.to_ascii_lowercase()
+            .replace(' ', "")
+            .starts_with("transfer-encoding:chunked")
+    });
+    match chunked {
+        true => Some((status, dechunk(body)?)),
+        false => Some((status, body.to_vec())),
+    }
+}
+
+fn dechunk(mut body: &[u8]) -> Option<Vec<u8>> {
+    let mut data = vec![];
+    loop {
+        let line_end = body.windows(2).position(|w| w == b"\r\n")?;
+        let size = std::str::from_utf8(&body[..line_end]).ok()?;
+        let size = usize::from_str_radix(size.split(';').next()?.trim(), 16).ok()?;
+        if size == 0 {
+            return Some(data);
+        }
+        let chunk = body.get(line_end + 2..line_end + 2 + size)?;
+        data.extend_from_slice(chunk);
+        body = body.get(line_end + 4 + size..)?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::Error;
+    use std::os::unix::net::UnixListener;
+    use std::thread;
+
+    #[test]
+    fn parses_responses() {
+        let response = b"HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n\r\n{\"result\":1}";
+        assert_eq!(
+            parse_response(response),
+            Some((200, b"{\"result\":1}".to_vec()))
+        );
+        let response = b"HTTP/1.1 500 Internal Server Error\r\n\r\n";
+        assert_eq!(parse_response(response), Some((500, vec![])));
+        assert_eq!(parse_response(b"HTTP/1.1 200 OK\r\n"), None);
+        assert_eq!(parse_response(b"garbage\r\n\r\n"), None);
+    }
+
+    #[test]
+    fn parses_chunked_responses() {
+        let response = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: Chunked\r\n\r\n5\r\nhello\r\nb;ext=1\r\n world body\r\n0\r\n\r\n";
+        assert_eq!(
+            parse_response(response),
+            Some((200, b"hello world body".to_vec()))
+        );
+        assert_eq!(dechunk(b"0\r\n\r\n"), Some(vec![]));
+        // Truncated or invalid chunks.
+        assert_eq!(dechunk(b"5\r\nhel"), None);
+        assert_eq!(dechunk(b"5\r\nhello\r\n"), None);
+        assert_eq!(dechunk(b"x\r\nhello\r\n0\r\n\r\n"), None);
+    }
+
+    #[test]
+    fn refused_credentials_are_a_config_error() {
+        let path = std::env::temp_dir().join(format!(
+            "non-standard-unix-socket-{}.sock",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = [0; 1024];
+            let _ = stream.read(&mut request);
+            let _ = stream.write_all(b"HTTP/1.1 401 Unauthorized\r\nContent-Length: 0\r\n\r\n");
+        });
+        let client = jsonrpc::Client::with_transport(UnixSocketTransport::new(
+            path.clone(),
+            "user",
+            "wrong",
+            Duration::from_secs(10),
+        ));
+        let error = Error::from(bitcoincore_rpc::Error::from(
+            client.call::<u64>("getblockcount", &[]).unwrap_err(),
+        ));
+        assert!(matches!(error, Error::Config(_)), "{}", error);
+        assert!(!error.is_transient());
+        std::fs::remove_file(&path).unwrap();
+    }
+}